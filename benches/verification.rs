@@ -0,0 +1,57 @@
+// benches/verification.rs
+//
+// Compares checking the proofs of a block one at a time with checking them
+// as one batch, for blocks of increasing size. Run with `cargo bench`.
+
+use privacy_blockchain::blockchain::Blockchain;
+use privacy_blockchain::shielded::{Note, NoteCommitmentTree, SpendingKey};
+use privacy_blockchain::transaction::Transaction;
+use privacy_blockchain::wallet::Wallet;
+use privacy_blockchain::zk_proofs::{install_parameters, SetupParameters};
+use std::time::{Duration, Instant};
+
+const BLOCK_SIZES: [usize; 4] = [1, 10, 50, 200];
+const RUNS: usize = 3;
+
+/// Fastest of `RUNS` runs of `f`.
+fn time(mut f: impl FnMut() -> bool) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            assert!(f());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    // Proving is slow, so blocks repeat a few distinct deposits, each carrying
+    // a shielded bundle. Transparent transactions carry no proof to check
+    println!("Generating parameters and proofs...");
+    install_parameters(SetupParameters::generate(&mut rand_core::OsRng).unwrap()).unwrap();
+    let sender = Wallet::new().public_key_hex();
+    let anchor = NoteCommitmentTree::default().root();
+    let distinct: Vec<Transaction> = (1..=4)
+        .map(|amount| {
+            let note = Note::new(amount, SpendingKey::random().address());
+            Transaction::new_deposit(sender.clone(), amount, 0, amount, &[note], anchor).unwrap()
+        })
+        .collect();
+
+    println!("{:>6} {:>8} {:>14} {:>14} {:>8}", "txs", "proofs", "one by one", "batched", "speedup");
+    for size in BLOCK_SIZES {
+        let block: Vec<Transaction> = distinct.iter().cycle().take(size).cloned().collect();
+        let proofs = block.len();
+        let single = time(|| block.iter().all(Transaction::verify_shielded));
+        let batched = time(|| Blockchain::first_invalid_proof(&block).is_none());
+        println!(
+            "{:>6} {:>8} {:>12.1}ms {:>12.1}ms {:>7.1}x",
+            size,
+            proofs,
+            single.as_secs_f64() * 1e3,
+            batched.as_secs_f64() * 1e3,
+            single.as_secs_f64() / batched.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// src/address.rs
+//
+// Human-readable account addresses.
+//
+// Accounts are identified on chain by their hex ed25519 public key, which
+// has no checksum, so a mistyped key is a valid-looking account that nobody
+// can spend from. Users see addresses instead: Bech32m (BIP350) strings
+// with the network prefix "pb", whose data is a version byte followed by
+// the public key. The checksum catches any four mistyped characters, and the
+// prefix keeps addresses of other networks from being accepted.
+
+use bech32::primitives::decode::{CheckedHrpstring, CheckedHrpstringError};
+use bech32::{Bech32m, Hrp};
+use ed25519_zebra::VerificationKey;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::str::FromStr;
+
+/// Network prefix of addresses on this chain.
+pub const ADDRESS_HRP: &str = "pb";
+
+/// Version of the addresses created by this node. Version 0 holds an
+/// ed25519 public key.
+pub const ADDRESS_VERSION: u8 = 0;
+
+/// Reason a string is not an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    /// Not a Bech32m string at all.
+    Malformed,
+    /// A Bech32m string whose checksum does not match, usually a typo.
+    InvalidChecksum,
+    /// An address of another network, with this prefix.
+    WrongNetwork(String),
+    UnsupportedVersion(u8),
+    /// The key is this many bytes long instead of 32.
+    InvalidLength(usize),
+    /// The key is not a point on the curve.
+    InvalidPublicKey,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Malformed => write!(f, "not an address"),
+            AddressError::InvalidChecksum => write!(f, "address checksum does not match; check it for typos"),
+            AddressError::WrongNetwork(hrp) => {
+                write!(f, "address is for network '{}', not '{}'", hrp, ADDRESS_HRP)
+            }
+            AddressError::UnsupportedVersion(v) => write!(f, "unsupported address version {}", v),
+            AddressError::InvalidLength(len) => write!(f, "address holds a {}-byte key instead of 32", len),
+            AddressError::InvalidPublicKey => write!(f, "address does not hold a valid public key"),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// An account address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    public_key: [u8; 32],
+}
+
+impl Address {
+    pub fn from_public_key(public_key: &VerificationKey) -> Self {
+        Address { public_key: public_key.as_ref().try_into().unwrap() }
+    }
+
+    /// Address of the account whose hex public key is `key`, as stored in
+    /// transactions.
+    pub fn from_public_key_hex(key: &str) -> Result<Self, AddressError> {
+        let bytes = hex::decode(key).map_err(|_| AddressError::Malformed)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Parses and validates an address, in lower or upper case.
+    pub fn parse(s: &str) -> Result<Self, AddressError> {
+        let checked = CheckedHrpstring::new::<Bech32m>(s).map_err(|e| match e {
+            CheckedHrpstringError::Checksum(_) => AddressError::InvalidChecksum,
+            _ => AddressError::Malformed,
+        })?;
+        let hrp = checked.hrp();
+        if hrp != Hrp::parse_unchecked(ADDRESS_HRP) {
+            return Err(AddressError::WrongNetwork(hrp.to_lowercase()));
+        }
+        checked.validate_segwit_padding().map_err(|_| AddressError::Malformed)?;
+        let data: Vec<u8> = checked.byte_iter().collect();
+        match data.split_first() {
+            Some((&ADDRESS_VERSION, key)) => Self::from_bytes(key),
+            Some((&version, _)) => Err(AddressError::UnsupportedVersion(version)),
+            None => Err(AddressError::InvalidLength(0)),
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    /// The account the address pays, as transactions and balances name it.
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.public_key)
+    }
+
+    fn from_bytes(key: &[u8]) -> Result<Self, AddressError> {
+        let public_key: [u8; 32] = key.try_into().map_err(|_| AddressError::InvalidLength(key.len()))?;
+        VerificationKey::try_from(public_key).map_err(|_| AddressError::InvalidPublicKey)?;
+        Ok(Address { public_key })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut data = vec![ADDRESS_VERSION];
+        data.extend_from_slice(&self.public_key);
+        let hrp = Hrp::parse_unchecked(ADDRESS_HRP);
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, hrp, &data).map_err(|_| fmt::Error)
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
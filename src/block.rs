@@ -4,26 +4,70 @@ use serde::{Serialize, Deserialize};
 use crate::transaction::Transaction;
 use sha2::{Sha256, Digest};
 use chrono::Utc;
+use crate::difficulty::{hash_meets_target, target_from_compact, work_for_target, POW_LIMIT_BITS};
+use crate::merkle::{merkle_proof, merkle_root, MerkleProof};
+use crate::encoding::{to_bytes, Decode, DecodeError, Encode, Reader};
+use crate::shielded::ShieldedState;
+use crate::state::AccountState;
+use crate::zk_proofs::parameters;
 
+/// Fixed genesis timestamp so that every node starts from the same block.
+pub const GENESIS_TIMESTAMP: i64 = 1_700_000_000;
+
+/// The part of a block covered by proof of work. Transactions are committed to
+/// through `merkle_root`, so mining never rehashes transaction data.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Block {
+pub struct BlockHeader {
     pub index: u64,
     pub timestamp: i64,
     pub previous_hash: String,
+    /// Merkle root of the ids of the block's transactions.
+    pub merkle_root: String,
+    /// Merkle root of the account state after applying the block (see
+    /// `AccountState::state_root`).
+    pub state_root: String,
+    /// Root of the note commitment tree after applying the block, which
+    /// shielded spends in later blocks use as their anchor.
+    pub note_root: String,
+    /// Proof-of-work target in compact form (see `difficulty::target_from_compact`).
+    pub bits: u32,
     pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn calculate_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(to_bytes(self));
+        let result = hasher.finalize();
+        hex::encode(result)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
     pub hash: String,
 }
 
 impl Block {
-    pub fn new(index: u64, previous_hash: String, transactions: Vec<Transaction>) -> Self {
+    /// Builds an unmined block. The miner fills in `state_root` and
+    /// `note_root` once it has applied the transactions.
+    pub fn new(index: u64, previous_hash: String, transactions: Vec<Transaction>, bits: u32) -> Self {
         let timestamp = Utc::now().timestamp();
         let nonce = 0;
-        let mut block = Block {
+        let header = BlockHeader {
             index,
             timestamp,
             previous_hash,
+            merkle_root: compute_merkle_root(&transactions),
+            state_root: AccountState::default().state_root(),
+            note_root: ShieldedState::default().note_root(),
+            bits,
             nonce,
+        };
+        let mut block = Block {
+            header,
             transactions,
             hash: String::new(),
         };
@@ -31,11 +75,106 @@ impl Block {
         block
     }
 
+    /// Builds the deterministic genesis block shared by all nodes.
+    ///
+    /// With no block before it, its `previous_hash` instead pins the
+    /// fingerprint of the chain's zk-SNARK parameters (see
+    /// `SetupParameters::fingerprint`). Nodes running other parameters build
+    /// a different genesis, so they reject this chain's blocks and data.
+    pub fn genesis() -> Self {
+        let header = BlockHeader {
+            index: 0,
+            timestamp: GENESIS_TIMESTAMP,
+            previous_hash: parameters().fingerprint(),
+            merkle_root: compute_merkle_root(&[]),
+            state_root: AccountState::default().state_root(),
+            note_root: ShieldedState::default().note_root(),
+            bits: POW_LIMIT_BITS,
+            nonce: 0,
+        };
+        let mut block = Block {
+            header,
+            transactions: vec![],
+            hash: String::new(),
+        };
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    /// Hash of the header alone.
     pub fn calculate_hash(&self) -> String {
-        let data = serde_json::to_string(self).unwrap();
-        let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
+        self.header.calculate_hash()
+    }
+
+    /// Recomputes the Merkle root from the block's transactions.
+    pub fn calculate_merkle_root(&self) -> String {
+        compute_merkle_root(&self.transactions)
+    }
+
+    /// Builds a proof that the transaction at `tx_index` is committed to by the header.
+    pub fn merkle_proof(&self, tx_index: usize) -> Option<MerkleProof> {
+        let ids: Vec<String> = self.transactions.iter().map(Transaction::id).collect();
+        merkle_proof(&ids, tx_index)
+    }
+
+    /// Returns true if the block hash, read as a number, is at or below the target.
+    pub fn meets_difficulty(&self) -> bool {
+        target_from_compact(self.header.bits).is_some_and(|target| hash_meets_target(&self.hash, target))
+    }
+
+    /// Expected number of hashes needed to mine this block.
+    pub fn work(&self) -> u128 {
+        target_from_compact(self.header.bits).map_or(0, work_for_target)
+    }
+}
+
+fn compute_merkle_root(transactions: &[Transaction]) -> String {
+    let ids: Vec<String> = transactions.iter().map(Transaction::id).collect();
+    merkle_root(&ids)
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.index.encode(out);
+        self.timestamp.encode(out);
+        self.previous_hash.encode(out);
+        self.merkle_root.encode(out);
+        self.state_root.encode(out);
+        self.note_root.encode(out);
+        self.bits.encode(out);
+        self.nonce.encode(out);
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(BlockHeader {
+            index: u64::decode(reader)?,
+            timestamp: i64::decode(reader)?,
+            previous_hash: String::decode(reader)?,
+            merkle_root: String::decode(reader)?,
+            state_root: String::decode(reader)?,
+            note_root: String::decode(reader)?,
+            bits: u32::decode(reader)?,
+            nonce: u64::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        self.transactions.encode(out);
+        self.hash.encode(out);
+    }
+}
+
+impl Decode for Block {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Block {
+            header: BlockHeader::decode(reader)?,
+            transactions: Vec::decode(reader)?,
+            hash: String::decode(reader)?,
+        })
     }
 }
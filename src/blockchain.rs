@@ -1,149 +1,1022 @@
 // src/blockchain.rs
 
 use crate::block::Block;
+use crate::difficulty::{median_time_past, next_bits, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN, RETARGET_INTERVAL};
+use crate::encoding::{from_bytes, to_bytes, Decode, DecodeError, Encode, Reader, ENCODING_VERSION};
+use crate::legacy::LegacyBlockchain;
+use crate::mempool::Mempool;
+use crate::shielded::{ShieldedState, MAX_ANCHOR_AGE};
+use crate::state::{Account, AccountState};
+use crate::storage::BlockStore;
 use crate::transaction::Transaction;
-use std::collections::VecDeque;
-use crate::zk_proofs::verify_transaction_proof;
-use log::{info, error};
-use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use crate::zk_proofs::BatchVerifier;
+use log::{info, error, warn};
 use std::fs::File;
 use std::io::{self, Read, Write};
-use serde_json;
+use std::path::Path;
+use std::fmt;
+use chrono::Utc;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Reason a block was rejected during validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    EmptyChain,
+    InvalidGenesis,
+    InvalidIndex { expected: u64, found: u64 },
+    PreviousHashMismatch,
+    HashMismatch,
+    MerkleRootMismatch,
+    StateRootMismatch,
+    NoteRootMismatch,
+    InvalidDifficulty { expected: u32, found: u32 },
+    InvalidTimestamp,
+    InsufficientProofOfWork,
+    InvalidReward,
+    InvalidTransaction(usize),
+    InvalidProof(usize),
+    InvalidNonce(usize),
+    InsufficientFunds(usize),
+    InvalidAnchor(usize),
+    DoubleSpend(usize),
+    NoteTreeFull(usize),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::EmptyChain => write!(f, "chain has no genesis block"),
+            BlockError::InvalidGenesis => write!(f, "genesis block does not match this network"),
+            BlockError::InvalidIndex { expected, found } => {
+                write!(f, "expected index {}, found {}", expected, found)
+            }
+            BlockError::PreviousHashMismatch => write!(f, "previous_hash does not match the preceding block"),
+            BlockError::HashMismatch => write!(f, "stored hash does not match the block header"),
+            BlockError::MerkleRootMismatch => write!(f, "merkle root does not match the block's transactions"),
+            BlockError::StateRootMismatch => write!(f, "state root does not match the accounts after the block"),
+            BlockError::NoteRootMismatch => write!(f, "note root does not match the note commitments after the block"),
+            BlockError::InvalidDifficulty { expected, found } => {
+                write!(f, "expected difficulty bits {:#010x}, found {:#010x}", expected, found)
+            }
+            BlockError::InvalidTimestamp => {
+                write!(f, "timestamp is not after the median of recent blocks or is too far in the future")
+            }
+            BlockError::InsufficientProofOfWork => write!(f, "hash does not meet the required difficulty"),
+            BlockError::InvalidReward => write!(f, "block must end with exactly one mining reward"),
+            BlockError::InvalidTransaction(i) => write!(f, "transaction {} has an invalid signature", i),
+            BlockError::InvalidProof(i) => write!(f, "transaction {} has an invalid zk-SNARK proof", i),
+            BlockError::InvalidNonce(i) => write!(f, "transaction {} is out of order or replayed", i),
+            BlockError::InsufficientFunds(i) => write!(f, "transaction {} spends more than the sender holds", i),
+            BlockError::InvalidAnchor(i) => write!(f, "transaction {} is not anchored to a recent note root", i),
+            BlockError::DoubleSpend(i) => write!(f, "transaction {} reveals a nullifier that was already spent", i),
+            BlockError::NoteTreeFull(i) => write!(f, "transaction {} does not fit in the note commitment tree", i),
+        }
+    }
+}
+
+/// A validation failure naming the offending block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub block_index: u64,
+    pub kind: BlockError,
+}
+
+impl ValidationError {
+    pub(crate) fn new(block_index: u64, kind: BlockError) -> Self {
+        ValidationError { block_index, kind }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {}: {}", self.block_index, self.kind)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Reason a transaction was refused by the pending pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    InvalidSignature,
+    InvalidProof,
+    /// The nonce was already used by a confirmed or pending transaction.
+    NonceReused { expected: u64, found: u64 },
+    /// The nonce skips ahead of the sender's next expected nonce.
+    NonceGap { expected: u64, found: u64 },
+    /// The sender cannot cover the amount once its pending spends are counted.
+    InsufficientFunds { available: u64, required: u64 },
+    /// The pool is full of transactions paying a higher fee rate.
+    MempoolFull,
+    /// The shielded spend is not anchored to a recent note root.
+    InvalidAnchor,
+    /// A nullifier was already revealed by a confirmed or pending transaction.
+    DoubleSpend,
+    /// The note commitment tree has no room for the created notes.
+    NoteTreeFull,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::InvalidSignature => write!(f, "invalid transaction signature"),
+            TransactionError::InvalidProof => write!(f, "invalid zk-SNARK proof"),
+            TransactionError::NonceReused { expected, found } => {
+                write!(f, "nonce {} already used (next nonce is {})", found, expected)
+            }
+            TransactionError::NonceGap { expected, found } => {
+                write!(f, "nonce {} is ahead of the next nonce {}", found, expected)
+            }
+            TransactionError::InsufficientFunds { available, required } => {
+                write!(f, "insufficient funds: {} available, {} required", available, required)
+            }
+            TransactionError::MempoolFull => write!(f, "mempool is full and the fee rate is too low"),
+            TransactionError::InvalidAnchor => write!(f, "anchor is not a recent note root"),
+            TransactionError::DoubleSpend => write!(f, "note already spent"),
+            TransactionError::NoteTreeFull => write!(f, "note commitment tree is full"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl TransactionError {
+    /// Maps a ledger failure of transaction `i` onto the block-level reason.
+    pub(crate) fn in_block(&self, i: usize) -> BlockError {
+        match self {
+            TransactionError::InvalidSignature => BlockError::InvalidTransaction(i),
+            TransactionError::InvalidProof => BlockError::InvalidProof(i),
+            TransactionError::NonceReused { .. } | TransactionError::NonceGap { .. } => BlockError::InvalidNonce(i),
+            TransactionError::InsufficientFunds { .. } => BlockError::InsufficientFunds(i),
+            TransactionError::MempoolFull => unreachable!("applying a block never consults the mempool"),
+            TransactionError::InvalidAnchor => BlockError::InvalidAnchor(i),
+            TransactionError::DoubleSpend => BlockError::DoubleSpend(i),
+            TransactionError::NoteTreeFull => BlockError::NoteTreeFull(i),
+        }
+    }
+}
+
+/// Failure to extend or replace the local chain.
+#[derive(Debug)]
+pub enum ChainError {
+    /// The new blocks break a consensus rule.
+    Invalid(ValidationError),
+    /// The block store could not be read or written.
+    Storage(io::Error),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Invalid(e) => write!(f, "{}", e),
+            ChainError::Storage(e) => write!(f, "block store: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl From<ValidationError> for ChainError {
+    fn from(e: ValidationError) -> Self {
+        ChainError::Invalid(e)
+    }
+}
+
+impl From<io::Error> for ChainError {
+    fn from(e: io::Error) -> Self {
+        ChainError::Storage(e)
+    }
+}
+
+/// Most transactions, besides the reward, that a mined block includes.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 10;
+
+/// Blocks a chain kept in a block store holds in memory: enough to validate
+/// the next block, whose anchor, timestamp and difficulty depend on at most
+/// this many predecessors.
+const RECENT_BLOCKS: usize = MAX_ANCHOR_AGE;
+
+const _: () = assert!(RECENT_BLOCKS >= MEDIAN_TIME_SPAN && RECENT_BLOCKS >= RETARGET_INTERVAL as usize);
+
+fn stale_template() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "chain advanced while the block was being prepared")
+}
+
+/// Transactions chosen for the next block by `Blockchain::block_template`,
+/// with the state they lead to, waiting for the miner's reward.
+pub struct BlockTemplate {
+    previous_hash: String,
+    height: u64,
+    transactions: Vec<Transaction>,
+    accounts: AccountState,
+    shielded: ShieldedState,
+    bits: u32,
+    /// Earliest timestamp past the median time of the chain.
+    min_timestamp: i64,
+}
+
+/// A block mined from a template, with the state it leads to, ready for
+/// `Blockchain::submit_block`.
+pub struct MinedBlock {
+    block: Block,
+    accounts: AccountState,
+    shielded: ShieldedState,
+}
+
+impl MinedBlock {
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+}
+
+impl BlockTemplate {
+    /// Height of the block, which its reward needs as its nonce.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Fees of the chosen transactions, which go to the miner.
+    pub fn fees(&self) -> u64 {
+        self.transactions.iter().map(|tx| tx.fee).sum()
+    }
+
+    /// Builds the block with `reward_tx` paying the miner and runs the proof
+    /// of work. Needs no access to the chain, so it can run on a blocking
+    /// thread while the chain keeps serving peers. Fails with `InvalidInput`
+    /// if the reward is not a valid one for the template, e.g. because it pays
+    /// something other than an account key.
+    pub fn mine(self, reward_tx: Transaction) -> io::Result<MinedBlock> {
+        if !reward_tx.is_reward()
+            || !reward_tx.is_valid()
+            || reward_tx.nonce != self.height
+            || reward_tx.amount != Transaction::MINING_REWARD + self.fees()
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "reward does not match the block"));
+        }
+        let BlockTemplate { previous_hash, height, mut transactions, mut accounts, shielded, bits, min_timestamp } =
+            self;
+
+        // Add the reward transaction to the transactions being added to the new block
+        accounts.apply(&reward_tx).expect("reward transactions always apply");
+        info!("Reward transaction created for miner: {}", reward_tx.recipient);
+        transactions.push(reward_tx);
+
+        // Create the new block with all transactions, including the mining reward
+        let mut block = Block::new(height, previous_hash, transactions, bits);
+        // Blocks mined within the same second still need to move past the median time
+        block.header.timestamp = block.header.timestamp.max(min_timestamp);
+        block.header.state_root = accounts.state_root();
+        block.header.note_root = shielded.note_root();
+        block.hash = block.calculate_hash();
+
+        proof_of_work(&mut block);
+        Ok(MinedBlock { block, accounts, shielded })
+    }
+}
+
+/// Increments the nonce of `block` until its hash falls below its target.
+fn proof_of_work(block: &mut Block) {
+    while !block.meets_difficulty() {
+        block.header.nonce += 1;
+        block.hash = block.calculate_hash();
+    }
+    println!("Block mined: {}", block.hash);
+}
+
+#[derive(Debug)]
 pub struct Blockchain {
-    pub chain: Vec<Block>,
-    pub pending_transactions: VecDeque<Transaction>,
-    pub difficulty: u32,
+    /// The most recent blocks of the best chain, ending at the tip. A chain
+    /// kept in a block store holds the last `RECENT_BLOCKS` and reads older
+    /// ones from the store; other chains hold every block.
+    recent: Vec<Block>,
+    /// Height of `recent[0]`.
+    start: u64,
+    pub pending_transactions: Mempool,
+    /// Account balances and nonces at the tip of the chain.
+    pub accounts: AccountState,
+    /// Note commitment tree and spent nullifiers at the tip of the chain.
+    pub shielded: ShieldedState,
+    /// On-disk block store kept in step with the chain, if the chain was opened from one.
+    store: Option<BlockStore>,
 }
 
 impl Blockchain {
     pub fn new() -> Self {
         let mut blockchain = Blockchain {
-            chain: Vec::new(),
-            pending_transactions: VecDeque::new(),
-            difficulty: 2,
+            recent: Vec::new(),
+            start: 0,
+            pending_transactions: Mempool::default(),
+            accounts: AccountState::default(),
+            shielded: ShieldedState::default(),
+            store: None,
         };
         let genesis_block = blockchain.create_genesis_block();
-        blockchain.chain.push(genesis_block);
+        blockchain.recent.push(genesis_block);
         blockchain
     }
 
+    /// Opens the chain kept in the block store at `dir`, creating it with the
+    /// genesis block if the store is empty.
+    ///
+    /// The tip comes from the store's index and the account and shielded state
+    /// from the snapshot saved for it, so only the last `RECENT_BLOCKS` blocks
+    /// are read. Every block is read once only if the snapshot is missing or
+    /// stale. Stored blocks were validated before they were written, so they
+    /// are only checksummed and decoded here, not validated again.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut store = BlockStore::open(dir)?;
+        let mut blockchain = Blockchain::new();
+        if store.is_empty() {
+            store.append(&blockchain.recent[0])?;
+        } else if store.hash_at(0) != Some(blockchain.recent[0].hash.clone()) {
+            let pinned = store.read_block(0)?.header.previous_hash;
+            let loaded = &blockchain.recent[0].header.previous_hash;
+            if &pinned != loaded {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chain was created with zk-SNARK parameters {}, not the loaded {}", pinned, loaded),
+                ));
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidData, BlockError::InvalidGenesis.to_string()));
+        }
+        if let Some(mempool) = store.load_mempool()? {
+            blockchain.pending_transactions = mempool;
+        }
+        let saved_state = store.load_state()?;
+        let tip = store.tip_hash();
+
+        blockchain.start = store.len().saturating_sub(RECENT_BLOCKS as u64);
+        blockchain.recent = (blockchain.start..store.len())
+            .map(|height| store.read_block(height))
+            .collect::<io::Result<Vec<_>>>()?;
+        blockchain.store = Some(store);
+
+        // The saved state is only current if it was written for our tip;
+        // otherwise we stopped between appending a block and saving the state
+        match saved_state {
+            Some((saved_tip, accounts, shielded)) if tip.as_ref() == Some(&saved_tip) => {
+                blockchain.accounts = accounts;
+                blockchain.shielded = shielded;
+                blockchain.prune_pending();
+            }
+            _ => {
+                info!("Rebuilding account state from {} blocks", blockchain.block_count());
+                blockchain.refresh_account_state()?;
+                if let Some(store) = &blockchain.store {
+                    store.save_state(&blockchain.get_latest_block().hash, &blockchain.accounts, &blockchain.shielded)?;
+                }
+            }
+        }
+        Ok(blockchain)
+    }
+
+    /// Imports a chain saved as a single file into a new block store at `dir`.
+    ///
+    /// A file in `save_to_file`'s binary format is imported as it is. A
+    /// `blockchain.json` from the first versions of the node predates every
+    /// consensus rule, so its blocks are mined again on top of our genesis,
+    /// each paying its reward to the same miner. Its transfers were signed
+    /// over a message this version no longer uses and cannot be replayed, so
+    /// the balances they moved would change: such a chain fails with
+    /// `InvalidData` unless `drop_transfers` accepts losing them.
+    pub fn import_legacy(
+        dir: impl AsRef<Path>,
+        legacy_file: impl AsRef<Path>,
+        drop_transfers: bool,
+    ) -> io::Result<Self> {
+        let data = std::fs::read(legacy_file)?;
+        let mut blockchain: Blockchain = if data.first() == Some(&b'{') {
+            Self::remine_legacy(&LegacyBlockchain::from_json(&data)?, drop_transfers)?
+        } else {
+            from_bytes(&data)?
+        };
+        blockchain.validate_chain()?;
+
+        let store = BlockStore::open(dir)?;
+        if !store.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "block store already holds a chain"));
+        }
+        store.save_mempool(&blockchain.pending_transactions)?;
+        blockchain.refresh_account_state()?;
+        blockchain.store = Some(store);
+        blockchain.persist_from(0)?;
+        Ok(blockchain)
+    }
+
+    /// Mines a chain paying the rewards of the blocks of `legacy`, in order.
+    fn remine_legacy(legacy: &LegacyBlockchain, drop_transfers: bool) -> io::Result<Self> {
+        let dropped = legacy.transfers().count();
+        if dropped > 0 && !drop_transfers {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("legacy chain holds {} transfers, which cannot be carried over without changing balances", dropped),
+            ));
+        }
+        let mut blockchain = Blockchain::new();
+        for block in &legacy.chain[1..] {
+            match block.miner() {
+                Some(miner) => blockchain.mine_pending_transactions(miner)?,
+                None => warn!("Legacy block {} has no reward to an account key; skipping it", block.index),
+            }
+        }
+        if dropped > 0 {
+            warn!("Dropped {} legacy transfers, which cannot be carried over", dropped);
+        }
+        Ok(blockchain)
+    }
+
+    /// Changes how many transactions the pending pool holds, evicting the
+    /// lowest fee-rate ones if it now holds too many.
+    pub fn set_mempool_size(&mut self, max_size: usize) {
+        self.pending_transactions.set_max_size(max_size);
+    }
+
+    /// Saves the pending pool next to the block store. Blocks are written as
+    /// they are added, so this is all that is left to persist on shutdown.
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.store {
+            Some(store) => store.save_mempool(&self.pending_transactions),
+            None => Ok(()),
+        }
+    }
+
+    /// Rewrites the block store from `height` upwards to match the chain,
+    /// then saves the account and shielded state for the new tip and drops
+    /// the blocks that are no longer needed from memory.
+    ///
+    /// The chain in memory is already updated when this fails; reopening the
+    /// store rebuilds the state for whichever blocks reached it.
+    fn persist_from(&mut self, height: u64) -> io::Result<()> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        store.truncate(height)?;
+        for block in &self.recent[(height - self.start) as usize..] {
+            store.append(block)?;
+        }
+        let tip = &self.recent[self.recent.len() - 1];
+        store.save_state(&tip.hash, &self.accounts, &self.shielded)?;
+
+        let forgotten = self.recent.len().saturating_sub(RECENT_BLOCKS);
+        self.recent.drain(..forgotten);
+        self.start += forgotten as u64;
+        Ok(())
+    }
+
     fn create_genesis_block(&self) -> Block {
-        Block::new(
-            0,
-            String::from("0"),
-            vec![],
-        )
+        Block::genesis()
     }
 
     pub fn get_latest_block(&self) -> &Block {
-        self.chain.last().unwrap()
+        self.recent.last().unwrap()
+    }
+
+    /// Number of blocks in the chain, genesis included.
+    pub fn block_count(&self) -> u64 {
+        self.start + self.recent.len() as u64
+    }
+
+    /// The most recent blocks, ending at the tip: at least the ones the next
+    /// block is validated against, and all of them for a chain that is not
+    /// kept in a block store.
+    pub fn recent_blocks(&self) -> &[Block] {
+        &self.recent
     }
 
-    pub fn add_transaction(&mut self, transaction: Transaction) {
-        if !transaction.is_valid() {
-            eprintln!("Invalid transaction");
-            return;
+    /// Hash of the block at `height`, without reading the block.
+    pub fn block_hash(&self, height: u64) -> Option<String> {
+        match height.checked_sub(self.start) {
+            Some(offset) => self.recent.get(offset as usize).map(|block| block.hash.clone()),
+            None => self.store.as_ref()?.hash_at(height),
         }
-        self.pending_transactions.push_back(transaction);
     }
 
-    pub fn mine_pending_transactions(&mut self, miner_address: &str) {
-        let previous_hash = self.get_latest_block().hash.clone();
-        let mut transactions = vec![];
+    /// Block at `height`, read from the block store if it is no longer in memory.
+    pub fn block(&self, height: u64) -> io::Result<Block> {
+        let block = match (height.checked_sub(self.start), &self.store) {
+            (Some(offset), _) => self.recent.get(offset as usize).cloned(),
+            (None, Some(store)) => return store.read_block(height),
+            (None, None) => None,
+        };
+        block.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no block at height {}", height)))
+    }
+
+    /// Blocks from `height` up to the tip.
+    pub fn blocks_from(&self, height: u64) -> io::Result<Vec<Block>> {
+        self.blocks_between(height, self.block_count())
+    }
 
-        // Collect pending transactions up to a limit (e.g., 10)
-        for _ in 0..10 {
-            if let Some(tx) = self.pending_transactions.pop_front() {
-                transactions.push(tx);
-            } else {
+    /// Heights and hashes of blocks of the chain from the tip back to
+    /// genesis: the last ten, then ever further apart. A peer finds the last
+    /// block it shares with us among them however long ago the chains forked.
+    pub fn block_locator(&self) -> Vec<(u64, String)> {
+        let mut locator = vec![];
+        let mut height = self.block_count() - 1;
+        let mut step = 1;
+        loop {
+            locator.push((height, self.block_hash(height).expect("every height up to the tip has a block")));
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// Blocks after the first block of `locator` on our chain, for the peer
+    /// that sent it: at most `max_blocks`, and past the first one no more
+    /// than `max_bytes` encoded. Starts at genesis if the locator shares no
+    /// block with us, and is empty once the peer has our tip.
+    pub fn blocks_after(&self, locator: &[(u64, String)], max_blocks: usize, max_bytes: usize) -> io::Result<Vec<Block>> {
+        let from = locator
+            .iter()
+            .find(|(height, hash)| self.block_hash(*height).as_ref() == Some(hash))
+            .map_or(0, |(height, _)| height + 1);
+        let to = self.block_count().min(from.saturating_add(max_blocks as u64));
+        let mut blocks = vec![];
+        let mut size = 0;
+        for height in from..to {
+            let block = self.block(height)?;
+            size += to_bytes(&block).len();
+            if !blocks.is_empty() && size > max_bytes {
                 break;
             }
+            blocks.push(block);
         }
+        Ok(blocks)
+    }
+
+    /// Blocks at heights `from..to`.
+    fn blocks_between(&self, from: u64, to: u64) -> io::Result<Vec<Block>> {
+        (from..to).map(|height| self.block(height)).collect()
+    }
 
-        // Verify zk-SNARK proofs for each transaction
-        for tx in &transactions {
-            if !verify_transaction_proof(&tx.proof) {
-                error!("Invalid zk-SNARK proof in transaction");
-                return;
+    /// Next nonce `sender` should use, counting transactions still pending.
+    pub fn next_nonce(&self, sender: &str) -> u64 {
+        let pending = self
+            .pending_transactions
+            .iter()
+            .filter(|tx| tx.sender == sender && tx.uses_nonce())
+            .count() as u64;
+        self.accounts.nonce(sender) + pending
+    }
+
+    /// Confirmed balance of `sender` minus what its pending transactions already spend.
+    pub fn spendable_balance(&self, sender: &str) -> u64 {
+        let pending: u64 = self
+            .pending_transactions
+            .iter()
+            .filter(|tx| tx.sender == sender)
+            .map(|tx| tx.total_cost().unwrap_or(u64::MAX))
+            .fold(0u64, u64::saturating_add);
+        self.accounts.balance(sender).saturating_sub(pending)
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        if transaction.is_reward() || !transaction.is_valid() {
+            return Err(TransactionError::InvalidSignature);
+        }
+        if !transaction.verify_shielded() {
+            return Err(TransactionError::InvalidProof);
+        }
+
+        // Nullifiers must be fresh with respect to the chain and the pending pool
+        if let Some(bundle) = &transaction.shielded {
+            if !Self::is_recent_anchor(&self.recent, &bundle.anchor) {
+                return Err(TransactionError::InvalidAnchor);
+            }
+            let pending: HashSet<&Vec<u8>> = self
+                .pending_transactions
+                .iter()
+                .filter_map(|tx| tx.shielded.as_ref())
+                .flat_map(|pending| &pending.nullifiers)
+                .collect();
+            let mut seen = HashSet::new();
+            if bundle
+                .nullifiers
+                .iter()
+                .any(|nullifier| self.shielded.is_spent(nullifier) || pending.contains(nullifier) || !seen.insert(nullifier))
+            {
+                return Err(TransactionError::DoubleSpend);
             }
         }
 
-        // Create a reward transaction for the miner
-        let reward_tx = Transaction::new_reward(miner_address.to_string());
+        // Check against the sender's account as it will be once its pending
+        // transactions are mined; incoming pending credits are not counted
+        let sender = &transaction.sender;
+        let mut projected = AccountState::with_account(
+            sender,
+            Account { balance: self.spendable_balance(sender), nonce: self.next_nonce(sender) },
+        );
+        projected.apply(&transaction)?;
 
-        // Add the reward transaction to the transactions being added to the new block
-        transactions.push(reward_tx.clone());
-        info!("Reward transaction created for miner: {}", miner_address);
+        match self.pending_transactions.insert(transaction) {
+            Ok(Some(evicted)) => info!("Evicted low-fee transaction from {}", evicted.sender),
+            Ok(None) => {}
+            Err(_) => return Err(TransactionError::MempoolFull),
+        }
+        Ok(())
+    }
 
-        // Create the new block with all transactions, including the mining reward
-        let mut block = Block::new(
-            self.chain.len() as u64,
-            previous_hash,
+    /// Mines a block of the best-paying pending transactions, paying the
+    /// reward to `miner_address`, and writes it to the block store.
+    pub fn mine_pending_transactions(&mut self, miner_address: &str) -> io::Result<()> {
+        let Some(template) = self.block_template() else {
+            return Ok(());
+        };
+        let reward_tx = Transaction::new_reward(miner_address.to_string(), template.height, template.fees());
+        self.mine_block(template, reward_tx)
+    }
+
+    /// Chooses the best-paying pending transactions that still apply cleanly
+    /// on top of the chain for the next block, dropping the ones that do not.
+    /// Returns `None`, and drops the selection, if a proof among them fails.
+    ///
+    /// The reward for the block can then be proven and the block mined
+    /// without holding the chain (see `BlockTemplate::mine`), and the result
+    /// passed to `submit_block`.
+    pub fn block_template(&mut self) -> Option<BlockTemplate> {
+        let mut accounts = self.accounts.clone();
+        let mut shielded = self.shielded.clone();
+        let chain = &self.recent;
+        let (transactions, rejected) = self
+            .pending_transactions
+            .select(MAX_BLOCK_TRANSACTIONS, |tx| {
+                Self::apply_pending(chain, &mut accounts, &mut shielded, tx).is_ok()
+            });
+        for tx in &rejected {
+            self.pending_transactions.remove(tx);
+        }
+        if !rejected.is_empty() {
+            error!("Dropped {} pending transactions that no longer apply", rejected.len());
+        }
+
+        // Verify the zk-SNARK proofs of all selected transactions together
+        if let Some(i) = Self::first_invalid_proof(&transactions) {
+            error!("Invalid zk-SNARK proof in transaction {}", i);
+            for tx in &transactions {
+                self.pending_transactions.remove(tx);
+            }
+            return None;
+        }
+        Some(BlockTemplate {
+            previous_hash: self.get_latest_block().hash.clone(),
+            height: self.block_count(),
             transactions,
-        );
+            accounts,
+            shielded,
+            bits: next_bits(&self.recent),
+            min_timestamp: median_time_past(&self.recent) + 1,
+        })
+    }
 
-        // Proof of Work
-        self.proof_of_work(&mut block);
-        self.chain.push(block.clone()); // Clone the block before pushing
+    /// Mines the block of `template` with `reward_tx` paying the miner, and
+    /// writes it to the block store. Fails as `BlockTemplate::mine` and
+    /// `submit_block` do.
+    pub fn mine_block(&mut self, template: BlockTemplate, reward_tx: Transaction) -> io::Result<()> {
+        if template.previous_hash != self.get_latest_block().hash {
+            return Err(stale_template());
+        }
+        self.submit_block(template.mine(reward_tx)?)
+    }
+
+    /// Adds a block mined from a template of this chain and writes it to the
+    /// block store. Fails with `Interrupted` if the chain has moved on since
+    /// the template was made, in which case its transactions stay pending.
+    pub fn submit_block(&mut self, mined: MinedBlock) -> io::Result<()> {
+        let MinedBlock { block, accounts, shielded } = mined;
+        if block.header.previous_hash != self.get_latest_block().hash {
+            return Err(stale_template());
+        }
+        for tx in &block.transactions {
+            self.pending_transactions.remove(tx);
+        }
+        self.recent.push(block.clone()); // Clone the block before pushing
+        self.accounts = accounts;
+        self.shielded = shielded;
+        self.prune_pending();
         info!("Block mined: {}", block.hash);
 
         // Debugging: Log the transactions in the mined block
         for (i, tx) in block.transactions.iter().enumerate() {
             info!("Transaction {} in block: sender = {}, recipient = {}, amount = {}", i, tx.sender, tx.recipient, tx.amount);
         }
+        self.persist_from(block.header.index)
     }
 
+    /// Confirmed balance of `address`. Every accepted block is checked for
+    /// overspends, so this is the exact on-chain state.
     pub fn get_balance(&self, address: &str) -> u64 {
-        let mut balance: i64 = 0; // Using i64 to handle negative balances temporarily
-
-        // Iterate over each block in the chain
-        for block in &self.chain {
-            // Iterate over each transaction in the block
-            for tx in &block.transactions {
-                // If the address is the sender, decrease the balance
-                if tx.sender == address {
-                    balance -= tx.amount as i64;
-                }
-                // If the address is the recipient, increase the balance
-                if tx.recipient == address {
-                    balance += tx.amount as i64;
+        self.accounts.balance(address)
+    }
+
+    /// Validates every block of the local chain, starting from genesis and
+    /// reading blocks back from the block store if needed.
+    pub fn validate_chain(&self) -> io::Result<()> {
+        let chain = self.blocks_from(0)?;
+        self.validate_blocks(&chain).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Validates a full chain (e.g. one received from a peer) against the local rules.
+    pub fn validate_blocks(&self, chain: &[Block]) -> Result<(), ValidationError> {
+        let genesis = chain
+            .first()
+            .ok_or_else(|| ValidationError::new(0, BlockError::EmptyChain))?;
+        if genesis.hash != Block::genesis().hash {
+            return Err(ValidationError::new(genesis.header.index, BlockError::InvalidGenesis));
+        }
+
+        let mut accounts = AccountState::default();
+        let mut shielded = ShieldedState::default();
+        for height in 1..chain.len() {
+            self.validate_block(&chain[height], &chain[..height])?;
+            Self::connect_block(&mut accounts, &mut shielded, &chain[height])?;
+        }
+        Ok(())
+    }
+
+    /// Applies a block to `accounts` and `shielded` and checks the state and
+    /// note roots it commits to.
+    fn connect_block(
+        accounts: &mut AccountState,
+        shielded: &mut ShieldedState,
+        block: &Block,
+    ) -> Result<(), ValidationError> {
+        accounts.apply_block(block)?;
+        if block.header.state_root != accounts.state_root() {
+            return Err(ValidationError::new(block.header.index, BlockError::StateRootMismatch));
+        }
+        shielded.apply_block(block)?;
+        if block.header.note_root != shielded.note_root() {
+            return Err(ValidationError::new(block.header.index, BlockError::NoteRootMismatch));
+        }
+        Ok(())
+    }
+
+    /// Returns true if `anchor` is the note root of one of the last
+    /// `MAX_ANCHOR_AGE` blocks of `chain`.
+    fn is_recent_anchor(chain: &[Block], anchor: &[u8]) -> bool {
+        let anchor = hex::encode(anchor);
+        chain.iter().rev().take(MAX_ANCHOR_AGE).any(|block| block.header.note_root == anchor)
+    }
+
+    /// Applies a pending transaction on top of `chain` to both ledgers,
+    /// failing without side effects.
+    fn apply_pending(
+        chain: &[Block],
+        accounts: &mut AccountState,
+        shielded: &mut ShieldedState,
+        tx: &Transaction,
+    ) -> Result<(), TransactionError> {
+        if let Some(bundle) = &tx.shielded {
+            if !Self::is_recent_anchor(chain, &bundle.anchor) {
+                return Err(TransactionError::InvalidAnchor);
+            }
+        }
+        shielded.apply(tx)?;
+        if let Err(e) = accounts.apply(tx) {
+            shielded.revert(tx);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Recomputes `accounts` and `shielded` from the chain and drops pending
+    /// transactions that no longer apply on top of it.
+    fn refresh_account_state(&mut self) -> io::Result<()> {
+        let mut accounts = AccountState::default();
+        let mut shielded = ShieldedState::default();
+        for height in 0..self.block_count() {
+            let block = self.block(height)?;
+            if let Err(e) = accounts.apply_block(&block).and_then(|()| shielded.apply_block(&block)) {
+                error!("Local chain failed to apply: {}", e);
+            }
+        }
+        self.accounts = accounts;
+        self.shielded = shielded;
+        self.prune_pending();
+        Ok(())
+    }
+
+    /// Drops pending transactions that no longer apply on top of the chain.
+    fn prune_pending(&mut self) {
+        let mut accounts = self.accounts.clone();
+        let mut shielded = self.shielded.clone();
+        let chain = &self.recent;
+        self.pending_transactions
+            .retain(|tx| Self::apply_pending(chain, &mut accounts, &mut shielded, tx).is_ok());
+    }
+
+    /// Validates a single block against the chain it extends, of which
+    /// `chain` may hold just the last `RECENT_BLOCKS` blocks.
+    pub fn validate_block(&self, block: &Block, chain: &[Block]) -> Result<(), ValidationError> {
+        let fail = |kind| Err(ValidationError::new(block.header.index, kind));
+        let Some(previous) = chain.last() else {
+            return fail(BlockError::EmptyChain);
+        };
+
+        if block.header.index != previous.header.index + 1 {
+            return fail(BlockError::InvalidIndex { expected: previous.header.index + 1, found: block.header.index });
+        }
+        if block.header.previous_hash != previous.hash {
+            return fail(BlockError::PreviousHashMismatch);
+        }
+        if block.hash != block.calculate_hash() {
+            return fail(BlockError::HashMismatch);
+        }
+        if block.header.merkle_root != block.calculate_merkle_root() {
+            return fail(BlockError::MerkleRootMismatch);
+        }
+        if block.header.timestamp <= median_time_past(chain)
+            || block.header.timestamp > Utc::now().timestamp() + MAX_FUTURE_BLOCK_TIME
+        {
+            return fail(BlockError::InvalidTimestamp);
+        }
+        let expected_bits = next_bits(chain);
+        if block.header.bits != expected_bits {
+            return fail(BlockError::InvalidDifficulty { expected: expected_bits, found: block.header.bits });
+        }
+        if !block.meets_difficulty() {
+            return fail(BlockError::InsufficientProofOfWork);
+        }
+
+        // Exactly one reward, placed last, paying the subsidy plus collected fees
+        let rewards = block.transactions.iter().filter(|tx| tx.is_reward()).count();
+        let fees = block
+            .transactions
+            .iter()
+            .filter(|tx| !tx.is_reward())
+            .try_fold(0u64, |total, tx| total.checked_add(tx.fee));
+        let expected_reward = fees.and_then(|fees| fees.checked_add(Transaction::MINING_REWARD));
+        match block.transactions.last() {
+            Some(tx) if rewards == 1 && tx.is_reward() && Some(tx.amount) == expected_reward => {}
+            _ => return fail(BlockError::InvalidReward),
+        }
+
+        for (i, tx) in block.transactions.iter().enumerate() {
+            if !tx.is_valid() {
+                return fail(BlockError::InvalidTransaction(i));
+            }
+            if let Some(bundle) = &tx.shielded {
+                if !Self::is_recent_anchor(chain, &bundle.anchor) {
+                    return fail(BlockError::InvalidAnchor(i));
                 }
             }
         }
+        // Proofs are by far the most expensive check, so they come last
+        if let Some(i) = Self::first_invalid_proof(&block.transactions) {
+            return fail(BlockError::InvalidProof(i));
+        }
+        Ok(())
+    }
 
-        // If balance is negative, return 0, otherwise return the actual balance
-        if balance < 0 {
-            0
-        } else {
-            balance as u64
+    /// Index of the first transaction with an invalid proof. All proofs are
+    /// checked as one batch, and only checked one by one if the batch fails.
+    pub fn first_invalid_proof(transactions: &[Transaction]) -> Option<usize> {
+        let mut batch = BatchVerifier::new();
+        if transactions.iter().all(|tx| tx.queue_proofs(&mut batch)) && batch.verify() {
+            return None;
+        }
+        transactions.iter().position(|tx| !tx.verify_shielded())
+    }
+
+    /// Total work of a sequence of blocks.
+    pub fn cumulative_work(blocks: &[Block]) -> u128 {
+        blocks.iter().map(Block::work).sum()
+    }
+
+    /// Height of the last block shared by the local chain and `chain`, a run
+    /// of consecutive blocks starting at any height, if any.
+    pub fn find_common_ancestor(&self, chain: &[Block]) -> Option<usize> {
+        let first = chain.first()?;
+        let shared = chain
+            .iter()
+            .take_while(|block| self.block_hash(block.header.index).as_ref() == Some(&block.hash))
+            .count() as u64;
+        match (first.header.index + shared).checked_sub(1) {
+            // The first block may also just extend one of ours
+            Some(height) if shared > 0 || self.block_hash(height).as_ref() == Some(&first.header.previous_hash) => {
+                Some(height as usize)
+            }
+            _ => None,
         }
     }
 
-    fn proof_of_work(&self, block: &mut Block) {
-        // Increment the nonce until a valid hash is found (based on difficulty)
-        while &block.hash[..self.difficulty as usize] != &"0".repeat(self.difficulty as usize) {
-            block.nonce += 1;
-            block.hash = block.calculate_hash();
+    /// Applies fork choice against blocks received from a peer: consecutive
+    /// blocks of its chain, starting at genesis or at any height up to our
+    /// tip plus one.
+    ///
+    /// The candidate is adopted only if the branch after the common ancestor
+    /// carries more cumulative work than the local one and validates on top of
+    /// it. The local branch is disconnected by reverting its blocks from the
+    /// account state rather than replaying the chain. Transactions from
+    /// disconnected blocks that the new branch does not include go back to the
+    /// pending pool. Returns whether the local chain changed.
+    pub fn try_reorganize(&mut self, candidate: Vec<Block>) -> Result<bool, ChainError> {
+        let Some(first) = candidate.first() else {
+            return Err(ValidationError::new(0, BlockError::EmptyChain).into());
+        };
+        let Some(fork_height) = self.find_common_ancestor(&candidate) else {
+            let kind = if first.header.index == 0 { BlockError::InvalidGenesis } else { BlockError::PreviousHashMismatch };
+            return Err(ValidationError::new(first.header.index, kind).into());
+        };
+        let fork_height = fork_height as u64;
+        let shared = (fork_height + 1 - first.header.index) as usize;
+        let branch: Vec<Block> = candidate.into_iter().skip(shared).collect();
+        let disconnected = self.blocks_from(fork_height + 1)?;
+        if Self::cumulative_work(&branch) <= Self::cumulative_work(&disconnected) {
+            return Ok(false);
+        }
+
+        let mut accounts = self.accounts.clone();
+        let mut shielded = self.shielded.clone();
+        for block in disconnected.iter().rev() {
+            accounts.revert_block(block);
+            shielded.revert_block(block);
+        }
+        // Validate the new branch against our own copy of the blocks it
+        // extends, read back from the store if the fork is that old
+        let start = self.start.min((fork_height + 1).saturating_sub(RECENT_BLOCKS as u64));
+        let mut chain = self.blocks_between(start, fork_height + 1)?;
+        for block in branch {
+            self.validate_block(&block, &chain)?;
+            Self::connect_block(&mut accounts, &mut shielded, &block)?;
+            chain.push(block);
+        }
+
+        let included: HashSet<String> = chain[(fork_height + 1 - start) as usize..]
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(Transaction::id))
+            .collect();
+        self.recent = chain;
+        self.start = start;
+        let orphaned: Vec<Transaction> = disconnected
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_reward() && !included.contains(&tx.id()))
+            .collect();
+
+        self.pending_transactions.retain(|tx| !included.contains(&tx.id()));
+        info!(
+            "Reorganized at height {}: {} orphaned transactions returned to the pool",
+            fork_height,
+            orphaned.len()
+        );
+        for tx in orphaned {
+            if self.pending_transactions.contains(&tx) {
+                continue;
+            }
+            if let Err(tx) = self.pending_transactions.insert(tx) {
+                info!("Mempool full, discarding orphaned transaction from {}", tx.sender);
+            }
         }
-        println!("Block mined: {}", block.hash);
+        self.accounts = accounts;
+        self.shielded = shielded;
+        self.prune_pending();
+        self.persist_from(fork_height + 1)?;
+        Ok(true)
     }
 
     /// Saves the current blockchain state to a file.
     pub fn save_to_file(&self, filename: &str) -> io::Result<()> {
-        let serialized = serde_json::to_string_pretty(&self)?;
+        // The layout `load_from_file` decodes: the chain, then the pending pool
+        let mut serialized = vec![ENCODING_VERSION];
+        self.blocks_from(0)?.encode(&mut serialized);
+        self.pending_transactions.encode(&mut serialized);
         let mut file = File::create(filename)?;
-        file.write_all(serialized.as_bytes())?;
+        file.write_all(&serialized)?;
         Ok(())
     }
 
-    /// Loads the blockchain state from a file.
+    /// Loads the blockchain state from a file, rejecting chains that fail validation.
     pub fn load_from_file(filename: &str) -> io::Result<Self> {
         let mut file = File::open(filename)?;
-        let mut data = String::new();
-        file.read_to_string(&mut data)?;
-        let blockchain: Blockchain = serde_json::from_str(&data)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut blockchain: Blockchain = from_bytes(&data)?;
+        blockchain.validate_chain()?;
+        blockchain.refresh_account_state()?;
         Ok(blockchain)
     }
 }
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decode for Blockchain {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Blockchain {
+            recent: Vec::decode(reader)?,
+            start: 0,
+            pending_transactions: Mempool::decode(reader)?,
+            accounts: AccountState::default(),
+            shielded: ShieldedState::default(),
+            store: None,
+        })
+    }
+}
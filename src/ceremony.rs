@@ -0,0 +1,503 @@
+// src/ceremony.rs
+//
+// Multi-party setup for the Groth16 parameters of the shielded transfer circuit.
+//
+// Groth16 parameters are only sound if nobody knows the randomness they were
+// generated from. The setup runs in two phases, each safe as long as a single
+// participant in it was honest.
+//
+// The first phase builds powers of tau (see `powers`) for the secrets that do
+// not depend on the circuit. `Ceremony::new` starts from the generators, where
+// every secret is one, and each participant multiplies `tau`, `alpha` and
+// `beta` by factors of their own and then forgets them. `Ceremony::prepare`
+// closes the phase and derives the circuit's parameters from the powers,
+// with `delta` the group generator.
+//
+// The second phase follows Zcash's Sapling ceremony: each participant
+// multiplies `delta` by a secret factor (dividing the `h` and `l` query
+// elements by it) and then forgets the factor.
+//
+// Each contribution is published with a proof of knowledge of its factors,
+// bound to a hash of everything before it, so anyone can check the whole
+// chain from the generators to the final parameters without trusting whoever
+// passed the transcript along.
+
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::powers::{same_ratio, PowersOfTau};
+use crate::zk_proofs::SetupParameters;
+use bellman::groth16::Parameters;
+use bellman::SynthesisError;
+use blstrs::{Bls12, G1Affine, G1Projective, G2Affine, G2Projective, Scalar as Fr};
+use ff::Field;
+use group::prime::PrimeCurveAffine;
+use group::{Curve, Group};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+/// First bytes of a ceremony transcript file.
+pub const CEREMONY_MAGIC: &[u8; 8] = b"PBZKMPC3";
+
+const HASH_TO_G2_DOMAIN: &[u8] = b"privacy_blockchain/ceremony/r";
+
+// Which secret a proof of knowledge is for, so that one cannot stand in for
+// another
+const TAU: &[u8] = b"tau";
+const ALPHA: &[u8] = b"alpha";
+const BETA: &[u8] = b"beta";
+const DELTA: &[u8] = b"delta";
+
+/// Reason a ceremony transcript was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CeremonyError {
+    /// A phase has no contributions, so whoever started it knows its secrets.
+    NoContributions,
+    /// The contribution to the powers of tau at this index does not prove
+    /// knowledge of its factors or does not follow the ones before it.
+    InvalidPowersContribution(usize),
+    /// The powers of tau are not consecutive powers of the contributed secrets.
+    MalformedPowers,
+    /// The first phase has not been closed with `Ceremony::prepare`.
+    PowersOpen,
+    /// The initial parameters are not the ones the powers of tau give.
+    UnderivedParameters,
+    /// The contribution to the parameters at this index does not prove
+    /// knowledge of its factor or does not follow the ones before it.
+    InvalidContribution(usize),
+    /// Parameters other than `delta`, `h` and `l` differ from the initial ones.
+    ModifiedParameters,
+    /// `delta`, `h` or `l` do not match the chain of contributions.
+    InconsistentDelta,
+}
+
+impl fmt::Display for CeremonyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CeremonyError::NoContributions => write!(f, "a phase of the ceremony has no contributions"),
+            CeremonyError::InvalidPowersContribution(i) => write!(f, "contribution {} to the powers of tau is invalid", i),
+            CeremonyError::MalformedPowers => write!(f, "powers of tau do not match the contributions"),
+            CeremonyError::PowersOpen => write!(f, "powers of tau have not been prepared"),
+            CeremonyError::UnderivedParameters => write!(f, "initial parameters do not follow from the powers of tau"),
+            CeremonyError::InvalidContribution(i) => write!(f, "contribution {} is invalid", i),
+            CeremonyError::ModifiedParameters => write!(f, "parameters were modified outside of delta"),
+            CeremonyError::InconsistentDelta => write!(f, "final parameters do not match the contributions"),
+        }
+    }
+}
+
+impl std::error::Error for CeremonyError {}
+
+/// What a participant publishes for one secret factor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PublicKey {
+    /// The secret, in G1, after the contribution.
+    after: G1Affine,
+    /// A random `s` and `s * factor`.
+    s: G1Affine,
+    s_factor: G1Affine,
+    /// `r * factor`, where `r` is hashed from the secret's name, the
+    /// transcript, `s` and `s_factor`.
+    r_factor: G2Affine,
+    /// Hash of everything before the contribution.
+    transcript: [u8; 32],
+}
+
+fn hash_to_g2(secret: &[u8], transcript: &[u8; 32], s: &G1Affine, s_factor: &G1Affine) -> G2Affine {
+    let mut message = transcript.to_vec();
+    message.extend_from_slice(&s.to_compressed());
+    message.extend_from_slice(&s_factor.to_compressed());
+    G2Projective::hash_to_curve(&message, HASH_TO_G2_DOMAIN, secret).to_affine()
+}
+
+fn scale(points: &[G1Affine], factor: Fr) -> Vec<G1Affine> {
+    let scaled: Vec<G1Projective> = points.iter().map(|point| G1Projective::from(point) * factor).collect();
+    let mut affine = vec![G1Affine::identity(); scaled.len()];
+    G1Projective::batch_normalize(&scaled, &mut affine);
+    affine
+}
+
+fn random_factor<R: RngCore>(rng: &mut R) -> Fr {
+    loop {
+        let factor = Fr::random(&mut *rng);
+        if !bool::from(factor.is_zero()) {
+            return factor;
+        }
+    }
+}
+
+impl PublicKey {
+    /// Proves knowledge of `factor`, which took `secret` to `after`.
+    fn new<R: RngCore>(secret: &[u8], factor: Fr, after: G1Affine, transcript: [u8; 32], rng: &mut R) -> Self {
+        let s = G1Projective::random(&mut *rng).to_affine();
+        let s_factor = (s * factor).to_affine();
+        let r_factor = (hash_to_g2(secret, &transcript, &s, &s_factor) * factor).to_affine();
+        PublicKey { after, s, s_factor, r_factor, transcript }
+    }
+
+    /// Multiplies the parameters' `delta` by a fresh secret factor, which is
+    /// dropped on return.
+    fn contribute<R: RngCore>(params: &mut Parameters<Bls12>, transcript: [u8; 32], rng: &mut R) -> Self {
+        let factor = random_factor(rng);
+        let inverse = factor.invert().unwrap();
+        params.vk.delta_g1 = (params.vk.delta_g1 * factor).to_affine();
+        params.vk.delta_g2 = (params.vk.delta_g2 * factor).to_affine();
+        params.h = Arc::new(scale(&params.h, inverse));
+        params.l = Arc::new(scale(&params.l, inverse));
+        PublicKey::new(DELTA, factor, params.vk.delta_g1, transcript, rng)
+    }
+
+    /// Checks the proof of knowledge and that `after` is `before` times the
+    /// same factor.
+    fn verify(&self, secret: &[u8], before: G1Affine) -> bool {
+        let r = hash_to_g2(secret, &self.transcript, &self.s, &self.s_factor);
+        !bool::from(self.s_factor.is_identity())
+            && same_ratio((self.s, self.s_factor), (r, self.r_factor))
+            && same_ratio((before, self.after), (r, self.r_factor))
+    }
+}
+
+/// One participant's contribution to the powers of tau.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowersContribution {
+    tau: PublicKey,
+    alpha: PublicKey,
+    beta: PublicKey,
+}
+
+impl PowersContribution {
+    /// Hash a participant publishes so others can find their contribution in
+    /// the transcript.
+    pub fn hash(&self) -> String {
+        let mut encoded = vec![];
+        self.encode(&mut encoded);
+        hex::encode(Sha256::digest(&encoded))
+    }
+}
+
+/// One participant's contribution to the transfer circuit's parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    transfer: PublicKey,
+}
+
+impl Contribution {
+    /// Hash a participant publishes so others can find their contribution in
+    /// the transcript.
+    pub fn hash(&self) -> String {
+        let mut encoded = vec![];
+        self.encode(&mut encoded);
+        hex::encode(Sha256::digest(&encoded))
+    }
+}
+
+/// A setup in progress: the powers of tau and the contributions to them,
+/// then, once they are prepared, the second phase.
+pub struct Ceremony {
+    powers: PowersOfTau,
+    powers_contributions: Vec<PowersContribution>,
+    circuits: Option<CircuitPhase>,
+}
+
+/// The second phase: the parameters derived from the powers of tau, the
+/// current ones, and the contributions leading from one to the other.
+struct CircuitPhase {
+    initial: SetupParameters,
+    current: SetupParameters,
+    contributions: Vec<Contribution>,
+}
+
+impl CircuitPhase {
+    /// Hash of the initial parameters followed by every contribution so far.
+    fn transcript(&self) -> io::Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        self.initial.write(&mut hasher)?;
+        for contribution in &self.contributions {
+            let mut encoded = vec![];
+            contribution.encode(&mut encoded);
+            hasher.update(&encoded);
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+impl Ceremony {
+    /// Starts from powers of tau large enough for the circuit, with every
+    /// secret still one.
+    pub fn new() -> Result<Self, SynthesisError> {
+        Ok(Ceremony {
+            powers: PowersOfTau::new(SetupParameters::powers_size()?),
+            powers_contributions: vec![],
+            circuits: None,
+        })
+    }
+
+    pub fn powers_contributions(&self) -> &[PowersContribution] {
+        &self.powers_contributions
+    }
+
+    /// Contributions to the parameters, empty until the powers are prepared.
+    pub fn contributions(&self) -> &[Contribution] {
+        self.circuits.as_ref().map_or(&[], |circuits| &circuits.contributions)
+    }
+
+    /// Whether `prepare` has closed the first phase.
+    pub fn is_prepared(&self) -> bool {
+        self.circuits.is_some()
+    }
+
+    /// Hash of the size of the powers followed by every contribution to them.
+    fn powers_hasher(&self) -> Sha256 {
+        let mut hasher = Sha256::new();
+        hasher.update((self.powers.size() as u32).to_le_bytes());
+        hasher
+    }
+
+    /// Adds a contribution drawing its secrets from `rng` to the current
+    /// phase, and returns its hash. The secrets are gone once this returns.
+    pub fn contribute<R: RngCore>(&mut self, rng: &mut R) -> io::Result<String> {
+        let Some(circuits) = &mut self.circuits else {
+            let mut hasher = self.powers_hasher();
+            for contribution in &self.powers_contributions {
+                let mut encoded = vec![];
+                contribution.encode(&mut encoded);
+                hasher.update(&encoded);
+            }
+            let transcript = hasher.finalize().into();
+            let [tau, alpha, beta] = [(); 3].map(|_| random_factor(rng));
+            self.powers.transform(tau, alpha, beta);
+            let contribution = PowersContribution {
+                tau: PublicKey::new(TAU, tau, self.powers.tau(), transcript, rng),
+                alpha: PublicKey::new(ALPHA, alpha, self.powers.alpha(), transcript, rng),
+                beta: PublicKey::new(BETA, beta, self.powers.beta(), transcript, rng),
+            };
+            let hash = contribution.hash();
+            self.powers_contributions.push(contribution);
+            return Ok(hash);
+        };
+        let transcript = circuits.transcript()?;
+        let contribution = Contribution { transfer: PublicKey::contribute(&mut circuits.current.transfer, transcript, rng) };
+        let hash = contribution.hash();
+        circuits.contributions.push(contribution);
+        Ok(hash)
+    }
+
+    /// Closes the first phase and derives the initial parameters of the
+    /// circuit from the powers of tau. This evaluates every query on group
+    /// elements and takes a while; checking the result later is much cheaper.
+    pub fn prepare(&mut self) -> io::Result<()> {
+        if self.circuits.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "powers of tau are already prepared"));
+        }
+        if self.powers_contributions.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, CeremonyError::NoContributions));
+        }
+        let initial = SetupParameters::from_powers(&self.powers).map_err(io::Error::other)?;
+        let current = initial.clone();
+        self.circuits = Some(CircuitPhase { initial, current, contributions: vec![] });
+        Ok(())
+    }
+
+    /// Checks every contribution in order, that the powers of tau are what
+    /// they add up to, that the initial parameters follow from the powers, and
+    /// that the current parameters are exactly the initial ones transformed
+    /// by the second phase.
+    pub fn verify(&self) -> Result<(), CeremonyError> {
+        if self.powers_contributions.is_empty() {
+            return Err(CeremonyError::NoContributions);
+        }
+        let mut hasher = self.powers_hasher();
+        let mut secrets = [G1Affine::generator(); 3];
+        for (i, contribution) in self.powers_contributions.iter().enumerate() {
+            let transcript: [u8; 32] = hasher.clone().finalize().into();
+            let keys = [(&contribution.tau, TAU), (&contribution.alpha, ALPHA), (&contribution.beta, BETA)];
+            for ((key, name), secret) in keys.into_iter().zip(&mut secrets) {
+                if key.transcript != transcript || !key.verify(name, *secret) {
+                    return Err(CeremonyError::InvalidPowersContribution(i));
+                }
+                *secret = key.after;
+            }
+            let mut encoded = vec![];
+            contribution.encode(&mut encoded);
+            hasher.update(&encoded);
+        }
+        if secrets != [self.powers.tau(), self.powers.alpha(), self.powers.beta()] || !self.powers.is_well_formed() {
+            return Err(CeremonyError::MalformedPowers);
+        }
+
+        let circuits = self.circuits.as_ref().ok_or(CeremonyError::PowersOpen)?;
+        if !circuits.initial.derived_from(&self.powers).unwrap_or(false) {
+            return Err(CeremonyError::UnderivedParameters);
+        }
+        if circuits.contributions.is_empty() {
+            return Err(CeremonyError::NoContributions);
+        }
+        let mut hasher = Sha256::new();
+        circuits.initial.write(&mut hasher).expect("hashing cannot fail");
+        let mut delta = circuits.initial.transfer.vk.delta_g1;
+        for (i, contribution) in circuits.contributions.iter().enumerate() {
+            let transcript: [u8; 32] = hasher.clone().finalize().into();
+            let key = &contribution.transfer;
+            if key.transcript != transcript || !key.verify(DELTA, delta) {
+                return Err(CeremonyError::InvalidContribution(i));
+            }
+            delta = key.after;
+            let mut encoded = vec![];
+            contribution.encode(&mut encoded);
+            hasher.update(&encoded);
+        }
+        check_transformed(&circuits.initial.transfer, &circuits.current.transfer, delta)
+    }
+
+    /// The final parameters, once the whole ceremony verifies.
+    pub fn into_parameters(self) -> Result<SetupParameters, CeremonyError> {
+        self.verify()?;
+        Ok(self.circuits.expect("verified ceremonies are prepared").current)
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(CEREMONY_MAGIC)?;
+        writer.write_all(&[self.circuits.is_some() as u8])?;
+        if let Some(circuits) = &self.circuits {
+            circuits.initial.write(&mut writer)?;
+            circuits.current.write(&mut writer)?;
+        }
+        let mut encoded = vec![];
+        self.powers.encode(&mut encoded);
+        self.powers_contributions.encode(&mut encoded);
+        if let Some(circuits) = &self.circuits {
+            circuits.contributions.encode(&mut encoded);
+        }
+        writer.write_all(&encoded)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CEREMONY_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a ceremony transcript"));
+        }
+        let mut prepared = [0u8];
+        reader.read_exact(&mut prepared)?;
+        let parameters = match prepared[0] {
+            0 => None,
+            1 => Some((SetupParameters::read(&mut reader)?, SetupParameters::read(&mut reader)?)),
+            tag => return Err(DecodeError::InvalidTag(tag).into()),
+        };
+        let mut encoded = vec![];
+        reader.read_to_end(&mut encoded)?;
+        let mut rest = Reader::new(&encoded);
+        let powers = PowersOfTau::decode(&mut rest)?;
+        let powers_contributions = Vec::decode(&mut rest)?;
+        let circuits = match parameters {
+            Some((initial, current)) => Some(CircuitPhase { initial, current, contributions: Vec::decode(&mut rest)? }),
+            None => None,
+        };
+        if !rest.is_empty() {
+            return Err(DecodeError::TrailingBytes.into());
+        }
+        Ok(Ceremony { powers, powers_contributions, circuits })
+    }
+}
+
+/// Checks that `current` is `initial` with `delta` replaced by `delta` and the
+/// `h` and `l` queries divided by the same factor.
+fn check_transformed(
+    initial: &Parameters<Bls12>,
+    current: &Parameters<Bls12>,
+    delta: G1Affine,
+) -> Result<(), CeremonyError> {
+    let (old, new) = (&initial.vk, &current.vk);
+    if new.alpha_g1 != old.alpha_g1
+        || new.beta_g1 != old.beta_g1
+        || new.beta_g2 != old.beta_g2
+        || new.gamma_g2 != old.gamma_g2
+        || new.ic != old.ic
+        || current.a != initial.a
+        || current.b_g1 != initial.b_g1
+        || current.b_g2 != initial.b_g2
+        || current.h.len() != initial.h.len()
+        || current.l.len() != initial.l.len()
+    {
+        return Err(CeremonyError::ModifiedParameters);
+    }
+
+    // Compare the queries through random linear combinations, so that one
+    // pairing check covers every element
+    let g1 = G1Affine::generator();
+    let g2 = G2Affine::generator();
+    let combine = |points: &[G1Affine], weights: &[Fr]| {
+        let points: Vec<G1Projective> = points.iter().map(G1Projective::from).collect();
+        G1Projective::multi_exp(&points, weights).to_affine()
+    };
+    let consistent = |old: &[G1Affine], new: &[G1Affine]| {
+        let weights: Vec<Fr> = old.iter().map(|_| Fr::random(&mut OsRng)).collect();
+        same_ratio((combine(old, &weights), combine(new, &weights)), (current.vk.delta_g2, g2))
+    };
+    if new.delta_g1 != delta
+        || !same_ratio((g1, new.delta_g1), (g2, new.delta_g2))
+        || !consistent(&initial.h, &current.h)
+        || !consistent(&initial.l, &current.l)
+    {
+        return Err(CeremonyError::InconsistentDelta);
+    }
+    Ok(())
+}
+
+impl Encode for PublicKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.after.to_compressed());
+        out.extend_from_slice(&self.s.to_compressed());
+        out.extend_from_slice(&self.s_factor.to_compressed());
+        out.extend_from_slice(&self.r_factor.to_compressed());
+        out.extend_from_slice(&self.transcript);
+    }
+}
+
+fn decode_g1(reader: &mut Reader) -> Result<G1Affine, DecodeError> {
+    let bytes = <[u8; 48]>::try_from(reader.take(48)?).unwrap();
+    Option::from(G1Affine::from_compressed(&bytes)).ok_or(DecodeError::InvalidValue)
+}
+
+impl Decode for PublicKey {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let after = decode_g1(reader)?;
+        let s = decode_g1(reader)?;
+        let s_factor = decode_g1(reader)?;
+        let r_factor = <[u8; 96]>::try_from(reader.take(96)?).unwrap();
+        let r_factor = Option::from(G2Affine::from_compressed(&r_factor)).ok_or(DecodeError::InvalidValue)?;
+        let transcript = <[u8; 32]>::try_from(reader.take(32)?).unwrap();
+        Ok(PublicKey { after, s, s_factor, r_factor, transcript })
+    }
+}
+
+impl Encode for Contribution {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.transfer.encode(out);
+    }
+}
+
+impl Decode for Contribution {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Contribution { transfer: PublicKey::decode(reader)? })
+    }
+}
+
+impl Encode for PowersContribution {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.tau.encode(out);
+        self.alpha.encode(out);
+        self.beta.encode(out);
+    }
+}
+
+impl Decode for PowersContribution {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(PowersContribution {
+            tau: PublicKey::decode(reader)?,
+            alpha: PublicKey::decode(reader)?,
+            beta: PublicKey::decode(reader)?,
+        })
+    }
+}
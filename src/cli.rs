@@ -1,13 +1,48 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use crate::address::Address;
+use crate::history::{HistoryEntry, WalletHistory};
+use crate::viewing::{PaymentAddress, ShieldedHistory, ViewingKey};
 use crate::wallet::Wallet;
+use crate::wallet_dir::{WalletDir, WalletDirError};
+use crate::hd::{generate_mnemonic, Mnemonic, DEFAULT_WORD_COUNT, HARDENED};
+use crate::shielded::Note;
 use crate::transaction::Transaction;
 use crate::blockchain::Blockchain;
+use crate::difficulty::next_bits;
 use crate::network::Network;
+use crate::prover::ProverPool;
+use crate::ceremony::Ceremony;
+use crate::zk_proofs::{parameters, SetupParameters};
+use rand_core::OsRng;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use zeroize::Zeroizing;
 
-pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Network>>) {
-    let matches = App::new("Privacy Blockchain")
+/// Default location of the zk-SNARK parameters file.
+pub const PARAMS_FILE: &str = "params.bin";
+
+/// Default directory of the node's wallets.
+pub const WALLET_DIR: &str = "wallets";
+
+/// Name given to a wallet created without one.
+pub const DEFAULT_WALLET: &str = "default";
+
+/// Single wallet file used by older versions, imported as the default wallet.
+pub const LEGACY_WALLET_FILE: &str = "wallet.dat";
+
+const WALLET_COMMANDS: &str =
+    "create, restore, watch, account, balance, history, viewing-key, payment-address, unlock, lock, list, use, rename, delete";
+
+/// Default location of a setup ceremony transcript.
+pub const CEREMONY_FILE: &str = "ceremony.bin";
+
+/// Command-line arguments of the node.
+pub fn app() -> App<'static> {
+    App::new("Privacy Blockchain")
         .version("1.0")
         .author("Your Name")
         .about("A Rust-based privacy-preserving blockchain")
@@ -19,19 +54,145 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
                 .default_value("6000")
                 .help("Port number for the node"),
         )
+        .arg(
+            Arg::with_name("params")
+                .long("params")
+                .takes_value(true)
+                .default_value(PARAMS_FILE)
+                .help("zk-SNARK parameters file produced by `setup`, or a verified `ceremony` transcript"),
+        )
+        .arg(
+            Arg::with_name("mempool-size")
+                .long("mempool-size")
+                .takes_value(true)
+                .help("Most pending transactions to keep before evicting the lowest fee rates"),
+        )
+        .arg(
+            Arg::with_name("drop-legacy-transfers")
+                .long("drop-legacy-transfers")
+                .help("Import a blockchain.json whose transfers cannot be carried over, dropping them"),
+        )
+        .arg(
+            Arg::with_name("wallet-dir")
+                .long("wallet-dir")
+                .takes_value(true)
+                .default_value(WALLET_DIR)
+                .help("Directory holding the named wallets"),
+        )
+        .subcommand(
+            SubCommand::with_name("setup")
+                .about("Generate the zk-SNARK parameters file and exit")
+                .arg(Arg::with_name("output").default_value(PARAMS_FILE).help("File to write the parameters to")),
+        )
+        .subcommand(
+            SubCommand::with_name("ceremony")
+                .about("Run a multi-party setup whose transcript can be used as the parameters file")
+                .subcommand(
+                    SubCommand::with_name("init")
+                        .about("Start a new ceremony")
+                        .arg(transcript_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("contribute")
+                        .about("Add your randomness to the current phase of the ceremony")
+                        .arg(transcript_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("prepare")
+                        .about("Close the powers of tau and derive the circuit parameters from them")
+                        .arg(transcript_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Check every contribution to the ceremony")
+                        .arg(transcript_arg()),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("wallet")
-                .about("Manage your wallet")
-                .subcommand(SubCommand::with_name("create").about("Create a new wallet"))
-                .subcommand(SubCommand::with_name("balance").about("Check wallet balance")),
+                .about("Manage your wallets")
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a new passphrase-protected wallet, asking before replacing one")
+                        .arg(wallet_name_arg(false)),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("Rebuild a wallet from its recovery phrase")
+                        .arg(wallet_name_arg(false))
+                        .arg(Arg::with_name("account").help("Account to sign with (defaults to 0)")),
+                )
+                .subcommand(
+                    SubCommand::with_name("watch")
+                        .about("Add a view-only wallet from a viewing key, showing shielded notes without spending them")
+                        .arg(wallet_name_arg(true))
+                        .arg(Arg::with_name("viewing-key").required(true).help("Incoming or full viewing key")),
+                )
+                .subcommand(
+                    SubCommand::with_name("account")
+                        .about("Switch the wallet to another account of its recovery phrase")
+                        .arg(Arg::with_name("number").required(true).help("Account number"))
+                        .arg(wallet_arg()),
+                )
+                .subcommand(SubCommand::with_name("balance").about("Check wallet balance").arg(wallet_arg()))
+                .subcommand(
+                    SubCommand::with_name("history")
+                        .about("List the wallet's sent, received and mined transactions, of all accounts while unlocked")
+                        .arg(wallet_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("viewing-key")
+                        .about("Show the key disclosing the wallet's shielded notes, for an auditor or 'wallet watch'")
+                        .arg(
+                            Arg::with_name("kind")
+                                .possible_values(["full", "incoming"])
+                                .default_value("full")
+                                .help("'incoming' only discloses received notes, not spends"),
+                        )
+                        .arg(wallet_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("payment-address")
+                        .about("Show the shielded address others can 'shield' funds to")
+                        .arg(wallet_arg()),
+                )
+                .subcommand(SubCommand::with_name("unlock").about("Decrypt the wallet for signing").arg(wallet_arg()))
+                .subcommand(SubCommand::with_name("lock").about("Forget the decrypted wallet"))
+                .subcommand(SubCommand::with_name("list").about("List the wallets, marking the selected one"))
+                .subcommand(
+                    SubCommand::with_name("use")
+                        .about("Select the wallet used when none is named")
+                        .arg(wallet_name_arg(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("rename")
+                        .about("Rename a wallet")
+                        .arg(wallet_name_arg(true))
+                        .arg(Arg::with_name("new-name").required(true).help("New wallet name")),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete a wallet after typing its name to confirm")
+                        .arg(wallet_name_arg(true)),
+                ),
         )
         .subcommand(
             SubCommand::with_name("transaction")
                 .about("Create a new transaction")
-                .arg(Arg::with_name("recipient").required(true).help("Recipient's public key"))
-                .arg(Arg::with_name("amount").required(true).help("Amount to send")),
+                .arg(Arg::with_name("recipient").required(true).help("Recipient's address"))
+                .arg(Arg::with_name("amount").required(true).help("Amount to send"))
+                .arg(Arg::with_name("fee").help("Fee paid to the miner (defaults to 0)"))
+                .arg(wallet_arg()),
         )
-        .subcommand(SubCommand::with_name("mine").about("Mine pending transactions"))
+        .subcommand(
+            SubCommand::with_name("shield")
+                .about("Move funds from the wallet into a new shielded note")
+                .arg(Arg::with_name("recipient").required(true).help("Recipient's payment address"))
+                .arg(Arg::with_name("amount").required(true).help("Amount to shield"))
+                .arg(Arg::with_name("fee").help("Fee paid to the miner (defaults to 0)"))
+                .arg(wallet_arg()),
+        )
+        .subcommand(SubCommand::with_name("mine").about("Mine pending transactions").arg(wallet_arg()))
         .subcommand(
             SubCommand::with_name("connect")
                 .about("Connect to a peer node")
@@ -39,8 +200,224 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
         )
         .subcommand(SubCommand::with_name("peers").about("List connected peers"))
         .subcommand(SubCommand::with_name("status").about("Show blockchain status and peer information"))
-        .get_matches();
+}
+
+/// `--wallet <name>`, selecting a wallet other than the current one.
+fn wallet_arg() -> Arg<'static> {
+    Arg::with_name("wallet")
+        .long("wallet")
+        .takes_value(true)
+        .help("Wallet to use instead of the one selected with 'wallet use'")
+}
+
+fn wallet_name_arg(required: bool) -> Arg<'static> {
+    Arg::with_name("name").required(required).help("Wallet name")
+}
 
+fn transcript_arg() -> Arg<'static> {
+    Arg::with_name("transcript").default_value(CEREMONY_FILE).help("Ceremony transcript file")
+}
+
+/// Runs a `ceremony` subcommand. Contributions replace the transcript only
+/// once it has been written out in full.
+pub fn run_ceremony(matches: &ArgMatches) -> io::Result<()> {
+    let (command, args) = matches
+        .subcommand()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected init, contribute, prepare or verify"))?;
+    let path = args.value_of("transcript").unwrap();
+    match command {
+        "init" => {
+            if Path::new(path).exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path)));
+            }
+            let ceremony = Ceremony::new().map_err(io::Error::other)?;
+            save_ceremony(&ceremony, path)?;
+            println!("Ceremony started in {}", path);
+        }
+        "contribute" => {
+            let mut ceremony = Ceremony::read(BufReader::new(File::open(path)?))?;
+            let hash = ceremony.contribute(&mut OsRng)?;
+            save_ceremony(&ceremony, path)?;
+            if ceremony.is_prepared() {
+                println!("Contribution {} added: {}", ceremony.contributions().len(), hash);
+            } else {
+                println!("Contribution {} to the powers of tau added: {}", ceremony.powers_contributions().len(), hash);
+            }
+        }
+        "prepare" => {
+            let mut ceremony = Ceremony::read(BufReader::new(File::open(path)?))?;
+            println!("Deriving the circuit parameters, this may take a while...");
+            ceremony.prepare()?;
+            save_ceremony(&ceremony, path)?;
+            println!("Powers of tau closed; contributions now go to the circuit parameters");
+        }
+        "verify" => {
+            let ceremony = Ceremony::read(BufReader::new(File::open(path)?))?;
+            for (i, contribution) in ceremony.powers_contributions().iter().enumerate() {
+                println!("Powers of tau contribution {}: {}", i + 1, contribution.hash());
+            }
+            for (i, contribution) in ceremony.contributions().iter().enumerate() {
+                println!("Contribution {}: {}", i + 1, contribution.hash());
+            }
+            let params = ceremony.into_parameters().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            println!("Transcript verified; it can be passed to --params");
+            println!("Verifying key fingerprint: {}", params.fingerprint());
+        }
+        _ => unreachable!("clap only accepts known subcommands"),
+    }
+    Ok(())
+}
+
+fn save_ceremony(ceremony: &Ceremony, path: &str) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    ceremony.write(&mut writer)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// Runs the trusted setup for the transfer circuit and writes the parameters to
+/// `path`. Every node of a chain must then be started with this same file.
+pub fn run_setup(path: &str) -> io::Result<()> {
+    if Path::new(path).exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path)));
+    }
+    println!("Generating zk-SNARK parameters, this may take a few minutes...");
+    let params = SetupParameters::generate(&mut OsRng).map_err(io::Error::other)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    params.write(&mut writer)?;
+    writer.flush()?;
+    println!("Parameters written to {}", path);
+    println!("Verifying key fingerprint: {}", params.fingerprint());
+    Ok(())
+}
+
+/// Reads a line from stdin after printing `message`. The input is echoed, so
+/// secrets go through `prompt_secret` instead.
+fn prompt(message: &str) -> Zeroizing<String> {
+    print!("{}", message);
+    let _ = io::stdout().flush();
+    let mut line = Zeroizing::new(String::new());
+    io::stdin().read_line(&mut line).expect("Failed to read line");
+    Zeroizing::new(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads a passphrase or recovery phrase from the terminal after printing
+/// `message`, with echo turned off.
+fn prompt_secret(message: &str) -> Zeroizing<String> {
+    Zeroizing::new(rpassword::prompt_password(message).expect("Failed to read from the terminal"))
+}
+
+/// Asks for a new passphrase twice, returning `None` if the entries differ
+/// or are empty.
+fn new_passphrase() -> Option<Zeroizing<String>> {
+    let passphrase = prompt_secret("New passphrase: ");
+    if passphrase.is_empty() {
+        println!("The passphrase must not be empty.");
+        return None;
+    }
+    if *prompt_secret("Repeat passphrase: ") != *passphrase {
+        println!("Passphrases do not match.");
+        return None;
+    }
+    Some(passphrase)
+}
+
+/// Opens the wallet directory, moving in the single wallet file of older
+/// versions as the default wallet.
+fn open_wallet_dir(dir: &str) -> Result<WalletDir, WalletDirError> {
+    let wallets = WalletDir::open(dir)?;
+    if Path::new(LEGACY_WALLET_FILE).exists() && !wallets.contains(DEFAULT_WALLET) {
+        wallets.import(LEGACY_WALLET_FILE, DEFAULT_WALLET)?;
+        if wallets.current()?.is_none() {
+            wallets.set_current(DEFAULT_WALLET)?;
+        }
+        println!("Moved {} into {} as wallet '{}'", LEGACY_WALLET_FILE, dir, DEFAULT_WALLET);
+    }
+    Ok(wallets)
+}
+
+/// Removes `--wallet <name>` or `--wallet=<name>` from `args`, returning
+/// the name.
+fn take_wallet_option<'a>(args: &mut Vec<&'a str>) -> Result<Option<&'a str>, String> {
+    let Some(i) = args.iter().position(|arg| *arg == "--wallet" || arg.starts_with("--wallet=")) else {
+        return Ok(None);
+    };
+    let name = match args.remove(i).strip_prefix("--wallet=") {
+        Some(name) => name,
+        None if i < args.len() => args.remove(i),
+        None => return Err("--wallet needs a wallet name.".to_string()),
+    };
+    Ok(Some(name))
+}
+
+/// Name and file of the wallet named by `selector`, or of the selected
+/// wallet. Prints why there is none.
+fn select_wallet(wallets: &WalletDir, selector: Option<&str>) -> Option<(String, PathBuf)> {
+    match wallets.resolve(selector).and_then(|name| Ok((wallets.path(&name)?, name))) {
+        Ok((path, name)) => Some((name, path)),
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
+/// Returns true if `path` holds a view-only wallet, which has no keys to
+/// unlock.
+fn is_view_only(path: &Path) -> bool {
+    ViewingKey::is_viewing_file(path).unwrap_or(false)
+}
+
+/// Returns true if the wallet named by `selector`, or the selected wallet,
+/// is view-only.
+fn selector_is_view_only(wallets: &WalletDir, selector: Option<&str>) -> bool {
+    wallets.resolve(selector).and_then(|name| wallets.path(&name)).is_ok_and(|path| is_view_only(&path))
+}
+
+/// Address of the account with hex public key `key`, or the key itself if
+/// it is not a valid public key.
+fn display_address(key: &str) -> String {
+    Address::from_public_key_hex(key).map_or_else(|_| key.to_string(), |address| address.to_string())
+}
+
+/// Prints `pending` transactions, then the confirmed `entries` newest first,
+/// then the `balance` they add up to.
+fn print_history(entries: &[HistoryEntry], pending: &[HistoryEntry], tip_height: Option<u64>, balance: i128) {
+    if entries.is_empty() && pending.is_empty() {
+        println!("No transactions yet.");
+        return;
+    }
+    let tip_height = tip_height.unwrap_or(0);
+    println!("{:>8} {:>6} {:>8} {:>12} {:>8}  {:<64}  Transaction", "Height", "Conf", "Type", "Net", "Fee", "Counterparty");
+    for entry in pending.iter().chain(entries.iter().rev()) {
+        let height = entry.height.map_or_else(|| "pending".to_string(), |height| height.to_string());
+        println!(
+            "{:>8} {:>6} {:>8} {:>12} {:>8}  {:<64}  {}",
+            height,
+            entry.confirmations(tip_height),
+            entry.direction.to_string(),
+            entry.net_change(),
+            entry.fee,
+            display_address(&entry.counterparty),
+            entry.tx_id
+        );
+    }
+    println!("Confirmed balance: {}", balance);
+}
+
+/// Asks the user to type `name` before its wallet is replaced.
+fn confirm_overwrite(name: &str) -> bool {
+    println!("Wallet '{}' already exists. Replacing it destroys its keys unless you have its recovery phrase.", name);
+    *prompt(&format!("Type '{}' to replace it: ", name)) == *name
+}
+
+pub async fn run_cli(
+    blockchain: Arc<Mutex<Blockchain>>,
+    network: Arc<Mutex<Network>>,
+    prover: Arc<ProverPool>,
+    matches: ArgMatches,
+) {
     // Clone the port value before using it inside the async block
     let port = matches.value_of("port").unwrap().to_string();
 
@@ -50,6 +427,27 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
         network_clone.lock().await.start_server(&format!("127.0.0.1:{}", port)).await;
     });
 
+    let wallet_dir = matches.value_of("wallet-dir").unwrap();
+    let wallets = match open_wallet_dir(wallet_dir) {
+        Ok(wallets) => wallets,
+        Err(e) => {
+            eprintln!("Failed to open wallet directory {}: {}", wallet_dir, e);
+            return;
+        }
+    };
+    if let Ok(Some(name)) = wallets.current() {
+        if Wallet::is_plaintext_file(wallets.path(&name).unwrap()).unwrap_or(false) {
+            println!("Warning: wallet '{}' stores its key unencrypted. Run 'wallet unlock' to encrypt it.", name);
+        }
+    }
+    // Decrypted only between 'wallet unlock' and 'wallet lock', with its name
+    let mut unlocked: Option<(String, Wallet)> = None;
+    // Scanned history of each account 'wallet history' was run for, and
+    // whether it covers every account of the unlocked wallet
+    let mut histories: HashMap<(String, bool), WalletHistory> = HashMap::new();
+    // Scanned notes of each viewing key a view-only wallet was checked with
+    let mut shielded_histories: HashMap<String, ShieldedHistory> = HashMap::new();
+
     // Interactive CLI loop
     loop {
         println!("Enter a command (type 'exit' to quit):");
@@ -61,43 +459,407 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
             break;
         }
 
-        let args: Vec<&str> = input.split_whitespace().collect();
+        let mut args: Vec<&str> = input.split_whitespace().collect();
         if args.is_empty() {
             continue;
         }
+        let selector = match take_wallet_option(&mut args) {
+            Ok(selector) => selector,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
 
         match args[0] {
             "wallet" => {
                 if args.len() > 1 {
                     match args[1] {
-                        "create" => {
-                            let wallet = Wallet::new();
-                            if let Err(e) = wallet.save_to_file("wallet.dat") {
+                        "create" | "restore" => {
+                            let name = args.get(2).copied().or(selector).unwrap_or(DEFAULT_WALLET);
+                            let path = match wallets.path(name) {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    println!("{}", e);
+                                    continue;
+                                }
+                            };
+                            let account = match (args[1], args.get(3)) {
+                                (_, None) => 0,
+                                ("restore", Some(a)) => match a.parse::<u32>() {
+                                    Ok(a) if a < HARDENED => a,
+                                    _ => {
+                                        eprintln!("Invalid account. Please enter a number below {}.", HARDENED);
+                                        continue;
+                                    }
+                                },
+                                _ => {
+                                    println!("Usage: wallet create [name]");
+                                    continue;
+                                }
+                            };
+                            if wallets.contains(name) && !confirm_overwrite(name) {
+                                println!("Wallet '{}' was kept.", name);
+                                continue;
+                            }
+                            let mnemonic = if args[1] == "create" {
+                                generate_mnemonic(DEFAULT_WORD_COUNT).expect("default word count is valid")
+                            } else {
+                                let phrase = prompt_secret("Recovery phrase: ");
+                                match Mnemonic::parse(phrase.as_str()) {
+                                    Ok(m) => m,
+                                    Err(e) => {
+                                        eprintln!("Invalid recovery phrase: {}", e);
+                                        continue;
+                                    }
+                                }
+                            };
+                            let Some(passphrase) = new_passphrase() else {
+                                continue;
+                            };
+                            let wallet = Wallet::from_mnemonic(&mnemonic, "", account).expect("account was checked above");
+                            if let Err(e) = wallet.save_to_file(&path, &passphrase) {
                                 eprintln!("Failed to save wallet: {}", e);
+                                continue;
+                            }
+                            if let Err(e) = wallets.set_current(name) {
+                                eprintln!("Failed to select wallet '{}': {}", name, e);
+                            }
+                            if args[1] == "create" {
+                                println!("Wallet '{}' created and saved to {}", name, path.display());
+                                println!("Address: {}", wallet.address());
+                                println!("Write down this recovery phrase and keep it offline.");
+                                println!("'wallet restore' rebuilds every account from it:");
+                                println!("{}", Zeroizing::new(mnemonic.to_string()).as_str());
                             } else {
-                                println!("Wallet created and saved to wallet.dat");
-                                println!("Public Key: {}", wallet.public_key_hex());
+                                println!("Wallet '{}' restored and saved to {}", name, path.display());
+                                println!("Account {}: {}", account, wallet.address());
+                            }
+                            unlocked = Some((name.to_string(), wallet));
+                        }
+                        "watch" => {
+                            let (Some(&name), Some(&key)) = (args.get(2), args.get(3)) else {
+                                println!("Usage: wallet watch <name> <viewing key>");
+                                continue;
+                            };
+                            let key = match ViewingKey::parse(key) {
+                                Ok(key) => key,
+                                Err(e) => {
+                                    eprintln!("Invalid viewing key: {}", e);
+                                    continue;
+                                }
+                            };
+                            let path = match wallets.path(name) {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    println!("{}", e);
+                                    continue;
+                                }
+                            };
+                            if wallets.contains(name) && !confirm_overwrite(name) {
+                                println!("Wallet '{}' was kept.", name);
+                                continue;
+                            }
+                            if let Err(e) = key.save_to_file(&path) {
+                                eprintln!("Failed to save wallet: {}", e);
+                                continue;
+                            }
+                            if let Err(e) = wallets.set_current(name) {
+                                eprintln!("Failed to select wallet '{}': {}", name, e);
+                            }
+                            if unlocked.as_ref().is_some_and(|(unlocked_name, _)| unlocked_name == name) {
+                                unlocked = None;
+                            }
+                            println!("View-only wallet '{}' saved to {}", name, path.display());
+                            if key.full().is_none() {
+                                println!("An incoming viewing key shows notes received, but not when they are spent.");
+                            }
+                        }
+                        "account" => {
+                            let account = match args.get(2).map(|a| a.parse::<u32>()) {
+                                Some(Ok(a)) if a < HARDENED => a,
+                                _ => {
+                                    println!("Usage: wallet account <number below {}>", HARDENED);
+                                    continue;
+                                }
+                            };
+                            let Some((name, path)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            if is_view_only(&path) {
+                                println!("Wallet '{}' is view-only and has no accounts.", name);
+                                continue;
+                            }
+                            // The file records the account, so it is re-encrypted
+                            let passphrase = prompt_secret("Passphrase: ");
+                            let wallet = match Wallet::load_from_file(&path, &passphrase) {
+                                Ok(wallet) => wallet,
+                                Err(e) => {
+                                    eprintln!("Failed to unlock wallet: {}", e);
+                                    continue;
+                                }
+                            };
+                            let Some(wallet) = wallet.with_account(account).expect("account was checked above") else {
+                                println!("Wallet '{}' holds a single key without a recovery phrase.", name);
+                                continue;
+                            };
+                            if let Err(e) = wallet.save_to_file(&path, &passphrase) {
+                                eprintln!("Failed to save wallet: {}", e);
+                            } else {
+                                println!("Switched '{}' to account {}: {}", name, account, wallet.address());
+                                unlocked = Some((name, wallet));
+                            }
+                        }
+                        "balance" | "history" if selector_is_view_only(&wallets, selector) => {
+                            let Some((_, path)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            let key = match ViewingKey::load_from_file(path) {
+                                Ok(key) => key,
+                                Err(e) => {
+                                    eprintln!("Failed to read wallet: {}", e);
+                                    continue;
+                                }
+                            };
+                            let history =
+                                shielded_histories.entry(key.to_string()).or_insert_with(|| ShieldedHistory::new(key));
+                            let blockchain = blockchain.lock().await;
+                            if let Err(e) = history.sync(&blockchain) {
+                                eprintln!("Failed to read the chain: {}", e);
+                                continue;
+                            }
+                            let pending = history.pending(&blockchain);
+                            drop(blockchain);
+                            if args[1] == "history" {
+                                print_history(history.entries(), &pending, history.tip_height(), history.balance());
+                            } else if key.full().is_some() {
+                                println!("Shielded balance: {}", history.balance());
+                            } else {
+                                println!("Shielded notes received: {}", history.balance());
+                                println!("An incoming viewing key cannot see spends, so some of it may be spent.");
                             }
                         }
                         "balance" => {
-                            if Wallet::exists("wallet.dat") {
-                                let wallet = Wallet::load_from_file("wallet.dat").expect("Failed to load wallet");
-                                let blockchain = blockchain.lock().await;
-                                let balance = blockchain.get_balance(&wallet.public_key_hex());
-                                println!("Wallet balance: {}", balance);
+                            let Some((_, path)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            match Wallet::public_key_from_file(path) {
+                                Ok(public_key) => {
+                                    let blockchain = blockchain.lock().await;
+                                    println!("Wallet balance: {}", blockchain.get_balance(&public_key));
+                                }
+                                Err(e) => eprintln!("Failed to read wallet: {}", e),
+                            }
+                        }
+                        "history" => {
+                            let Some((name, path)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            let public_key = match Wallet::public_key_from_file(path) {
+                                Ok(public_key) => public_key,
+                                Err(e) => {
+                                    eprintln!("Failed to read wallet: {}", e);
+                                    continue;
+                                }
+                            };
+                            // Other accounts can only be derived from the unlocked seed
+                            let hd_wallet = match &unlocked {
+                                Some((unlocked_name, wallet)) if *unlocked_name == name && wallet.is_hd() => Some(wallet),
+                                _ => None,
+                            };
+                            let history = histories
+                                .entry((public_key.clone(), hd_wallet.is_some()))
+                                .or_insert_with(|| WalletHistory::new([public_key]));
+                            let blockchain = blockchain.lock().await;
+                            let synced = match hd_wallet {
+                                Some(wallet) => history.discover_accounts(&blockchain, |account| {
+                                    Some(wallet.with_account(account).ok()??.public_key_hex())
+                                }),
+                                None => history.sync(&blockchain),
+                            };
+                            if let Err(e) = synced {
+                                eprintln!("Failed to read the chain: {}", e);
+                                continue;
+                            }
+                            let pending = history.pending(&blockchain);
+                            drop(blockchain);
+                            print_history(history.entries(), &pending, history.tip_height(), history.balance());
+                            if hd_wallet.is_none() {
+                                println!("Only the account in use is shown; unlock an HD wallet to include all of its accounts.");
+                            }
+                        }
+                        "viewing-key" => {
+                            let Some((name, _)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            let wallet = match &unlocked {
+                                Some((unlocked_name, wallet)) if *unlocked_name == name => wallet,
+                                _ => {
+                                    println!("Wallet '{}' is locked. Run 'wallet unlock --wallet {}' first.", name, name);
+                                    continue;
+                                }
+                            };
+                            let key = match args.get(2).copied().unwrap_or("full") {
+                                "full" => wallet.viewing_key(),
+                                "incoming" => ViewingKey::Incoming(wallet.viewing_key().incoming()),
+                                _ => {
+                                    println!("Usage: wallet viewing-key [full|incoming]");
+                                    continue;
+                                }
+                            };
+                            println!("Anyone holding this key can see the wallet's shielded notes, but not spend them.");
+                            if key.full().is_some() {
+                                println!("A full viewing key also shows when each note is spent.");
+                            }
+                            println!("{}", Zeroizing::new(key.to_string()).as_str());
+                        }
+                        "payment-address" => {
+                            let Some((name, path)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            let address = if is_view_only(&path) {
+                                match ViewingKey::load_from_file(path) {
+                                    Ok(key) => key.address(),
+                                    Err(e) => {
+                                        eprintln!("Failed to read wallet: {}", e);
+                                        continue;
+                                    }
+                                }
                             } else {
-                                println!("Wallet not found. Please create one first.");
+                                match &unlocked {
+                                    Some((unlocked_name, wallet)) if *unlocked_name == name => {
+                                        wallet.spending_key().address()
+                                    }
+                                    _ => {
+                                        println!("Wallet '{}' is locked. Run 'wallet unlock --wallet {}' first.", name, name);
+                                        continue;
+                                    }
+                                }
+                            };
+                            println!("{}", address);
+                        }
+                        "unlock" => {
+                            let Some((name, path)) = select_wallet(&wallets, selector) else {
+                                continue;
+                            };
+                            if is_view_only(&path) {
+                                println!("Wallet '{}' is view-only and has no keys to unlock.", name);
+                                continue;
                             }
+                            let wallet = if Wallet::is_plaintext_file(&path).unwrap_or(false) {
+                                println!("Wallet '{}' is not encrypted. Choose a passphrase to encrypt it.", name);
+                                let Some(passphrase) = new_passphrase() else {
+                                    continue;
+                                };
+                                Wallet::migrate_plaintext_file(&path, &passphrase)
+                            } else {
+                                Wallet::load_from_file(&path, &prompt_secret("Passphrase: "))
+                            };
+                            match wallet {
+                                Ok(wallet) => {
+                                    println!("Wallet '{}' unlocked: {}", name, wallet.address());
+                                    unlocked = Some((name, wallet));
+                                }
+                                Err(e) => eprintln!("Failed to unlock wallet: {}", e),
+                            }
+                        }
+                        "lock" => {
+                            unlocked = None;
+                            println!("Wallet locked.");
                         }
-                        _ => println!("Unknown wallet command. Use 'create' or 'balance'."),
+                        "list" => {
+                            let names = match wallets.list() {
+                                Ok(names) => names,
+                                Err(e) => {
+                                    eprintln!("Failed to list wallets: {}", e);
+                                    continue;
+                                }
+                            };
+                            if names.is_empty() {
+                                println!("No wallets. Create one with 'wallet create [name]'.");
+                            }
+                            let current = wallets.current().ok().flatten();
+                            for name in names {
+                                let marker = if current.as_deref() == Some(name.as_str()) { '*' } else { ' ' };
+                                let address = match wallets.path(&name) {
+                                    Ok(path) if is_view_only(&path) => "view-only".to_string(),
+                                    path => path
+                                        .ok()
+                                        .and_then(|path| Wallet::public_key_from_file(path).ok())
+                                        .map_or_else(|| "unreadable".to_string(), |key| display_address(&key)),
+                                };
+                                let state = match &unlocked {
+                                    Some((unlocked_name, _)) if *unlocked_name == name => " (unlocked)",
+                                    _ => "",
+                                };
+                                println!("{} {} {}{}", marker, name, address, state);
+                            }
+                        }
+                        "use" => {
+                            let Some(&name) = args.get(2) else {
+                                println!("Usage: wallet use <name>");
+                                continue;
+                            };
+                            match wallets.set_current(name) {
+                                Ok(()) => println!("Using wallet '{}'.", name),
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        "rename" => {
+                            let (Some(&from), Some(&to)) = (args.get(2), args.get(3)) else {
+                                println!("Usage: wallet rename <name> <new name>");
+                                continue;
+                            };
+                            match wallets.rename(from, to) {
+                                Ok(()) => {
+                                    if let Some((name, _)) = unlocked.as_mut().filter(|(name, _)| name == from) {
+                                        *name = to.to_string();
+                                    }
+                                    println!("Renamed wallet '{}' to '{}'.", from, to);
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        "delete" => {
+                            let Some(&name) = args.get(2) else {
+                                println!("Usage: wallet delete <name>");
+                                continue;
+                            };
+                            if !wallets.contains(name) {
+                                println!("No wallet named '{}'.", name);
+                                continue;
+                            }
+                            println!("Deleting wallet '{}' destroys its keys unless you have its recovery phrase.", name);
+                            if *prompt(&format!("Type '{}' to delete it: ", name)) != *name {
+                                println!("Wallet '{}' was kept.", name);
+                                continue;
+                            }
+                            match wallets.delete(name) {
+                                Ok(()) => {
+                                    if unlocked.as_ref().is_some_and(|(unlocked_name, _)| unlocked_name == name) {
+                                        unlocked = None;
+                                    }
+                                    println!("Deleted wallet '{}'.", name);
+                                }
+                                Err(e) => eprintln!("Failed to delete wallet: {}", e),
+                            }
+                        }
+                        _ => println!("Unknown wallet command. Use {}.", WALLET_COMMANDS),
                     }
                 } else {
-                    println!("Usage: wallet <create|balance>");
+                    println!("Usage: wallet <{}> [--wallet <name>]", WALLET_COMMANDS.replace(", ", "|"));
                 }
             }
             "transaction" => {
-                if args.len() == 3 {
-                    let recipient = args[1];
+                if args.len() == 3 || args.len() == 4 {
+                    // Checked before anything is signed, so a typo cannot send funds astray
+                    let recipient = match Address::parse(args[1]) {
+                        Ok(address) => address,
+                        Err(e) => {
+                            eprintln!("Invalid recipient: {}", e);
+                            continue;
+                        }
+                    };
                     let amount: u64 = match args[2].parse() {
                         Ok(a) => a,
                         Err(_) => {
@@ -105,36 +867,164 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
                             continue;
                         }
                     };
-                    if !Wallet::exists("wallet.dat") {
-                        println!("Wallet not found. Please create one first.");
+                    let fee: u64 = match args.get(3).map(|f| f.parse()).unwrap_or(Ok(0)) {
+                        Ok(f) => f,
+                        Err(_) => {
+                            eprintln!("Invalid fee. Please enter a valid number.");
+                            continue;
+                        }
+                    };
+                    let Some((name, _)) = select_wallet(&wallets, selector) else {
                         continue;
-                    }
-                    let wallet = Wallet::load_from_file("wallet.dat").expect("Failed to load wallet");
-                    let mut tx = Transaction::new(wallet.public_key_hex(), recipient.to_string(), amount);
-                    tx.sign_transaction(&wallet.signing_key);
+                    };
+                    let wallet = match &unlocked {
+                        Some((unlocked_name, wallet)) if *unlocked_name == name => wallet,
+                        _ => {
+                            println!("Wallet '{}' is locked. Run 'wallet unlock --wallet {}' first.", name, name);
+                            continue;
+                        }
+                    };
+                    let sender = wallet.public_key_hex();
                     let mut bc = blockchain.lock().await;
-                    bc.add_transaction(tx);
-                    println!("Transaction added to pending transactions.");
+                    let nonce = bc.next_nonce(&sender);
+                    let mut tx = match Transaction::new(sender, &recipient.to_string(), amount, fee, nonce) {
+                        Ok(tx) => tx,
+                        Err(e) => {
+                            eprintln!("Invalid recipient: {}", e);
+                            continue;
+                        }
+                    };
+                    tx.sign_transaction(&wallet.signing_key);
+                    match bc.add_transaction(tx) {
+                        Ok(()) => println!("Transaction added to pending transactions."),
+                        Err(e) => {
+                            eprintln!("Transaction rejected: {}", e);
+                            continue;
+                        }
+                    }
 
-                    if let Err(e) = bc.save_to_file("blockchain.json") {
+                    if let Err(e) = bc.flush() {
                         eprintln!("Failed to save blockchain: {}", e);
                     }
                 } else {
-                    println!("Usage: transaction <recipient> <amount>");
+                    println!("Usage: transaction <recipient> <amount> [fee] [--wallet <name>]");
                 }
             }
-            "mine" => {
-                if Wallet::exists("wallet.dat") {
-                    let wallet = Wallet::load_from_file("wallet.dat").expect("Unable to load wallet");
+            "shield" => {
+                if args.len() == 3 || args.len() == 4 {
+                    let recipient = match PaymentAddress::parse(args[1]) {
+                        Ok(address) => address,
+                        Err(e) => {
+                            eprintln!("Invalid recipient: {}", e);
+                            continue;
+                        }
+                    };
+                    let amount: u64 = match args[2].parse() {
+                        Ok(a) => a,
+                        Err(_) => {
+                            eprintln!("Invalid amount. Please enter a valid number.");
+                            continue;
+                        }
+                    };
+                    let fee: u64 = match args.get(3).map(|f| f.parse()).unwrap_or(Ok(0)) {
+                        Ok(f) => f,
+                        Err(_) => {
+                            eprintln!("Invalid fee. Please enter a valid number.");
+                            continue;
+                        }
+                    };
+                    let Some((name, _)) = select_wallet(&wallets, selector) else {
+                        continue;
+                    };
+                    let wallet = match &unlocked {
+                        Some((unlocked_name, wallet)) if *unlocked_name == name => wallet,
+                        _ => {
+                            println!("Wallet '{}' is locked. Run 'wallet unlock --wallet {}' first.", name, name);
+                            continue;
+                        }
+                    };
+                    let sender = wallet.public_key_hex();
+                    let (nonce, anchor) = {
+                        let bc = blockchain.lock().await;
+                        (bc.next_nonce(&sender), bc.shielded.tree.root())
+                    };
+
+                    println!("Generating proof...");
+                    let note = Note::new(amount, recipient);
+                    let proving =
+                        prover.submit(move || Transaction::new_deposit(sender, amount, fee, nonce, &[note], anchor)).await;
+                    let mut tx = match proving.await {
+                        Ok(Ok(tx)) => tx,
+                        Ok(Err(e)) => {
+                            eprintln!("Failed to prove the deposit: {}", e);
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to create transaction: {}", e);
+                            continue;
+                        }
+                    };
+                    tx.sign_transaction(&wallet.signing_key);
                     let mut bc = blockchain.lock().await;
-                    bc.mine_pending_transactions(&wallet.public_key_hex());
-                    println!("Mining complete. Wallet address: {}", wallet.public_key_hex());
+                    match bc.add_transaction(tx) {
+                        Ok(()) => println!("Deposit added to pending transactions."),
+                        Err(e) => {
+                            eprintln!("Transaction rejected: {}", e);
+                            continue;
+                        }
+                    }
 
-                    if let Err(e) = bc.save_to_file("blockchain.json") {
+                    if let Err(e) = bc.flush() {
                         eprintln!("Failed to save blockchain: {}", e);
                     }
                 } else {
-                    println!("Wallet not found. Please create one first.");
+                    println!("Usage: shield <payment address> <amount> [fee] [--wallet <name>]");
+                }
+            }
+            "mine" => {
+                // Mining only needs the address, so it works while the wallet is locked
+                let Some((_, path)) = select_wallet(&wallets, selector) else {
+                    continue;
+                };
+                match Wallet::public_key_from_file(path) {
+                    Ok(address) => {
+                        let Some(template) = blockchain.lock().await.block_template() else {
+                            eprintln!("Dropped pending transactions with an invalid proof; nothing was mined");
+                            continue;
+                        };
+
+                        // The proof of work runs without the chain; only submitting takes it
+                        let reward = Transaction::new_reward(address.clone(), template.height(), template.fees());
+                        let mined = match tokio::task::spawn_blocking(move || template.mine(reward)).await {
+                            Ok(Ok(mined)) => mined,
+                            Ok(Err(e)) => {
+                                eprintln!("Failed to mine the block: {}", e);
+                                continue;
+                            }
+                            Err(e) => {
+                                eprintln!("Mining stopped: {}", e);
+                                continue;
+                            }
+                        };
+                        let mut bc = blockchain.lock().await;
+                        match bc.submit_block(mined) {
+                            Ok(()) => {}
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                                eprintln!("A new block arrived while mining; run 'mine' again");
+                                continue;
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to store the mined block: {}", e);
+                                continue;
+                            }
+                        }
+                        println!("Mining complete. Wallet address: {}", display_address(&address));
+
+                        if let Err(e) = bc.flush() {
+                            eprintln!("Failed to save blockchain: {}", e);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to read wallet: {}", e),
                 }
             }
             "connect" => {
@@ -163,8 +1053,10 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
             "status" => {
                 let bc = blockchain.lock().await;
                 println!("Blockchain status:");
-                println!("  Blocks: {}", bc.chain.len());
+                println!("  Blocks: {}", bc.block_count());
                 println!("  Pending transactions: {}", bc.pending_transactions.len());
+                println!("  Next difficulty bits: {:#010x}", next_bits(bc.recent_blocks()));
+                println!("  Verifying key fingerprint: {}", parameters().fingerprint());
 
                 let peers = network.lock().await.get_peers().await;
                 println!("Connected peers: {}", peers.len());
@@ -173,7 +1065,7 @@ pub async fn run_cli(blockchain: Arc<Mutex<Blockchain>>, network: Arc<Mutex<Netw
                 }
             }
             _ => {
-                println!("Unknown command. Use 'wallet', 'transaction', 'mine', 'connect', 'peers', or 'status'.");
+                println!("Unknown command. Use 'wallet', 'transaction', 'shield', 'mine', 'connect', 'peers', or 'status'.");
             }
         }
     }
@@ -0,0 +1,273 @@
+// src/difficulty.rs
+
+use crate::block::Block;
+use std::cmp::Ordering;
+use std::ops::{Div, Not, Shl, Shr};
+
+/// Easiest target allowed, in compact form. Equivalent to the old two leading hex zeros.
+pub const POW_LIMIT_BITS: u32 = 0x2000ffff;
+
+/// Number of blocks between difficulty adjustments.
+pub const RETARGET_INTERVAL: u64 = 10;
+
+/// Desired seconds between blocks.
+pub const TARGET_BLOCK_TIME: i64 = 30;
+
+/// Number of previous blocks whose median timestamp a new block must exceed.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of local time a block timestamp may be, in seconds.
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+/// Unsigned 256-bit integer, most significant limb first so the derived ordering is numeric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        U256([0, 0, 0, value])
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.chunks_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Number of significant bits.
+    pub fn bits(&self) -> u32 {
+        for (i, limb) in self.0.iter().enumerate() {
+            if *limb != 0 {
+                return (3 - i as u32) * 64 + (64 - limb.leading_zeros());
+            }
+        }
+        0
+    }
+
+    pub fn low_u128(&self) -> u128 {
+        ((self.0[2] as u128) << 64) | self.0[3] as u128
+    }
+
+    fn bit(&self, bit: u32) -> bool {
+        (self.0[3 - (bit / 64) as usize] >> (bit % 64)) & 1 == 1
+    }
+
+    fn set_bit(&mut self, bit: u32) {
+        self.0[3 - (bit / 64) as usize] |= 1 << (bit % 64);
+    }
+
+    fn shifted_left(self, shift: u32) -> U256 {
+        let mut result = U256::ZERO;
+        for bit in 0..256u32.saturating_sub(shift) {
+            if self.bit(bit) {
+                result.set_bit(bit + shift);
+            }
+        }
+        result
+    }
+
+    fn shifted_right(self, shift: u32) -> U256 {
+        let mut result = U256::ZERO;
+        for bit in shift.min(256)..256 {
+            if self.bit(bit) {
+                result.set_bit(bit - shift);
+            }
+        }
+        result
+    }
+
+    pub fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            result[i] = sum;
+            carry = c1 || c2;
+        }
+        (U256(result), carry)
+    }
+
+    fn wrapping_sub(self, other: U256) -> U256 {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            result[i] = diff;
+            borrow = b1 || b2;
+        }
+        U256(result)
+    }
+
+    /// Multiplies by a small factor, saturating at `U256::MAX`.
+    pub fn saturating_mul_u64(self, factor: u64) -> Self {
+        let mut result = [0u64; 4];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let product = self.0[i] as u128 * factor as u128 + carry;
+            result[i] = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            U256::MAX
+        } else {
+            U256(result)
+        }
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        self.shifted_left(shift)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        self.shifted_right(shift)
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    /// Long division; `divisor` must be non-zero.
+    fn div(self, divisor: U256) -> U256 {
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for bit in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            if self.bit(bit) {
+                remainder.0[3] |= 1;
+            }
+            if remainder >= divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.set_bit(bit);
+            }
+        }
+        quotient
+    }
+}
+
+/// Expands a compact `bits` value into a full target.
+///
+/// The top byte is the target's length in bytes and the low three bytes its
+/// most significant digits, as in Bitcoin's `nBits`. Negative or overflowing
+/// encodings yield `None`.
+pub fn target_from_compact(bits: u32) -> Option<U256> {
+    let exponent = bits >> 24;
+    let mantissa = bits & 0x00ff_ffff;
+    if mantissa & 0x0080_0000 != 0 {
+        return None;
+    }
+    let value = U256::from_u64(mantissa as u64);
+    if exponent <= 3 {
+        return Some(value >> (8 * (3 - exponent)));
+    }
+    let shift = 8 * (exponent - 3);
+    if mantissa != 0 && value.bits() + shift > 256 {
+        return None;
+    }
+    Some(value << shift)
+}
+
+/// Encodes a target in compact form, dropping precision below the top three bytes.
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        (target.low_u128() as u32) << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u128() as u32
+    };
+    // Keep the mantissa's sign bit clear
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    (size << 24) | mantissa
+}
+
+/// Expected number of hashes needed to find a hash at or below `target`.
+pub fn work_for_target(target: U256) -> u128 {
+    // 2^256 / (target + 1), computed as (~target / (target + 1)) + 1 to stay in range
+    let (divisor, overflow) = target.overflowing_add(U256::from_u64(1));
+    if overflow {
+        return 1;
+    }
+    let work = (!target / divisor).overflowing_add(U256::from_u64(1)).0;
+    if work.bits() > 128 {
+        u128::MAX
+    } else {
+        work.low_u128()
+    }
+}
+
+/// Compact target required for the block that extends `chain`.
+///
+/// Every `RETARGET_INTERVAL` blocks the target is scaled by how long the last
+/// interval actually took compared to `TARGET_BLOCK_TIME` per block, with the
+/// adjustment clamped to a factor of four and never easier than `POW_LIMIT_BITS`.
+/// `chain` may be just the most recent blocks, as long as it holds the last
+/// `RETARGET_INTERVAL` of them.
+pub fn next_bits(chain: &[Block]) -> u32 {
+    let Some(previous) = chain.last() else {
+        return POW_LIMIT_BITS;
+    };
+    let height = previous.header.index + 1;
+    if !height.is_multiple_of(RETARGET_INTERVAL) {
+        return previous.header.bits;
+    }
+
+    let first = &chain[chain.len() - RETARGET_INTERVAL as usize];
+    let expected = TARGET_BLOCK_TIME * RETARGET_INTERVAL as i64;
+    let actual = (previous.header.timestamp - first.header.timestamp).clamp(expected / 4, expected * 4);
+
+    let pow_limit = target_from_compact(POW_LIMIT_BITS).unwrap();
+    let current = target_from_compact(previous.header.bits).unwrap_or(pow_limit);
+    // Divide first: targets near the limit would overflow if multiplied first
+    let retargeted = (current / U256::from_u64(expected as u64)).saturating_mul_u64(actual as u64);
+    target_to_compact(retargeted.min(pow_limit))
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`.
+pub fn median_time_past(chain: &[Block]) -> i64 {
+    let start = chain.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<i64> = chain[start..].iter().map(|block| block.header.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(i64::MIN)
+}
+
+/// Compares a hex-encoded hash against a target; unparsable hashes never qualify.
+pub fn hash_meets_target(hash: &str, target: U256) -> bool {
+    let mut bytes = [0u8; 32];
+    match hex::decode_to_slice(hash, &mut bytes) {
+        Ok(()) => U256::from_be_bytes(bytes).cmp(&target) != Ordering::Greater,
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,123 @@
+// src/hd.rs
+//
+// Hierarchical deterministic wallet keys.
+//
+// Every key of an HD wallet comes from one 64-byte seed, which is backed up
+// as a BIP39 mnemonic phrase. Signing keys are derived from the seed with
+// SLIP-0010 for ed25519, which only defines hardened derivation, along the
+// path m/44'/1'/account'. Coin type 1 is the one SLIP-0044 reserves for
+// chains without a registered number.
+
+use ed25519_zebra::SigningKey;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha512;
+use std::fmt;
+use zeroize::Zeroizing;
+
+pub use bip39::{Error as MnemonicError, Mnemonic};
+
+/// Added to a child index to make it hardened.
+pub const HARDENED: u32 = 1 << 31;
+
+/// BIP44 purpose field of the derivation path.
+pub const PURPOSE: u32 = 44;
+
+/// SLIP-0044 coin type of the derivation path.
+pub const COIN_TYPE: u32 = 1;
+
+/// Number of words in newly generated mnemonics (256 bits of entropy).
+pub const DEFAULT_WORD_COUNT: usize = 24;
+
+/// Generates a mnemonic of `word_count` words, which must be 12, 15, 18, 21
+/// or 24.
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic, MnemonicError> {
+    if !word_count.is_multiple_of(3) {
+        return Err(MnemonicError::BadWordCount(word_count));
+    }
+    // Every three words carry 32 bits of entropy and one checksum bit
+    let mut entropy = Zeroizing::new(vec![0u8; word_count / 3 * 4]);
+    OsRng.fill_bytes(&mut entropy);
+    Mnemonic::from_entropy(&entropy).map_err(|_| MnemonicError::BadWordCount(word_count))
+}
+
+/// BIP39 seed of `mnemonic`, protected by an optional `passphrase` (empty
+/// for none). A different passphrase yields an unrelated wallet.
+pub fn mnemonic_to_seed(mnemonic: &Mnemonic, passphrase: &str) -> Zeroizing<[u8; 64]> {
+    Zeroizing::new(mnemonic.to_seed(passphrase))
+}
+
+/// An account number with the hardened bit set. Every step of the path is
+/// hardened anyway, so it would alias the account `HARDENED` below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidAccount(pub u32);
+
+impl fmt::Display for InvalidAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "account {} is not below {}", self.0, HARDENED)
+    }
+}
+
+impl std::error::Error for InvalidAccount {}
+
+/// Hardened derivation path of `account`.
+pub fn account_path(account: u32) -> [u32; 3] {
+    [PURPOSE, COIN_TYPE, account]
+}
+
+/// An ed25519 secret key together with the chain code needed to derive its
+/// children.
+#[derive(Clone)]
+pub struct ExtendedKey {
+    secret_key: Zeroizing<[u8; 32]>,
+    chain_code: Zeroizing<[u8; 32]>,
+}
+
+impl ExtendedKey {
+    /// Master key of `seed`.
+    pub fn master(seed: &[u8]) -> Self {
+        Self::from_hmac(b"ed25519 seed", &[seed])
+    }
+
+    /// Hardened child `index`. The hardened bit is set whether or not
+    /// `index` already has it, as ed25519 has no other kind of child.
+    pub fn derive(&self, index: u32) -> Self {
+        Self::from_hmac(&self.chain_code[..], &[&[0], &self.secret_key[..], &(index | HARDENED).to_be_bytes()])
+    }
+
+    /// Descendant of this key along `path`, every step hardened.
+    pub fn derive_path(&self, path: &[u32]) -> Self {
+        path.iter().fold(self.clone(), |key, &index| key.derive(index))
+    }
+
+    /// Signing key of `account` in the wallet with this master key.
+    pub fn account_key(&self, account: u32) -> SigningKey {
+        self.derive_path(&account_path(account)).signing_key()
+    }
+
+    pub fn secret_key(&self) -> &[u8; 32] {
+        &self.secret_key
+    }
+
+    pub fn chain_code(&self) -> &[u8; 32] {
+        &self.chain_code
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from(*self.secret_key)
+    }
+
+    /// Splits HMAC-SHA512(`key`, `message`) into a secret key and chain code.
+    fn from_hmac(key: &[u8], message: &[&[u8]]) -> Self {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        for part in message {
+            mac.update(part);
+        }
+        let output = mac.finalize().into_bytes();
+        let mut key = ExtendedKey { secret_key: Zeroizing::new([0; 32]), chain_code: Zeroizing::new([0; 32]) };
+        key.secret_key.copy_from_slice(&output[..32]);
+        key.chain_code.copy_from_slice(&output[32..]);
+        key
+    }
+}
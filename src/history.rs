@@ -0,0 +1,325 @@
+// src/history.rs
+//
+// Wallet-side transaction history.
+//
+// `WalletHistory` scans blocks for transactions paying or paid by a set of
+// watched accounts and records one entry per transaction. Scanning is
+// incremental: the hashes of the scanned blocks are kept, so a later scan
+// only looks at new blocks, and after a reorganization it drops the entries
+// of the blocks that left the chain and rescans from the fork.
+//
+// The accounts of an HD wallet are found the way BIP44 discovers accounts:
+// they are derived in order until `ACCOUNT_GAP_LIMIT` in a row have never
+// been used on chain.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::transaction::Transaction;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+
+/// Number of consecutive unused accounts after which account discovery
+/// stops. It leaves room for accounts skipped with `wallet account`.
+pub const ACCOUNT_GAP_LIMIT: u32 = 5;
+
+/// How a transaction moved value for the wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Paid to a watched account.
+    Incoming,
+    /// Paid by a watched account, which also paid the fee.
+    Outgoing,
+    /// Between two watched accounts, so only the fee left the wallet.
+    SelfTransfer,
+    /// Block reward, including the block's fees, paid to a watched account.
+    Mined,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Incoming => write!(f, "received"),
+            Direction::Outgoing => write!(f, "sent"),
+            Direction::SelfTransfer => write!(f, "self"),
+            Direction::Mined => write!(f, "mined"),
+        }
+    }
+}
+
+/// A transaction involving the wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Height of the block holding the transaction, `None` while pending.
+    pub height: Option<u64>,
+    /// Timestamp of that block.
+    pub timestamp: Option<i64>,
+    pub tx_id: String,
+    pub direction: Direction,
+    /// The other side, as the chain names it: the sender of incoming
+    /// transactions and the recipient of the others.
+    pub counterparty: String,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+impl HistoryEntry {
+    /// Number of blocks from the transaction's block up to the tip at
+    /// `tip_height`, or 0 while the transaction is pending.
+    pub fn confirmations(&self, tip_height: u64) -> u64 {
+        self.height.map_or(0, |height| tip_height.saturating_sub(height) + 1)
+    }
+
+    /// Change to the wallet's balance.
+    pub fn net_change(&self) -> i128 {
+        match self.direction {
+            Direction::Incoming | Direction::Mined => self.amount as i128,
+            Direction::Outgoing => -(self.amount as i128) - self.fee as i128,
+            Direction::SelfTransfer => -(self.fee as i128),
+        }
+    }
+}
+
+/// Blocks a history has scanned: the hash of each, by height, and what the
+/// history needs to resume after it.
+#[derive(Debug, Clone)]
+pub(crate) struct ScannedBlocks<T> {
+    blocks: Vec<(String, T)>,
+}
+
+impl<T> Default for ScannedBlocks<T> {
+    fn default() -> Self {
+        ScannedBlocks { blocks: vec![] }
+    }
+}
+
+impl<T> ScannedBlocks<T> {
+    /// Height of the first scanned block that is not on the chain of
+    /// `blockchain`, which is where the next scan starts.
+    pub(crate) fn fork_point(&self, blockchain: &Blockchain) -> usize {
+        self.blocks
+            .iter()
+            .enumerate()
+            .take_while(|(height, (hash, _))| blockchain.block_hash(*height as u64).as_ref() == Some(hash))
+            .count()
+    }
+
+    /// As `fork_point`, against `chain`, which starts at genesis.
+    pub(crate) fn fork_point_in(&self, chain: &[Block]) -> usize {
+        self.blocks.iter().zip(chain).take_while(|((hash, _), block)| *hash == block.hash).count()
+    }
+
+    /// Forgets the blocks from height `fork` on.
+    pub(crate) fn rewind(&mut self, fork: usize) {
+        self.blocks.truncate(fork);
+    }
+
+    pub(crate) fn push(&mut self, block: &Block, state: T) {
+        self.blocks.push((block.hash.clone(), state));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// What was recorded after the last scanned block.
+    pub(crate) fn last(&self) -> Option<&T> {
+        self.blocks.last().map(|(_, state)| state)
+    }
+
+    pub(crate) fn tip_height(&self) -> Option<u64> {
+        self.blocks.len().checked_sub(1).map(|height| height as u64)
+    }
+}
+
+/// Drops the entries of the blocks from height `fork` on.
+pub(crate) fn rewind_entries(entries: &mut Vec<HistoryEntry>, fork: usize) {
+    entries.retain(|entry| entry.height.is_some_and(|height| height < fork as u64));
+}
+
+/// History of the transactions involving a set of accounts.
+#[derive(Debug, Clone, Default)]
+pub struct WalletHistory {
+    accounts: HashSet<String>,
+    entries: Vec<HistoryEntry>,
+    /// Heights at which each watched account sent or received.
+    used: Vec<(u64, String)>,
+    scanned: ScannedBlocks<()>,
+}
+
+impl WalletHistory {
+    /// History of `accounts`, named as the chain names them (hex public
+    /// keys). Nothing is scanned yet.
+    pub fn new(accounts: impl IntoIterator<Item = String>) -> Self {
+        WalletHistory { accounts: accounts.into_iter().collect(), ..Default::default() }
+    }
+
+    /// Adds `account`, returning false if it was already watched. The next
+    /// scan starts over, as earlier blocks may involve it.
+    pub fn watch(&mut self, account: String) -> bool {
+        if !self.accounts.insert(account) {
+            return false;
+        }
+        self.entries.clear();
+        self.used.clear();
+        self.scanned.clear();
+        true
+    }
+
+    pub fn is_watching(&self, account: &str) -> bool {
+        self.accounts.contains(account)
+    }
+
+    /// Returns true if a scanned transaction involves `account`.
+    pub fn is_used(&self, account: &str) -> bool {
+        self.used.iter().any(|(_, used)| used == account)
+    }
+
+    /// Watches every account of an HD wallet in use on the chain of
+    /// `blockchain` and brings the history up to date. `key(n)` is the chain's
+    /// name of account `n`, or `None` past the last one; accounts are taken in
+    /// order until `ACCOUNT_GAP_LIMIT` in a row are unused.
+    ///
+    /// Accounts found this way are back-filled over the blocks already
+    /// scanned, so the chain is read at most once more however many turn up.
+    pub fn discover_accounts<F>(&mut self, blockchain: &Blockchain, mut key: F) -> io::Result<()>
+    where
+        F: FnMut(u32) -> Option<String>,
+    {
+        self.sync(blockchain)?;
+        let mut chain = None;
+        let mut next = 0u32;
+        loop {
+            let batch: Vec<String> = (next..next.saturating_add(ACCOUNT_GAP_LIMIT)).map_while(&mut key).collect();
+            let new: HashSet<String> = batch.iter().filter(|account| !self.is_watching(account)).cloned().collect();
+            if !new.is_empty() {
+                if chain.is_none() {
+                    chain = Some(blockchain.blocks_from(0)?);
+                }
+                self.accounts.extend(new.iter().cloned());
+                self.backfill(&new, chain.as_deref().unwrap());
+            }
+            match batch.iter().rposition(|account| self.is_used(account)) {
+                Some(last_used) => next += last_used as u32 + 1,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Brings the history up to date with the chain of `blockchain`, reading
+    /// only the blocks after the last scanned one still on the chain.
+    pub fn sync(&mut self, blockchain: &Blockchain) -> io::Result<()> {
+        let fork = self.scanned.fork_point(blockchain);
+        let blocks = blockchain.blocks_from(fork as u64)?;
+        self.scan_from(fork, &blocks);
+        Ok(())
+    }
+
+    /// Brings the history up to date with `chain`, which starts at genesis.
+    pub fn scan(&mut self, chain: &[Block]) {
+        let fork = self.scanned.fork_point_in(chain);
+        self.scan_from(fork, &chain[fork..]);
+    }
+
+    /// Scans `blocks`, which start at height `fork`, after dropping
+    /// everything scanned from there on.
+    fn scan_from(&mut self, fork: usize, blocks: &[Block]) {
+        // Rewind past any block that is no longer on the chain
+        self.scanned.rewind(fork);
+        rewind_entries(&mut self.entries, fork);
+        self.used.retain(|(height, _)| *height < fork as u64);
+
+        for block in blocks {
+            for tx in &block.transactions {
+                for account in [&tx.sender, &tx.recipient] {
+                    if self.accounts.contains(account) {
+                        self.used.push((block.header.index, account.clone()));
+                    }
+                }
+                if let Some(mut entry) = self.entry(tx) {
+                    entry.height = Some(block.header.index);
+                    entry.timestamp = Some(block.header.timestamp);
+                    self.entries.push(entry);
+                }
+            }
+            self.scanned.push(block, ());
+        }
+    }
+
+    /// Adds the transactions of the scanned blocks that involve `added`, just
+    /// inserted into the watched accounts, keeping the entries in chain order.
+    /// `chain` starts at genesis and holds at least the scanned blocks.
+    fn backfill(&mut self, added: &HashSet<String>, chain: &[Block]) {
+        let scanned = self.scanned.tip_height().map_or(0, |height| height as usize + 1);
+        let mut old = std::mem::take(&mut self.entries).into_iter().peekable();
+        for block in &chain[..scanned] {
+            let height = block.header.index;
+            for tx in &block.transactions {
+                let is_old = old.peek().is_some_and(|entry| entry.height == Some(height) && entry.tx_id == tx.id());
+                let mut involves_added = false;
+                for account in [&tx.sender, &tx.recipient] {
+                    if added.contains(account) {
+                        self.used.push((height, account.clone()));
+                        involves_added = true;
+                    }
+                }
+                if involves_added {
+                    // An entry for a watched account on the other side changes direction
+                    let mut entry = self.entry(tx).expect("the transaction involves a watched account");
+                    entry.height = Some(height);
+                    entry.timestamp = Some(block.header.timestamp);
+                    self.entries.push(entry);
+                    if is_old {
+                        old.next();
+                    }
+                } else if is_old {
+                    self.entries.extend(old.next());
+                }
+            }
+        }
+        self.entries.extend(old);
+    }
+
+    /// Confirmed transactions, oldest first.
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// Transactions waiting in the mempool of `blockchain`.
+    pub fn pending(&self, blockchain: &Blockchain) -> Vec<HistoryEntry> {
+        blockchain.pending_transactions.iter().filter_map(|tx| self.entry(tx)).collect()
+    }
+
+    /// Height of the last scanned block.
+    pub fn tip_height(&self) -> Option<u64> {
+        self.scanned.tip_height()
+    }
+
+    /// Sum of `net_change` over the confirmed transactions, which is the
+    /// balance of the watched accounts at the scanned tip.
+    pub fn balance(&self) -> i128 {
+        self.entries.iter().map(HistoryEntry::net_change).sum()
+    }
+
+    /// Entry for `tx` if it involves a watched account, not yet placed in a block.
+    fn entry(&self, tx: &Transaction) -> Option<HistoryEntry> {
+        let sent = self.accounts.contains(&tx.sender);
+        let received = self.accounts.contains(&tx.recipient);
+        let (direction, counterparty) = match (sent, received) {
+            (true, true) => (Direction::SelfTransfer, &tx.recipient),
+            (true, false) => (Direction::Outgoing, &tx.recipient),
+            (false, true) if tx.is_reward() => (Direction::Mined, &tx.sender),
+            (false, true) => (Direction::Incoming, &tx.sender),
+            (false, false) => return None,
+        };
+        Some(HistoryEntry {
+            height: None,
+            timestamp: None,
+            tx_id: tx.id(),
+            direction,
+            counterparty: counterparty.clone(),
+            amount: tx.amount,
+            fee: tx.fee,
+        })
+    }
+}
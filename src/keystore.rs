@@ -0,0 +1,294 @@
+// src/keystore.rs
+//
+// Passphrase-encrypted storage for a wallet's secret key or HD seed.
+//
+// The passphrase is stretched with scrypt (RFC 7914) into an encryption key
+// and a MAC key. The secret key is encrypted with AES-256 in counter mode,
+// and HMAC-SHA256 over the whole header and ciphertext authenticates it
+// (encrypt-then-MAC), so a wrong passphrase or a modified file is detected
+// before anything is decrypted. The public key is kept in the clear so a
+// locked wallet can still show its address.
+//
+// Layout, with integers little-endian:
+//
+//   magic "PBKEYSTR" | version | log2(N), r, p | salt | nonce | public key |
+//   secret kind | encrypted secret | MAC
+//
+// The secret kind is 0 for a 32-byte signing key, or 1 and an account
+// number for a 64-byte HD seed whose account key is the public key above.
+// Version 1 files have no secret kind and always hold a signing key.
+
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use aes::cipher::{KeyIvInit, StreamCipher};
+use aes::Aes256;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// First bytes of an encrypted keystore file.
+pub const KEYSTORE_MAGIC: &[u8; 8] = b"PBKEYSTR";
+
+/// Current keystore format version.
+pub const KEYSTORE_VERSION: u8 = 2;
+
+/// Largest scrypt memory cost accepted from a file (1 GiB), so a crafted
+/// keystore cannot make unlocking exhaust memory.
+const MAX_KDF_MEMORY: u64 = 1 << 30;
+
+/// Reason a keystore could not be opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeystoreError {
+    /// The data is not a keystore, or is truncated.
+    Malformed,
+    UnsupportedVersion(u8),
+    /// The scrypt parameters are out of the accepted range.
+    InvalidKdfParams,
+    /// The passphrase is wrong, or the keystore was modified.
+    WrongPassphrase,
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Malformed => write!(f, "not an encrypted keystore"),
+            KeystoreError::UnsupportedVersion(v) => write!(f, "unsupported keystore version {}", v),
+            KeystoreError::InvalidKdfParams => write!(f, "keystore key derivation parameters are out of range"),
+            KeystoreError::WrongPassphrase => write!(f, "wrong passphrase, or the keystore is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<DecodeError> for KeystoreError {
+    fn from(_: DecodeError) -> Self {
+        KeystoreError::Malformed
+    }
+}
+
+/// scrypt cost parameters: `2^log_n` iterations over `128 * r` byte blocks,
+/// run `p` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    /// 32 MiB of memory, which takes well under a second to unlock.
+    fn default() -> Self {
+        KdfParams { log_n: 15, r: 8, p: 1 }
+    }
+}
+
+impl KdfParams {
+    fn is_valid(&self) -> bool {
+        (1..32).contains(&self.log_n)
+            && (1..=1024).contains(&self.r)
+            && (1..=16).contains(&self.p)
+            && (self.log_n as u32) < 16 * self.r
+            && 128 * self.r as u64 * (1u64 << self.log_n) <= MAX_KDF_MEMORY
+    }
+}
+
+/// What a keystore encrypts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretKind {
+    /// A 32-byte ed25519 secret key.
+    SigningKey,
+    /// A 64-byte HD wallet seed, signing with the key of `account`.
+    Seed { account: u32 },
+}
+
+impl SecretKind {
+    /// Length of the secret in bytes.
+    pub fn secret_len(&self) -> usize {
+        match self {
+            SecretKind::SigningKey => 32,
+            SecretKind::Seed { .. } => 64,
+        }
+    }
+}
+
+impl Encode for SecretKind {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SecretKind::SigningKey => 0u8.encode(out),
+            SecretKind::Seed { account } => {
+                1u8.encode(out);
+                account.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for SecretKind {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match u8::decode(reader)? {
+            0 => Ok(SecretKind::SigningKey),
+            1 => Ok(SecretKind::Seed { account: u32::decode(reader)? }),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+}
+
+/// A secret key or seed encrypted under a passphrase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keystore {
+    version: u8,
+    params: KdfParams,
+    salt: [u8; 16],
+    nonce: [u8; 16],
+    public_key: [u8; 32],
+    kind: SecretKind,
+    ciphertext: Vec<u8>,
+    mac: [u8; 32],
+}
+
+impl Keystore {
+    /// Encrypts `secret`, a secret of the given `kind`, under `passphrase`
+    /// with fresh salt and nonce. `public_key` is stored in the clear.
+    pub fn seal(secret: &[u8], kind: SecretKind, public_key: [u8; 32], passphrase: &str, params: KdfParams) -> Self {
+        assert!(params.is_valid(), "invalid scrypt parameters");
+        assert_eq!(secret.len(), kind.secret_len(), "secret does not match its kind");
+        let mut keystore = Keystore {
+            version: KEYSTORE_VERSION,
+            params,
+            salt: [0; 16],
+            nonce: [0; 16],
+            public_key,
+            kind,
+            ciphertext: secret.to_vec(),
+            mac: [0; 32],
+        };
+        OsRng.fill_bytes(&mut keystore.salt);
+        OsRng.fill_bytes(&mut keystore.nonce);
+        let (encryption_key, mac_key) = keystore.derive_keys(passphrase);
+        aes_ctr(&encryption_key, &keystore.nonce, &mut keystore.ciphertext);
+        keystore.mac = keystore.mac(&mac_key).finalize().into_bytes().into();
+        keystore
+    }
+
+    /// Decrypts the secret, checking the MAC first.
+    pub fn open(&self, passphrase: &str) -> Result<Zeroizing<Vec<u8>>, KeystoreError> {
+        let (encryption_key, mac_key) = self.derive_keys(passphrase);
+        // `verify_slice` compares in constant time
+        if self.mac(&mac_key).verify_slice(&self.mac).is_err() {
+            return Err(KeystoreError::WrongPassphrase);
+        }
+        let mut secret = Zeroizing::new(self.ciphertext.clone());
+        aes_ctr(&encryption_key, &self.nonce, &mut secret);
+        Ok(secret)
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    pub fn params(&self) -> KdfParams {
+        self.params
+    }
+
+    pub fn kind(&self) -> SecretKind {
+        self.kind
+    }
+
+    /// Returns true if `data` starts like a keystore, whatever its version.
+    pub fn is_keystore(data: &[u8]) -> bool {
+        data.starts_with(KEYSTORE_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.header();
+        out.extend_from_slice(&self.ciphertext);
+        out.extend_from_slice(&self.mac);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, KeystoreError> {
+        if !Self::is_keystore(data) {
+            return Err(KeystoreError::Malformed);
+        }
+        let mut reader = Reader::new(&data[KEYSTORE_MAGIC.len()..]);
+        let version = u8::decode(&mut reader)?;
+        if !(1..=KEYSTORE_VERSION).contains(&version) {
+            return Err(KeystoreError::UnsupportedVersion(version));
+        }
+        let params = KdfParams {
+            log_n: u8::decode(&mut reader)?,
+            r: u32::decode(&mut reader)?,
+            p: u32::decode(&mut reader)?,
+        };
+        if !params.is_valid() {
+            return Err(KeystoreError::InvalidKdfParams);
+        }
+        let salt = reader.take_array()?;
+        let nonce = reader.take_array()?;
+        let public_key = reader.take_array()?;
+        let kind = if version == 1 { SecretKind::SigningKey } else { SecretKind::decode(&mut reader)? };
+        let keystore = Keystore {
+            version,
+            params,
+            salt,
+            nonce,
+            public_key,
+            kind,
+            ciphertext: reader.take(kind.secret_len())?.to_vec(),
+            mac: reader.take_array()?,
+        };
+        if !reader.is_empty() {
+            return Err(KeystoreError::Malformed);
+        }
+        Ok(keystore)
+    }
+
+    /// Everything before the ciphertext, all of which the MAC covers.
+    fn header(&self) -> Vec<u8> {
+        let mut out = KEYSTORE_MAGIC.to_vec();
+        self.version.encode(&mut out);
+        self.params.log_n.encode(&mut out);
+        self.params.r.encode(&mut out);
+        self.params.p.encode(&mut out);
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&self.public_key);
+        if self.version > 1 {
+            self.kind.encode(&mut out);
+        }
+        out
+    }
+
+    fn derive_keys(&self, passphrase: &str) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+        let mut derived = Zeroizing::new([0u8; 64]);
+        scrypt(passphrase.as_bytes(), &self.salt, self.params, derived.as_mut());
+        let mut encryption_key = Zeroizing::new([0u8; 32]);
+        let mut mac_key = Zeroizing::new([0u8; 32]);
+        encryption_key.copy_from_slice(&derived[..32]);
+        mac_key.copy_from_slice(&derived[32..]);
+        (encryption_key, mac_key)
+    }
+
+    /// HMAC-SHA256 over the header and ciphertext, ready to finalize or verify.
+    fn mac(&self, mac_key: &[u8; 32]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC takes keys of any length");
+        mac.update(&self.header());
+        mac.update(&self.ciphertext);
+        mac
+    }
+}
+
+/// XORs `data` with the AES-256 keystream starting at counter block `nonce`.
+fn aes_ctr(key: &[u8; 32], nonce: &[u8; 16], data: &mut [u8]) {
+    ctr::Ctr128BE::<Aes256>::new(key.into(), nonce.into()).apply_keystream(data);
+}
+
+/// scrypt key derivation (RFC 7914) into `out`, which must not be empty.
+pub fn scrypt(password: &[u8], salt: &[u8], params: KdfParams, out: &mut [u8]) {
+    let params = scrypt::Params::new(params.log_n, params.r, params.p, scrypt::Params::RECOMMENDED_LEN)
+        .expect("invalid scrypt parameters");
+    scrypt::scrypt(password, salt, &params, out).expect("scrypt output must not be empty");
+}
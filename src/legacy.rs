@@ -0,0 +1,96 @@
+// src/legacy.rs
+//
+// The `blockchain.json` file written by the first versions of the node.
+//
+// Those versions saved the whole chain as pretty JSON after every command:
+// blocks without a header, holding transactions with neither a fee nor a
+// nonce, each carrying a proof together with its own verifying key. Nothing
+// in such a chain passes today's consensus rules, and its transfers were
+// signed over a different message, so the blocks cannot be copied into a
+// block store. What carries over is who mined them, and a chain holding
+// transfers is only imported if the caller agrees to drop them (see
+// `Blockchain::import_legacy`).
+
+use crate::address::Address;
+use serde::Deserialize;
+use std::io;
+
+/// Sender of the mining reward in legacy blocks.
+pub const LEGACY_REWARD_SENDER: &str = "System";
+
+/// A chain as saved in `blockchain.json`.
+#[derive(Deserialize, Debug)]
+pub struct LegacyBlockchain {
+    pub chain: Vec<LegacyBlock>,
+    pub pending_transactions: Vec<LegacyTransaction>,
+    /// Number of leading zero hex digits a block hash needed.
+    pub difficulty: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LegacyBlock {
+    pub index: u64,
+    pub timestamp: i64,
+    pub previous_hash: String,
+    pub nonce: u64,
+    pub transactions: Vec<LegacyTransaction>,
+    pub hash: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LegacyTransaction {
+    pub sender: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub signature: Option<String>,
+    pub proof: LegacyProofData,
+}
+
+/// A proof serialized together with the verifying key it was made for.
+#[derive(Deserialize, Debug)]
+pub struct LegacyProofData {
+    pub proof: Vec<u8>,
+    pub vk: Vec<u8>,
+}
+
+impl LegacyBlockchain {
+    /// Parses the contents of a `blockchain.json` file, checking that its
+    /// blocks are numbered from 0 and each links to the hash of the one before.
+    pub fn from_json(data: &[u8]) -> io::Result<Self> {
+        let legacy: LegacyBlockchain = serde_json::from_slice(data)?;
+        if legacy.chain.first().is_none_or(|genesis| genesis.index != 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "legacy chain has no genesis block"));
+        }
+        for pair in legacy.chain.windows(2) {
+            if pair[1].index != pair[0].index + 1 || pair[1].previous_hash != pair[0].hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("legacy block {} does not extend the block before it", pair[1].index),
+                ));
+            }
+        }
+        Ok(legacy)
+    }
+
+    /// Transfers that cannot be carried over: those in blocks and those
+    /// still pending.
+    pub fn transfers(&self) -> impl Iterator<Item = &LegacyTransaction> {
+        self.chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .chain(&self.pending_transactions)
+            .filter(|tx| tx.sender != LEGACY_REWARD_SENDER)
+    }
+}
+
+impl LegacyBlock {
+    /// Account the block's reward was paid to, if it is one this version can
+    /// pay: legacy miners were named by their hex public key.
+    pub fn miner(&self) -> Option<&str> {
+        self.transactions
+            .iter()
+            .find(|tx| tx.sender == LEGACY_REWARD_SENDER)
+            .map(|tx| tx.recipient.as_str())
+            .filter(|recipient| Address::from_public_key_hex(recipient).is_ok())
+    }
+}
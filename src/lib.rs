@@ -1,9 +1,28 @@
 // src/lib.rs
 
 pub mod blockchain;
+pub mod storage;
+pub mod legacy;
+pub mod state;
+pub mod shielded;
+pub mod viewing;
 pub mod block;
+pub mod encoding;
+pub mod merkle;
+pub mod difficulty;
 pub mod transaction;
+pub mod mempool;
+pub mod address;
 pub mod wallet;
+pub mod wallet_dir;
+pub mod history;
+pub mod keystore;
+pub mod hd;
 pub mod network;
+pub mod protocol;
 pub mod zk_proofs;
+pub mod mimc;
+pub mod powers;
+pub mod ceremony;
+pub mod prover;
 pub mod cli;
\ No newline at end of file
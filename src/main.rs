@@ -3,27 +3,66 @@ use tokio::sync::Mutex;
 use privacy_blockchain::blockchain::Blockchain;
 use privacy_blockchain::network::Network;
 use privacy_blockchain::cli;
+use privacy_blockchain::prover::ProverPool;
+use privacy_blockchain::zk_proofs::load_parameters;
+use std::fs;
+use std::io;
 use std::path::Path;
 
+const DATA_DIR: &str = "chaindata";
+const LEGACY_FILES: [&str; 2] = ["blockchain.dat", "blockchain.json"];
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    let matches = cli::app().get_matches();
 
-    // Load or create blockchain
-    let blockchain = if Path::new("blockchain.json").exists() {
-        match Blockchain::load_from_file("blockchain.json") {
-            Ok(bc) => bc,
-            Err(e) => {
-                eprintln!("Failed to load blockchain: {}", e);
-                Blockchain::new()
-            }
+    if let Some(setup) = matches.subcommand_matches("setup") {
+        if let Err(e) = cli::run_setup(setup.value_of("output").unwrap()) {
+            eprintln!("Setup failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(ceremony) = matches.subcommand_matches("ceremony") {
+        if let Err(e) = cli::run_ceremony(ceremony) {
+            eprintln!("Ceremony failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Proofs are only checked against the verifying keys in this file
+    let params_file = matches.value_of("params").unwrap();
+    match load_parameters(params_file) {
+        Ok(params) => println!("Loaded zk-SNARK parameters {}", params.fingerprint()),
+        Err(e) => {
+            eprintln!("Failed to load zk-SNARK parameters from {}: {} (run `setup` or a `ceremony` to create them)", params_file, e);
+            std::process::exit(1);
+        }
+    }
+
+    // Open the block store, importing a chain saved by older versions as a single file
+    let mut blockchain = match open_blockchain(matches.is_present("drop-legacy-transfers")) {
+        Ok(bc) => bc,
+        Err(e) => {
+            eprintln!("Failed to open blockchain: {}", e);
+            std::process::exit(1);
         }
-    } else {
-        Blockchain::new()
     };
+    if let Some(size) = matches.value_of("mempool-size") {
+        match size.parse() {
+            Ok(size) => blockchain.set_mempool_size(size),
+            Err(_) => {
+                eprintln!("Invalid mempool size: {}", size);
+                std::process::exit(1);
+            }
+        }
+    }
 
     let blockchain = Arc::new(Mutex::new(blockchain));
     let network = Arc::new(Mutex::new(Network::new(Arc::clone(&blockchain))));
+    let prover = Arc::new(ProverPool::with_available_parallelism());
 
     // Start the networking in a separate task
     let network_clone = Arc::clone(&network);
@@ -32,11 +71,23 @@ async fn main() {
     });
 
     // Run the CLI, passing both blockchain and network
-    cli::run_cli(Arc::clone(&blockchain), Arc::clone(&network)).await;
+    cli::run_cli(Arc::clone(&blockchain), Arc::clone(&network), prover, matches).await;
 
     // Save the blockchain state before exiting
     let bc = blockchain.lock().await;
-    if let Err(e) = bc.save_to_file("blockchain.json") {
+    if let Err(e) = bc.flush() {
         eprintln!("Failed to save blockchain: {}", e);
     }
-}
\ No newline at end of file
+}
+
+fn open_blockchain(drop_transfers: bool) -> io::Result<Blockchain> {
+    if !Path::new(DATA_DIR).exists() {
+        if let Some(legacy) = LEGACY_FILES.iter().find(|file| Path::new(file).exists()) {
+            println!("Importing {} into {}", legacy, DATA_DIR);
+            let blockchain = Blockchain::import_legacy(DATA_DIR, legacy, drop_transfers)?;
+            fs::rename(legacy, format!("{}.migrated", legacy))?;
+            return Ok(blockchain);
+        }
+    }
+    Blockchain::open(DATA_DIR)
+}
@@ -0,0 +1,250 @@
+// src/mempool.rs
+
+use crate::transaction::Transaction;
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap};
+
+/// Default number of transactions the pool holds before evicting.
+pub const DEFAULT_MEMPOOL_SIZE: usize = 5_000;
+
+/// Pending transactions ordered by fee rate.
+///
+/// Transactions are keyed by sender and nonce, so each sender's queue is always
+/// in the order it has to be mined. Selection and eviction only ever look at the
+/// front (respectively back) of each queue, which keeps nonce sequences intact.
+/// Spends from the shielded pool carry no nonce and depend on nothing else
+/// pending, so each one is a queue of its own (see `queue_key`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "MempoolSnapshot", into = "MempoolSnapshot")]
+pub struct Mempool {
+    transactions: BTreeMap<(String, u64), Transaction>,
+    max_size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MempoolSnapshot {
+    max_size: usize,
+    transactions: Vec<Transaction>,
+}
+
+impl From<MempoolSnapshot> for Mempool {
+    fn from(snapshot: MempoolSnapshot) -> Self {
+        let mut mempool = Mempool::new(snapshot.max_size);
+        for tx in snapshot.transactions {
+            mempool.transactions.insert(queue_key(&tx), tx);
+        }
+        mempool
+    }
+}
+
+impl From<Mempool> for MempoolSnapshot {
+    fn from(mempool: Mempool) -> Self {
+        MempoolSnapshot {
+            max_size: mempool.max_size,
+            transactions: mempool.transactions.into_values().collect(),
+        }
+    }
+}
+
+impl Encode for Mempool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.max_size as u64).encode(out);
+        // Same layout as `Vec<Transaction>`, without collecting into one first
+        (self.transactions.len() as u32).encode(out);
+        for tx in self.transactions.values() {
+            tx.encode(out);
+        }
+    }
+}
+
+impl Decode for Mempool {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let max_size = u64::decode(reader)? as usize;
+        let transactions = Vec::decode(reader)?;
+        Ok(MempoolSnapshot { max_size, transactions }.into())
+    }
+}
+
+/// A sender's next minable transaction, ordered by fee rate.
+struct Candidate<'a>(&'a Transaction);
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_fee_rate(self.0, other.0)
+    }
+}
+
+/// Key of a transaction: the queue it waits in, then its place in that queue.
+///
+/// A queue is a sender's transactions in nonce order, except that every pool
+/// spend is a queue of one, named after the pool and its first nullifier.
+fn queue_key(tx: &Transaction) -> (String, u64) {
+    match tx.shielded.as_ref().and_then(|bundle| bundle.nullifiers.first()) {
+        Some(nullifier) if !tx.uses_nonce() => (format!("{}:{}", tx.sender, hex::encode(nullifier)), tx.nonce),
+        _ => (tx.sender.clone(), tx.nonce),
+    }
+}
+
+/// Compares fee per byte without floating point: a.fee / a.size vs b.fee / b.size.
+fn compare_fee_rate(a: &Transaction, b: &Transaction) -> Ordering {
+    let lhs = a.fee as u128 * b.size() as u128;
+    let rhs = b.fee as u128 * a.size() as u128;
+    lhs.cmp(&rhs)
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Mempool {
+            transactions: BTreeMap::new(),
+            max_size,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Iterates over pending transactions, grouped by sender in nonce order.
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values()
+    }
+
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.transactions.contains_key(&queue_key(tx))
+    }
+
+    /// Changes how many transactions the pool holds, evicting the lowest fee-rate
+    /// entries if it now holds too many.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        while self.transactions.len() > self.max_size {
+            let Some(key) = self.lowest_tail(None) else {
+                break;
+            };
+            self.transactions.remove(&key);
+        }
+    }
+
+    /// Adds a transaction, evicting the lowest fee-rate entry if the pool is full.
+    ///
+    /// Only the last transaction of each sender can be evicted, so no remaining
+    /// entry is left waiting on a missing nonce, and never one of `tx`'s own
+    /// queue, which it may depend on. Returns the evicted transaction, or gives
+    /// `tx` back as the error if it pays too little to displace anything.
+    pub fn insert(&mut self, tx: Transaction) -> Result<Option<Transaction>, Box<Transaction>> {
+        let mut evicted = None;
+        if self.transactions.len() >= self.max_size {
+            let key = queue_key(&tx);
+            match self.lowest_tail(Some(&key.0)) {
+                Some(key) if compare_fee_rate(&tx, &self.transactions[&key]) == Ordering::Greater => {
+                    evicted = self.transactions.remove(&key);
+                }
+                _ => return Err(Box::new(tx)),
+            }
+        }
+        self.transactions.insert(queue_key(&tx), tx);
+        Ok(evicted)
+    }
+
+    /// Removes a transaction if present.
+    pub fn remove(&mut self, tx: &Transaction) -> Option<Transaction> {
+        self.transactions.remove(&queue_key(tx))
+    }
+
+    /// Keeps only the transactions for which `keep` returns true, visiting each
+    /// queue in nonce order.
+    pub fn retain<F: FnMut(&Transaction) -> bool>(&mut self, mut keep: F) {
+        self.transactions.retain(|_, tx| keep(tx));
+    }
+
+    /// Picks up to `limit` transactions for a block, highest fee rate first.
+    ///
+    /// `accept` sees each candidate in the order it would be mined; when it
+    /// returns false the rest of that queue is skipped. Returns the
+    /// accepted transactions and the rejected ones.
+    pub fn select<F: FnMut(&Transaction) -> bool>(
+        &self,
+        limit: usize,
+        mut accept: F,
+    ) -> (Vec<Transaction>, Vec<Transaction>) {
+        let mut heap: BinaryHeap<Candidate> = self.queue_heads().map(Candidate).collect();
+        let mut selected = vec![];
+        let mut rejected = vec![];
+
+        while selected.len() < limit {
+            let Some(Candidate(tx)) = heap.pop() else {
+                break;
+            };
+            if !accept(tx) {
+                rejected.push(tx.clone());
+                continue;
+            }
+            selected.push(tx.clone());
+            let (queue, nonce) = queue_key(tx);
+            if let Some(next) = self.transactions.get(&(queue, nonce + 1)) {
+                heap.push(Candidate(next));
+            }
+        }
+        (selected, rejected)
+    }
+
+    /// Key of the lowest fee-rate transaction that can be evicted: the last one
+    /// of some queue other than `exclude`.
+    fn lowest_tail(&self, exclude: Option<&str>) -> Option<(String, u64)> {
+        self.queue_tails()
+            .filter(|(key, _)| Some(key.0.as_str()) != exclude)
+            .min_by(|a, b| compare_fee_rate(a.1, b.1))
+            .map(|(key, _)| key.clone())
+    }
+
+    /// Lowest-nonce transaction of every queue.
+    fn queue_heads(&self) -> impl Iterator<Item = &Transaction> {
+        let mut last_queue: Option<&str> = None;
+        self.transactions.iter().filter_map(move |((queue, _), tx)| {
+            let is_head = last_queue != Some(queue.as_str());
+            last_queue = Some(queue.as_str());
+            is_head.then_some(tx)
+        })
+    }
+
+    /// Highest-nonce transaction of every queue, with its key.
+    fn queue_tails(&self) -> impl Iterator<Item = (&(String, u64), &Transaction)> {
+        let mut iter = self.transactions.iter().peekable();
+        std::iter::from_fn(move || loop {
+            let (key, tx) = iter.next()?;
+            if iter.peek().is_none_or(|(next, _)| next.0 != key.0) {
+                return Some((key, tx));
+            }
+        })
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMPOOL_SIZE)
+    }
+}
@@ -0,0 +1,104 @@
+// src/merkle.rs
+
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+// Leaves and inner nodes are hashed with different prefixes so that an inner
+// node can never be passed off as a transaction id
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// One step from a leaf towards the root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleStep {
+    /// Hex-encoded hash of the sibling node.
+    pub hash: String,
+    /// True if the sibling sits to the left of the running hash.
+    pub is_left: bool,
+}
+
+/// Proof that a transaction id is included under a Merkle root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub steps: Vec<MerkleStep>,
+}
+
+fn hash_leaf(leaf: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf.as_bytes());
+    hasher.finalize().into()
+}
+
+fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Hashes one level of the tree into the next. An odd node out is carried up
+/// unchanged rather than paired with a copy of itself.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Computes the hex-encoded Merkle root of `leaves` (e.g. transaction ids).
+/// An empty tree has the all-zero root.
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return hex::encode([0u8; 32]);
+    }
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    hex::encode(level[0])
+}
+
+/// Builds the inclusion proof for the leaf at `index`.
+pub fn merkle_proof(leaves: &[String], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    let mut position = index;
+    let mut steps = vec![];
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            steps.push(MerkleStep {
+                hash: hex::encode(level[sibling]),
+                is_left: sibling < position,
+            });
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof { steps })
+}
+
+/// Checks that `leaf` is included under the hex-encoded `root`.
+pub fn verify_merkle_proof(leaf: &str, proof: &MerkleProof, root: &str) -> bool {
+    let mut current = hash_leaf(leaf);
+    for step in &proof.steps {
+        let mut sibling = [0u8; 32];
+        if hex::decode_to_slice(&step.hash, &mut sibling).is_err() {
+            return false;
+        }
+        current = if step.is_left {
+            hash_node(&sibling, &current)
+        } else {
+            hash_node(&current, &sibling)
+        };
+    }
+    hex::encode(current) == root
+}
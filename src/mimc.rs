@@ -0,0 +1,146 @@
+// src/mimc.rs
+//
+// MiMC-Feistel sponge over the BLS12-381 scalar field.
+//
+// Each permutation runs `MIMC_ROUNDS` Feistel rounds of
+// `(xl, xr) -> (xr + (xl + c_i)^3, xl)`, the round count bellman's MiMC example
+// uses for this field. Inputs are absorbed one element at a time into `xl`,
+// with `xr` as the capacity, and the hash is `xl` after the last permutation.
+// Each round costs two constraints, which keeps tree paths and nullifiers
+// affordable inside the shielded circuits.
+
+use bellman::gadgets::num::AllocatedNum;
+use bellman::{ConstraintSystem, LinearCombination, SynthesisError};
+use blstrs::Scalar as Fr;
+use ff::Field;
+use sha2::{Sha256, Digest};
+use std::sync::OnceLock;
+
+pub const MIMC_ROUNDS: usize = 322;
+
+const CONSTANTS_DOMAIN: &[u8] = b"privacy_blockchain/mimc";
+
+/// Round constants derived by hashing the domain and round index. Clearing the
+/// top two bits keeps each value below the field modulus.
+fn round_constants() -> &'static [Fr] {
+    static CONSTANTS: OnceLock<Vec<Fr>> = OnceLock::new();
+    CONSTANTS.get_or_init(|| {
+        (0..MIMC_ROUNDS as u32)
+            .map(|i| {
+                let mut bytes: [u8; 32] = Sha256::new()
+                    .chain_update(CONSTANTS_DOMAIN)
+                    .chain_update(i.to_le_bytes())
+                    .finalize()
+                    .into();
+                bytes[31] &= 0x3f;
+                Fr::from_bytes_le(&bytes).unwrap()
+            })
+            .collect()
+    })
+}
+
+fn permute(mut xl: Fr, mut xr: Fr) -> (Fr, Fr) {
+    for c in round_constants() {
+        let shifted = xl + c;
+        let new_xl = shifted.square() * shifted + xr;
+        xr = xl;
+        xl = new_xl;
+    }
+    (xl, xr)
+}
+
+/// Hashes a sequence of field elements.
+pub fn hash(inputs: &[Fr]) -> Fr {
+    let (mut xl, mut xr) = (Fr::ZERO, Fr::ZERO);
+    for input in inputs {
+        (xl, xr) = permute(xl + input, xr);
+    }
+    xl
+}
+
+/// A linear combination together with its value, if known.
+#[derive(Clone)]
+pub struct Expression {
+    pub lc: LinearCombination<Fr>,
+    pub value: Option<Fr>,
+}
+
+impl Expression {
+    pub fn zero() -> Self {
+        Expression { lc: LinearCombination::zero(), value: Some(Fr::ZERO) }
+    }
+
+    fn plus(&self, other: &Expression) -> Self {
+        Expression {
+            lc: self.lc.clone() + &other.lc,
+            value: self.value.zip(other.value).map(|(a, b)| a + b),
+        }
+    }
+}
+
+impl From<&AllocatedNum<Fr>> for Expression {
+    fn from(num: &AllocatedNum<Fr>) -> Self {
+        Expression { lc: LinearCombination::zero() + num.get_variable(), value: num.get_value() }
+    }
+}
+
+/// In-circuit permutation. Returns the final `xl` and `xr`.
+fn permute_gadget<CS: ConstraintSystem<Fr>>(
+    mut cs: CS,
+    xl: Expression,
+    xr: Expression,
+) -> Result<(AllocatedNum<Fr>, AllocatedNum<Fr>), SynthesisError> {
+    let (mut xl, mut xr) = (xl, xr);
+    let mut state = None;
+    for (i, c) in round_constants().iter().enumerate() {
+        let mut cs = cs.namespace(|| format!("round {}", i));
+        let shifted = xl.lc.clone() + (*c, CS::one());
+        let shifted_value = xl.value.map(|x| x + c);
+
+        let square = AllocatedNum::alloc(cs.namespace(|| "square"), || {
+            shifted_value.map(|x| x.square()).ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        cs.enforce(
+            || "square = (xl + c)^2",
+            |lc| lc + &shifted,
+            |lc| lc + &shifted,
+            |lc| lc + square.get_variable(),
+        );
+
+        let new_xl = AllocatedNum::alloc(cs.namespace(|| "new xl"), || {
+            let cube = square.get_value().zip(shifted_value).map(|(s, x)| s * x);
+            cube.zip(xr.value).map(|(cube, xr)| cube + xr).ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        cs.enforce(
+            || "new xl = (xl + c)^3 + xr",
+            |lc| lc + square.get_variable(),
+            |lc| lc + &shifted,
+            |lc| lc + new_xl.get_variable() - &xr.lc,
+        );
+
+        xr = xl;
+        xl = Expression::from(&new_xl);
+        let previous = state.take().map(|(current, _)| current);
+        state = Some((new_xl, previous));
+    }
+    match state {
+        Some((xl, Some(xr))) => Ok((xl, xr)),
+        _ => unreachable!("MiMC has more than one round"),
+    }
+}
+
+/// In-circuit `hash`.
+pub fn hash_gadget<CS: ConstraintSystem<Fr>>(
+    mut cs: CS,
+    inputs: &[Expression],
+) -> Result<AllocatedNum<Fr>, SynthesisError> {
+    let (mut xl, mut xr) = (Expression::zero(), Expression::zero());
+    let mut output = None;
+    for (i, input) in inputs.iter().enumerate() {
+        let (new_xl, new_xr) = permute_gadget(cs.namespace(|| format!("absorb {}", i)), xl.plus(input), xr)?;
+        xl = Expression::from(&new_xl);
+        xr = Expression::from(&new_xr);
+        output = Some(new_xl);
+    }
+    output.ok_or(SynthesisError::Unsatisfiable)
+}
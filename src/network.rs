@@ -1,9 +1,9 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use serde_json::Value;
 use tokio::sync::{Mutex};
 use std::sync::Arc;
+use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::protocol::{read_message, write_message, Message, MAX_BLOCKS_BYTES, MAX_BLOCKS_PER_MESSAGE};
 use log::{info, error};
 use std::collections::HashSet;
 use std::net::SocketAddr;
@@ -37,7 +37,7 @@ impl Network {
             }
 
             tokio::spawn(async move {
-                handle_connection(socket, blockchain, peers).await;
+                handle_connection(socket, blockchain, peers, false).await;
             });
         }
     }
@@ -56,13 +56,17 @@ impl Network {
                     }
                 }
     
-                // Send initial request for blockchain synchronization
-                if let Ok(blockchain) = serde_json::to_string(&self.blockchain.lock().await.chain) {
-                    if let Err(e) = stream.write_all(blockchain.as_bytes()).await {
-                        error!("Failed to send blockchain data to peer: {}", e);
-                        return Err(format!("Failed to send blockchain data to peer: {}", e));
-                    }
+                // Announce our tip; the peer answers with its own and both sides sync from there
+                let hello = hello_message(&self.blockchain).await;
+                if let Err(e) = write_message(&mut stream, &hello).await {
+                    error!("Failed to send handshake to peer: {}", e);
+                    return Err(format!("Failed to send handshake to peer: {}", e));
                 }
+
+                let blockchain = Arc::clone(&self.blockchain);
+                tokio::spawn(async move {
+                    handle_connection(stream, blockchain, peers, true).await;
+                });
                 Ok(())
             }
             Err(e) => {
@@ -78,13 +82,46 @@ impl Network {
     }
 }
 
-async fn handle_connection(mut socket: TcpStream, blockchain: Arc<Mutex<Blockchain>>, peers: Arc<Mutex<HashSet<SocketAddr>>>) {
-    let mut buffer = [0u8; 1024];
+async fn hello_message(blockchain: &Arc<Mutex<Blockchain>>) -> Message {
+    let blockchain_guard = blockchain.lock().await;
+    let tip = blockchain_guard.get_latest_block();
+    Message::Hello { height: tip.header.index, best_hash: tip.hash.clone() }
+}
+
+/// What a connection remembers between messages.
+#[derive(Default)]
+struct PeerState {
+    sent_hello: bool,
+    /// Blocks of a peer branch that forks from our chain, collected page by
+    /// page until the peer has sent all of it.
+    branch: Vec<Block>,
+}
+
+/// Asks for the blocks after the last one we share with the peer.
+fn get_blocks(blockchain: &Blockchain) -> Message {
+    Message::GetBlocks { locator: blockchain.block_locator() }
+}
+
+async fn handle_connection(
+    mut socket: TcpStream,
+    blockchain: Arc<Mutex<Blockchain>>,
+    peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    sent_hello: bool,
+) {
+    let mut state = PeerState { sent_hello, ..Default::default() };
+    let peer_addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Failed to read peer address: {}", e);
+            return;
+        }
+    };
+
     loop {
-        let n = match socket.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
+        let msg = match read_message(&mut socket).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
                 // Connection closed
-                let peer_addr = socket.peer_addr().unwrap();
                 {
                     let mut peers_guard = peers.lock().await;
                     peers_guard.remove(&peer_addr);
@@ -92,30 +129,114 @@ async fn handle_connection(mut socket: TcpStream, blockchain: Arc<Mutex<Blockcha
                 info!("Connection closed: {}", peer_addr);
                 return;
             }
-            Ok(n) => n,
-            Err(_) => {
-                error!("Failed to read from socket");
+            Err(e) => {
+                error!("Failed to read message from {}: {}", peer_addr, e);
+                let mut peers_guard = peers.lock().await;
+                peers_guard.remove(&peer_addr);
                 return;
             }
         };
 
-        let received = String::from_utf8_lossy(&buffer[..n]);
-        match serde_json::from_str::<Value>(&received) {
-            Ok(msg) => {
-                // Handle the received message
-                info!("Received: {:?}", msg);
-                if let Some(chain) = msg.get("chain") {
-                    let mut blockchain_guard = blockchain.lock().await;
-                    let new_chain: Vec<_> = serde_json::from_value(chain.clone()).unwrap_or_else(|_| blockchain_guard.chain.clone());
-                    if new_chain.len() > blockchain_guard.chain.len() {
-                        blockchain_guard.chain = new_chain;
-                        info!("Blockchain updated from peer");
-                    }
+        if let Some(reply) = handle_message(msg, &blockchain, &mut state).await {
+            for message in reply {
+                if let Err(e) = write_message(&mut socket, &message).await {
+                    error!("Failed to send message to {}: {}", peer_addr, e);
+                    return;
                 }
             }
-            Err(e) => {
-                error!("Failed to parse message: {}", e);
+        }
+    }
+}
+
+/// Applies a message from a peer and returns any messages to send back.
+async fn handle_message(
+    msg: Message,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    state: &mut PeerState,
+) -> Option<Vec<Message>> {
+    match msg {
+        Message::Hello { height, best_hash } => {
+            info!("Peer at height {} with tip {}", height, best_hash);
+            let mut replies = vec![];
+            if !state.sent_hello {
+                replies.push(hello_message(blockchain).await);
+                state.sent_hello = true;
+            }
+            let blockchain_guard = blockchain.lock().await;
+            let tip = blockchain_guard.get_latest_block();
+            if height >= tip.header.index && best_hash != tip.hash {
+                replies.push(get_blocks(&blockchain_guard));
             }
+            Some(replies)
         }
+        Message::GetBlocks { locator } => {
+            let blockchain_guard = blockchain.lock().await;
+            match blockchain_guard.blocks_after(&locator, MAX_BLOCKS_PER_MESSAGE, MAX_BLOCKS_BYTES) {
+                Ok(blocks) => Some(vec![Message::Blocks(blocks)]),
+                Err(e) => {
+                    error!("Failed to read blocks for peer: {}", e);
+                    None
+                }
+            }
+        }
+        Message::Blocks(blocks) => {
+            let mut blockchain_guard = blockchain.lock().await;
+            let Some(first) = blocks.first() else {
+                // The peer has sent everything; a branch may now carry more work
+                if !state.branch.is_empty() {
+                    sync_chain(&mut blockchain_guard, std::mem::take(&mut state.branch));
+                }
+                return None;
+            };
+            let extends_tip = first.header.previous_hash == blockchain_guard.get_latest_block().hash;
+            if state.branch.is_empty() && extends_tip {
+                // Ask for the next page only while the pages keep applying
+                return sync_chain(&mut blockchain_guard, blocks).then(|| vec![get_blocks(&blockchain_guard)]);
+            }
+            // A page of a branch, which can only be weighed once it is complete
+            if state.branch.last().is_some_and(|last| last.hash != first.header.previous_hash) {
+                state.branch.clear();
+            }
+            state.branch.extend(blocks);
+            let last = state.branch.last().expect("the page is not empty");
+            let mut locator = vec![(last.header.index, last.hash.clone())];
+            locator.extend(blockchain_guard.block_locator());
+            Some(vec![Message::GetBlocks { locator }])
+        }
+        Message::NewTransaction(tx) => {
+            if let Err(e) = blockchain.lock().await.add_transaction(tx) {
+                error!("Rejected transaction from peer: {}", e);
+            }
+            None
+        }
+        Message::NewBlock(block) => {
+            let mut blockchain_guard = blockchain.lock().await;
+            if block.header.previous_hash != blockchain_guard.get_latest_block().hash {
+                // We are missing its ancestors or it is on another branch
+                return Some(vec![get_blocks(&blockchain_guard)]);
+            }
+            sync_chain(&mut blockchain_guard, vec![block]);
+            None
+        }
+        Message::Ping(nonce) => Some(vec![Message::Pong(nonce)]),
+        Message::Pong(_) => None,
     }
-}
\ No newline at end of file
+}
+
+/// Applies fork choice to `candidate`, returning whether our chain changed.
+fn sync_chain(blockchain_guard: &mut Blockchain, candidate: Vec<Block>) -> bool {
+    match blockchain_guard.try_reorganize(candidate) {
+        Ok(true) => {
+            info!("Blockchain updated from peer");
+            true
+        }
+        Ok(false) => {
+            info!("Kept local chain: peer chain has no more work");
+            false
+        }
+        Err(e) => {
+            error!("Rejected chain from peer: {}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,516 @@
+// src/powers.rs
+//
+// Powers of tau: the first phase of the multi-party setup (see `ceremony`).
+//
+// Groth16 parameters hide three secrets that do not depend on the circuit:
+// `tau`, the point the circuit's polynomials are evaluated at, and `alpha`
+// and `beta`. The accumulator holds them only in the exponent: `tau^i * G1`
+// for i < 2n - 1, and `tau^i * G2`, `alpha * tau^i * G1` and
+// `beta * tau^i * G1` for i < n, and `beta * G2`. It starts out from the
+// generators, i.e. with every secret equal to one, and each participant
+// multiplies the secrets by factors of their own. Whether the result is
+// still well formed, with consecutive powers differing by the same `tau`,
+// comes down to a handful of pairings over random linear combinations.
+//
+// Parameters for any circuit of at most `n` constraints follow from the
+// accumulator alone: the evaluation bellman's `generate_parameters` does
+// with the secrets in hand, carried out on group elements instead. Their
+// `gamma` and `delta` are one; the second phase randomizes `delta`.
+
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use bellman::groth16::{Parameters, VerifyingKey};
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use blstrs::{pairing, Bls12, G1Affine, G1Projective, G2Affine, G2Projective, Scalar as Fr};
+use ff::{Field, PrimeField};
+use group::prime::PrimeCurveAffine;
+use group::{Curve, Group};
+use rand_core::OsRng;
+use std::collections::BTreeMap;
+use std::ops::{Add, Mul, Sub};
+use std::sync::Arc;
+
+/// Returns true if `b / a == d / c` for the hidden exponents, i.e. both pairs
+/// differ by the same factor.
+pub(crate) fn same_ratio(g1: (G1Affine, G1Affine), g2: (G2Affine, G2Affine)) -> bool {
+    pairing(&g1.0, &g2.1) == pairing(&g1.1, &g2.0)
+}
+
+/// The secrets of the first phase, in the exponent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowersOfTau {
+    tau_g1: Vec<G1Affine>,
+    tau_g2: Vec<G2Affine>,
+    alpha_tau_g1: Vec<G1Affine>,
+    beta_tau_g1: Vec<G1Affine>,
+    beta_g2: G2Affine,
+}
+
+impl PowersOfTau {
+    /// Powers for circuits of up to `size` constraints, a power of two, with
+    /// every secret still one.
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two() && size > 1, "size must be a power of two");
+        PowersOfTau {
+            tau_g1: vec![G1Affine::generator(); 2 * size - 1],
+            tau_g2: vec![G2Affine::generator(); size],
+            alpha_tau_g1: vec![G1Affine::generator(); size],
+            beta_tau_g1: vec![G1Affine::generator(); size],
+            beta_g2: G2Affine::generator(),
+        }
+    }
+
+    /// Most constraints a circuit can have to get parameters from these powers.
+    pub fn size(&self) -> usize {
+        self.tau_g2.len()
+    }
+
+    /// `tau * G1`.
+    pub(crate) fn tau(&self) -> G1Affine {
+        self.tau_g1[1]
+    }
+
+    /// `alpha * G1`.
+    pub(crate) fn alpha(&self) -> G1Affine {
+        self.alpha_tau_g1[0]
+    }
+
+    /// `beta * G1`.
+    pub(crate) fn beta(&self) -> G1Affine {
+        self.beta_tau_g1[0]
+    }
+
+    /// Multiplies `tau`, `alpha` and `beta` by the given factors.
+    pub(crate) fn transform(&mut self, tau: Fr, alpha: Fr, beta: Fr) {
+        let powers: Vec<Fr> = std::iter::successors(Some(Fr::ONE), |power| Some(power * tau))
+            .take(self.tau_g1.len())
+            .collect();
+        let size = self.size();
+        let scaled = |by: Fr| -> Vec<Fr> { powers[..size].iter().map(|power| power * by).collect() };
+        self.tau_g1 = scale_each(&self.tau_g1, &powers);
+        self.tau_g2 = scale_each(&self.tau_g2, &powers);
+        self.alpha_tau_g1 = scale_each(&self.alpha_tau_g1, &scaled(alpha));
+        self.beta_tau_g1 = scale_each(&self.beta_tau_g1, &scaled(beta));
+        self.beta_g2 = (self.beta_g2 * beta).to_affine();
+    }
+
+    /// Returns true if every list holds consecutive powers of the same `tau`,
+    /// starting at the generator or at `alpha` and `beta` respectively, and
+    /// `beta` is the same in both groups. None of the secrets may be zero.
+    pub fn is_well_formed(&self) -> bool {
+        let (g1, g2) = (G1Affine::generator(), G2Affine::generator());
+        let tau_g2 = self.tau_g2[1];
+        self.tau_g1[0] == g1
+            && self.tau_g2[0] == g2
+            && ![self.tau(), self.alpha(), self.beta()].iter().any(|point| bool::from(point.is_identity()))
+            && same_ratio(consecutive_g1(&self.tau_g1), (g2, tau_g2))
+            && same_ratio(consecutive_g1(&self.alpha_tau_g1), (g2, tau_g2))
+            && same_ratio(consecutive_g1(&self.beta_tau_g1), (g2, tau_g2))
+            && same_ratio((g1, self.tau()), consecutive_g2(&self.tau_g2))
+            && same_ratio((g1, self.beta()), (g2, self.beta_g2))
+    }
+
+    /// Groth16 parameters for `circuit` with `gamma` and `delta` one, or
+    /// `PolynomialDegreeTooLarge` if it has more constraints than the powers
+    /// allow.
+    pub fn parameters<C: Circuit<Fr>>(&self, circuit: C) -> Result<Parameters<Bls12>, SynthesisError> {
+        let assembly = Assembly::synthesize(circuit)?;
+        let m = self.domain_size(&assembly)?;
+        let lagrange_g1 = lagrange(&self.tau_g1[..m]);
+        let lagrange_g2 = lagrange(&self.tau_g2[..m]);
+        let alpha_lagrange = lagrange(&self.alpha_tau_g1[..m]);
+        let beta_lagrange = lagrange(&self.beta_tau_g1[..m]);
+
+        let mut a = vec![];
+        let mut b_g1 = vec![];
+        let mut b_g2 = vec![];
+        let mut ext = vec![];
+        for column in assembly.columns() {
+            a.push(evaluate(&lagrange_g1, &column.a));
+            b_g1.push(evaluate(&lagrange_g1, &column.b));
+            b_g2.push(evaluate(&lagrange_g2, &column.b));
+            ext.push(
+                evaluate(&beta_lagrange, &column.a)
+                    + evaluate(&alpha_lagrange, &column.b)
+                    + evaluate(&lagrange_g1, &column.c),
+            );
+        }
+        let mut ic = normalize(&ext);
+        let l = ic.split_off(assembly.inputs.len());
+        if l.iter().any(|point| bool::from(point.is_identity())) {
+            return Err(SynthesisError::UnconstrainedVariable);
+        }
+        let (g1, g2) = (G1Affine::generator(), G2Affine::generator());
+        let vk = VerifyingKey {
+            alpha_g1: self.alpha(),
+            beta_g1: self.beta(),
+            beta_g2: self.beta_g2,
+            gamma_g2: g2,
+            delta_g1: g1,
+            delta_g2: g2,
+            ic,
+        };
+        // Like bellman, leave out the points at infinity of the A and B queries
+        Ok(Parameters {
+            vk,
+            h: Arc::new(normalize(&self.h_query(m))),
+            l: Arc::new(l),
+            a: Arc::new(non_zero(normalize(&a))),
+            b_g1: Arc::new(non_zero(normalize(&b_g1))),
+            b_g2: Arc::new(non_zero(normalize(&b_g2))),
+        })
+    }
+
+    /// Returns true if `params` are what `parameters` makes of `circuit`.
+    ///
+    /// Rather than evaluating every query again, each query is compared
+    /// through a random linear combination of its elements: the same
+    /// combination of the circuit's polynomials, interpolated in the field,
+    /// gives coefficients for a single multi-exponentiation over the powers.
+    pub fn derived<C: Circuit<Fr>>(&self, circuit: C, params: &Parameters<Bls12>) -> Result<bool, SynthesisError> {
+        let assembly = Assembly::synthesize(circuit)?;
+        let Ok(m) = self.domain_size(&assembly) else {
+            return Ok(false);
+        };
+        let (g1, g2) = (G1Affine::generator(), G2Affine::generator());
+        let vk = &params.vk;
+        if vk.alpha_g1 != self.alpha()
+            || vk.beta_g1 != self.beta()
+            || vk.beta_g2 != self.beta_g2
+            || vk.gamma_g2 != g2
+            || vk.delta_g1 != g1
+            || vk.delta_g2 != g2
+            || params.h.as_slice() != normalize(&self.h_query(m))
+        {
+            return Ok(false);
+        }
+
+        let columns: Vec<&Column> = assembly.columns().collect();
+        let a_columns: Vec<&Column> = columns.iter().copied().filter(|column| !is_zero(&column.a)).collect();
+        let b_columns: Vec<&Column> = columns.iter().copied().filter(|column| !is_zero(&column.b)).collect();
+        let ext: Vec<G1Affine> = vk.ic.iter().chain(params.l.iter()).copied().collect();
+        if params.a.len() != a_columns.len()
+            || params.b_g1.len() != b_columns.len()
+            || params.b_g2.len() != b_columns.len()
+            || vk.ic.len() != assembly.inputs.len()
+            || ext.len() != columns.len()
+        {
+            return Ok(false);
+        }
+
+        // Coefficients, in the power basis, of a random combination of the
+        // columns' polynomials
+        let combine = |columns: &[&Column], polynomial: fn(&Column) -> &[(Fr, usize)], weights: &[Fr]| {
+            let mut evaluations = vec![Fr::ZERO; m];
+            for (column, weight) in columns.iter().zip(weights) {
+                for (coeff, constraint) in polynomial(column) {
+                    evaluations[*constraint] += coeff * weight;
+                }
+            }
+            ifft(&mut evaluations);
+            evaluations
+        };
+        let weights = |count: usize| -> Vec<Fr> { (0..count).map(|_| Fr::random(&mut OsRng)).collect() };
+        let tau_g1 = projective(&self.tau_g1[..m]);
+
+        let w = weights(a_columns.len());
+        if combine_g1(&params.a, &w) != G1Projective::multi_exp(&tau_g1, &combine(&a_columns, |c| &c.a, &w)) {
+            return Ok(false);
+        }
+        let w = weights(b_columns.len());
+        let b = combine(&b_columns, |c| &c.b, &w);
+        if combine_g1(&params.b_g1, &w) != G1Projective::multi_exp(&tau_g1, &b)
+            || combine_g2(&params.b_g2, &w) != G2Projective::multi_exp(&projective(&self.tau_g2[..m]), &b)
+        {
+            return Ok(false);
+        }
+        let w = weights(columns.len());
+        let expected = G1Projective::multi_exp(&projective(&self.beta_tau_g1[..m]), &combine(&columns, |c| &c.a, &w))
+            + G1Projective::multi_exp(&projective(&self.alpha_tau_g1[..m]), &combine(&columns, |c| &c.b, &w))
+            + G1Projective::multi_exp(&tau_g1, &combine(&columns, |c| &c.c, &w));
+        Ok(combine_g1(&ext, &w) == expected)
+    }
+
+    /// Evaluation domain of the circuit, if the powers are enough for it.
+    fn domain_size(&self, assembly: &Assembly) -> Result<usize, SynthesisError> {
+        let m = assembly.domain_size();
+        if m > self.size() {
+            return Err(SynthesisError::PolynomialDegreeTooLarge);
+        }
+        Ok(m)
+    }
+
+    /// `tau^i * t(tau) * G1` for i < m - 1, where `t(x) = x^m - 1` vanishes
+    /// on the domain.
+    fn h_query(&self, m: usize) -> Vec<G1Projective> {
+        (0..m - 1)
+            .map(|i| G1Projective::from(self.tau_g1[i + m]) - G1Projective::from(self.tau_g1[i]))
+            .collect()
+    }
+}
+
+/// Number of constraints, rounded up to a power of two, that parameters for
+/// `circuit` need from the powers of tau.
+pub(crate) fn domain_size<C: Circuit<Fr>>(circuit: C) -> Result<usize, SynthesisError> {
+    Ok(Assembly::synthesize(circuit)?.domain_size())
+}
+
+/// Each point times the matching factor.
+fn scale_each<C: PrimeCurveAffine<Scalar = Fr>>(points: &[C], factors: &[Fr]) -> Vec<C> {
+    let scaled: Vec<C::Curve> = points.iter().zip(factors).map(|(point, factor)| *point * factor).collect();
+    normalize(&scaled)
+}
+
+fn normalize<G: Curve>(points: &[G]) -> Vec<G::AffineRepr>
+where
+    G::AffineRepr: PrimeCurveAffine,
+{
+    let mut affine = vec![G::AffineRepr::identity(); points.len()];
+    G::batch_normalize(points, &mut affine);
+    affine
+}
+
+fn non_zero<C: PrimeCurveAffine>(points: Vec<C>) -> Vec<C> {
+    points.into_iter().filter(|point| !bool::from(point.is_identity())).collect()
+}
+
+fn projective<C: PrimeCurveAffine>(points: &[C]) -> Vec<C::Curve> {
+    points.iter().map(PrimeCurveAffine::to_curve).collect()
+}
+
+fn combine_g1(points: &[G1Affine], weights: &[Fr]) -> G1Projective {
+    G1Projective::multi_exp(&projective(points), weights)
+}
+
+fn combine_g2(points: &[G2Affine], weights: &[Fr]) -> G2Projective {
+    G2Projective::multi_exp(&projective(points), weights)
+}
+
+/// The same random combination of `points[..n - 1]` and of `points[1..]`,
+/// which differ by `tau` if the points are consecutive powers.
+fn consecutive_g1(points: &[G1Affine]) -> (G1Affine, G1Affine) {
+    let weights: Vec<Fr> = points[1..].iter().map(|_| Fr::random(&mut OsRng)).collect();
+    let (head, tail) = (&points[..points.len() - 1], &points[1..]);
+    (combine_g1(head, &weights).to_affine(), combine_g1(tail, &weights).to_affine())
+}
+
+fn consecutive_g2(points: &[G2Affine]) -> (G2Affine, G2Affine) {
+    let weights: Vec<Fr> = points[1..].iter().map(|_| Fr::random(&mut OsRng)).collect();
+    let (head, tail) = (&points[..points.len() - 1], &points[1..]);
+    (combine_g2(head, &weights).to_affine(), combine_g2(tail, &weights).to_affine())
+}
+
+/// Turns `tau^i * G` for i < m into `L_i(tau) * G`, where `L_i` are the
+/// Lagrange polynomials of the domain of size m.
+fn lagrange<C: PrimeCurveAffine<Scalar = Fr>>(powers: &[C]) -> Vec<C::Curve> {
+    let mut points = projective(powers);
+    ifft(&mut points);
+    points
+}
+
+/// `sum(coeff * basis[constraint])` over the terms of one polynomial.
+fn evaluate<G: Group<Scalar = Fr>>(basis: &[G], terms: &[(Fr, usize)]) -> G {
+    terms.iter().fold(G::identity(), |sum, (coeff, constraint)| {
+        if *coeff == Fr::ONE {
+            sum + basis[*constraint]
+        } else {
+            sum + basis[*constraint] * coeff
+        }
+    })
+}
+
+/// Returns true if the terms of a polynomial cancel out.
+fn is_zero(terms: &[(Fr, usize)]) -> bool {
+    let mut sums: BTreeMap<usize, Fr> = BTreeMap::new();
+    for (coeff, constraint) in terms {
+        *sums.entry(*constraint).or_insert(Fr::ZERO) += coeff;
+    }
+    sums.values().all(|sum| bool::from(sum.is_zero()))
+}
+
+/// Inverse FFT over the domain of size `values.len()`, a power of two, for
+/// field elements and curve points alike.
+fn ifft<T>(values: &mut [T])
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Fr, Output = T>,
+{
+    let n = values.len();
+    let log_n = n.trailing_zeros();
+    let omega = Fr::ROOT_OF_UNITY.pow_vartime([1u64 << (Fr::S - log_n)]);
+    let omega_inv = omega.invert().unwrap();
+
+    for k in 0..n {
+        let rk = k.reverse_bits() >> (usize::BITS - log_n);
+        if k < rk {
+            values.swap(k, rk);
+        }
+    }
+    let mut half = 1;
+    while half < n {
+        let w_m = omega_inv.pow_vartime([(n / (2 * half)) as u64]);
+        for chunk in values.chunks_mut(2 * half) {
+            let mut w = Fr::ONE;
+            for j in 0..half {
+                let t = if j == 0 { chunk[j + half] } else { chunk[j + half] * w };
+                chunk[j + half] = chunk[j] - t;
+                chunk[j] = chunk[j] + t;
+                w *= w_m;
+            }
+        }
+        half *= 2;
+    }
+    let n_inv = Fr::from(n as u64).invert().unwrap();
+    for value in values.iter_mut() {
+        *value = *value * n_inv;
+    }
+}
+
+/// Where one variable appears: its coefficient in the A, B and C linear
+/// combinations of each constraint that uses it.
+#[derive(Default)]
+struct Column {
+    a: Vec<(Fr, usize)>,
+    b: Vec<(Fr, usize)>,
+    c: Vec<(Fr, usize)>,
+}
+
+/// A circuit's constraints, laid out as bellman's parameter generation sees
+/// them.
+#[derive(Default)]
+struct Assembly {
+    inputs: Vec<Column>,
+    aux: Vec<Column>,
+    num_constraints: usize,
+}
+
+impl Assembly {
+    fn synthesize<C: Circuit<Fr>>(circuit: C) -> Result<Self, SynthesisError> {
+        let mut assembly = Assembly::default();
+        assembly.alloc_input(|| "one", || Ok(Fr::ONE))?;
+        circuit.synthesize(&mut assembly)?;
+        // Like bellman, constrain every input as `input * 0 = 0`, so the IC
+        // query is fully dense
+        for i in 0..assembly.inputs.len() {
+            assembly.enforce(|| "input", |lc| lc + Variable::new_unchecked(Index::Input(i)), |lc| lc, |lc| lc);
+        }
+        Ok(assembly)
+    }
+
+    fn domain_size(&self) -> usize {
+        self.num_constraints.next_power_of_two()
+    }
+
+    /// Notes the terms of `lc` in the current constraint's row of the
+    /// chosen polynomial.
+    fn record(&mut self, lc: LinearCombination<Fr>, polynomial: fn(&mut Column) -> &mut Vec<(Fr, usize)>) {
+        for (variable, coeff) in lc.as_ref() {
+            let column = match variable.get_unchecked() {
+                Index::Input(i) => &mut self.inputs[i],
+                Index::Aux(i) => &mut self.aux[i],
+            };
+            polynomial(column).push((*coeff, self.num_constraints));
+        }
+    }
+
+    /// Every variable's column, inputs first.
+    fn columns(&self) -> impl Iterator<Item = &Column> {
+        self.inputs.iter().chain(&self.aux)
+    }
+}
+
+impl ConstraintSystem<Fr> for Assembly {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _: A, _: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<Fr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.aux.push(Column::default());
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _: A, _: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<Fr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.inputs.push(Column::default());
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len() - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<Fr>) -> LinearCombination<Fr>,
+        LB: FnOnce(LinearCombination<Fr>) -> LinearCombination<Fr>,
+        LC: FnOnce(LinearCombination<Fr>) -> LinearCombination<Fr>,
+    {
+        self.record(a(LinearCombination::zero()), |column| &mut column.a);
+        self.record(b(LinearCombination::zero()), |column| &mut column.b);
+        self.record(c(LinearCombination::zero()), |column| &mut column.c);
+        self.num_constraints += 1;
+    }
+
+    fn push_namespace<NR, N>(&mut self, _: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+/// Reads `count` compressed points, checking each is in the prime-order subgroup.
+fn decode_points<C: PrimeCurveAffine>(reader: &mut Reader, count: usize) -> Result<Vec<C>, DecodeError> {
+    let len = C::Repr::default().as_ref().len();
+    reader
+        .take(count * len)?
+        .chunks(len)
+        .map(|bytes| {
+            let mut encoding = C::Repr::default();
+            encoding.as_mut().copy_from_slice(bytes);
+            Option::from(C::from_bytes(&encoding)).ok_or(DecodeError::InvalidValue)
+        })
+        .collect()
+}
+
+fn encode_points<C: PrimeCurveAffine>(points: &[C], out: &mut Vec<u8>) {
+    for point in points {
+        out.extend_from_slice(point.to_bytes().as_ref());
+    }
+}
+
+impl Encode for PowersOfTau {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.size() as u32).encode(out);
+        encode_points(&self.tau_g1, out);
+        encode_points(&self.tau_g2, out);
+        encode_points(&self.alpha_tau_g1, out);
+        encode_points(&self.beta_tau_g1, out);
+        encode_points(&[self.beta_g2], out);
+    }
+}
+
+impl Decode for PowersOfTau {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let size = u32::decode(reader)? as usize;
+        if !size.is_power_of_two() || size < 2 {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(PowersOfTau {
+            tau_g1: decode_points(reader, 2 * size - 1)?,
+            tau_g2: decode_points(reader, size)?,
+            alpha_tau_g1: decode_points(reader, size)?,
+            beta_tau_g1: decode_points(reader, size)?,
+            beta_g2: decode_points(reader, 1)?[0],
+        })
+    }
+}
@@ -0,0 +1,133 @@
+// src/protocol.rs
+
+use crate::block::Block;
+use crate::transaction::Transaction;
+use crate::encoding::{from_bytes, to_bytes, Decode, DecodeError, Encode, Reader};
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io;
+
+/// Version carried in every frame; peers speaking another version are rejected.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Upper bound on a single frame so a peer cannot make us allocate without limit.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Most blocks sent in one `Blocks` message.
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 500;
+
+/// Most encoded bytes of blocks sent in one `Blocks` message, past the first
+/// block. Leaves room below `MAX_FRAME_SIZE` for the block that crosses it.
+pub const MAX_BLOCKS_BYTES: usize = MAX_FRAME_SIZE / 2;
+
+/// Messages exchanged between peers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    /// Sent by both sides when a connection opens.
+    Hello { height: u64, best_hash: String },
+    /// Requests the blocks after the first block of `locator` (see
+    /// `Blockchain::block_locator`) on the receiver's chain.
+    GetBlocks { locator: Vec<(u64, String)> },
+    /// Consecutive blocks answering `GetBlocks`, one page at a time: at most
+    /// `MAX_BLOCKS_PER_MESSAGE` and about `MAX_BLOCKS_BYTES`. Empty once the
+    /// requester has caught up.
+    Blocks(Vec<Block>),
+    NewTransaction(Transaction),
+    NewBlock(Block),
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Encode for Message {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::Hello { height, best_hash } => {
+                out.push(0);
+                height.encode(out);
+                best_hash.encode(out);
+            }
+            Message::GetBlocks { locator } => {
+                out.push(1);
+                locator.encode(out);
+            }
+            Message::Blocks(blocks) => {
+                out.push(2);
+                blocks.encode(out);
+            }
+            Message::NewTransaction(tx) => {
+                out.push(3);
+                tx.encode(out);
+            }
+            Message::NewBlock(block) => {
+                out.push(4);
+                block.encode(out);
+            }
+            Message::Ping(nonce) => {
+                out.push(5);
+                nonce.encode(out);
+            }
+            Message::Pong(nonce) => {
+                out.push(6);
+                nonce.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Message {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match u8::decode(reader)? {
+            0 => Ok(Message::Hello {
+                height: u64::decode(reader)?,
+                best_hash: String::decode(reader)?,
+            }),
+            1 => Ok(Message::GetBlocks { locator: Vec::decode(reader)? }),
+            2 => Ok(Message::Blocks(Vec::decode(reader)?)),
+            3 => Ok(Message::NewTransaction(Transaction::decode(reader)?)),
+            4 => Ok(Message::NewBlock(Block::decode(reader)?)),
+            5 => Ok(Message::Ping(u64::decode(reader)?)),
+            6 => Ok(Message::Pong(u64::decode(reader)?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+/// Writes one frame: a 4-byte big-endian length, a 2-byte version and the
+/// canonically encoded message.
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> io::Result<()> {
+    let payload = to_bytes(message);
+    let frame_len = payload.len() + 2;
+    if frame_len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "message exceeds maximum frame size"));
+    }
+
+    writer.write_u32(frame_len as u32).await?;
+    writer.write_u16(PROTOCOL_VERSION).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await
+}
+
+/// Reads one frame. Returns `Ok(None)` if the peer closed the connection cleanly.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Message>> {
+    let frame_len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !(2..=MAX_FRAME_SIZE).contains(&frame_len) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid frame length {}", frame_len)));
+    }
+
+    let version = reader.read_u16().await?;
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported protocol version {}", version),
+        ));
+    }
+
+    let mut payload = vec![0u8; frame_len - 2];
+    reader.read_exact(&mut payload).await?;
+    let message = from_bytes(&payload)?;
+    Ok(Some(message))
+}
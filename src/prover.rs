@@ -0,0 +1,179 @@
+// src/prover.rs
+//
+// Runs proof generation off the async runtime.
+//
+// Groth16 proving takes seconds of CPU time, so calling `Transaction::new` or
+// `create_shielded_bundle` from a tokio task would stall every other task on
+// its thread. `ProverPool` owns a fixed number of worker threads fed from a
+// bounded queue; callers submit a proving job and await its `ProofHandle`.
+
+use log::info;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of jobs that may wait for a worker before submissions are held back.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// Reason a proving job produced no result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProverError {
+    /// The queue was full and the job was not accepted.
+    QueueFull,
+    /// The job was cancelled before it finished.
+    Cancelled,
+    /// The job panicked on its worker.
+    Panicked,
+}
+
+impl fmt::Display for ProverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProverError::QueueFull => write!(f, "prover queue is full"),
+            ProverError::Cancelled => write!(f, "proving job was cancelled"),
+            ProverError::Panicked => write!(f, "proving job panicked"),
+        }
+    }
+}
+
+impl std::error::Error for ProverError {}
+
+struct Job {
+    cancelled: Arc<AtomicBool>,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+/// A bounded pool of threads that generate proofs.
+///
+/// Jobs run in submission order, at most one per worker at a time. Dropping
+/// the pool lets the workers finish the jobs already queued and then exit.
+pub struct ProverPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ProverPool {
+    /// Starts `workers` threads (at least one) behind a queue holding up to
+    /// `queue_capacity` waiting jobs.
+    pub fn new(workers: usize, queue_capacity: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>(queue_capacity.max(1));
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = workers.max(1);
+        for i in 0..workers {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("prover-{}", i))
+                .spawn(move || worker(queue))
+                .expect("failed to start prover thread");
+        }
+        info!("Started {} prover threads", workers);
+        ProverPool { jobs }
+    }
+
+    /// A pool with one worker per available CPU.
+    pub fn with_available_parallelism() -> Self {
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(workers, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Queues `job`, waiting for room in the queue if it is full.
+    pub async fn submit<T, F>(&self, job: F) -> ProofHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (job, handle) = package(job);
+        // Workers only exit once every sender is gone, so this cannot fail
+        let _ = self.jobs.send(job).await;
+        handle
+    }
+
+    /// Queues `job`, failing with `QueueFull` instead of waiting.
+    pub fn try_submit<T, F>(&self, job: F) -> Result<ProofHandle<T>, ProverError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (job, handle) = package(job);
+        self.jobs.try_send(job).map_err(|_| ProverError::QueueFull)?;
+        Ok(handle)
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.jobs.max_capacity() - self.jobs.capacity()
+    }
+}
+
+fn package<T, F>(job: F) -> (Job, ProofHandle<T>)
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (result, receiver) = oneshot::channel();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let run = Box::new(move || {
+        let output = panic::catch_unwind(AssertUnwindSafe(job)).map_err(|_| ProverError::Panicked);
+        // The handle may have been dropped in the meantime
+        let _ = result.send(output);
+    });
+    let job = Job { cancelled: Arc::clone(&cancelled), run };
+    (job, ProofHandle { result: receiver, cancelled })
+}
+
+fn worker(queue: Arc<Mutex<mpsc::Receiver<Job>>>) {
+    loop {
+        // Only the worker holding the lock waits on the queue
+        let job = queue.lock().unwrap_or_else(|e| e.into_inner()).blocking_recv();
+        let Some(job) = job else {
+            return;
+        };
+        if job.cancelled.load(Ordering::Acquire) {
+            continue;
+        }
+        (job.run)();
+    }
+}
+
+/// Result of a submitted job, resolved once a worker has run it.
+///
+/// Cancelling, or dropping the handle, keeps a job that has not started from
+/// running, but it holds its slot in the queue until a worker reaches it and
+/// skips it. A job already running cannot be interrupted, since proving has no
+/// cancellation points, so its result is discarded instead.
+pub struct ProofHandle<T> {
+    result: oneshot::Receiver<Result<T, ProverError>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T> ProofHandle<T> {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Future for ProofHandle<T> {
+    type Output = Result<T, ProverError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.is_cancelled() {
+            return Poll::Ready(Err(ProverError::Cancelled));
+        }
+        Pin::new(&mut self.result).poll(cx).map(|result| result.unwrap_or(Err(ProverError::Cancelled)))
+    }
+}
+
+impl<T> Drop for ProofHandle<T> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
@@ -0,0 +1,374 @@
+// src/shielded.rs
+//
+// Note model for the shielded pool.
+//
+// A note belongs to the holder of a spending key `sk`. The key's nullifier key
+// is `nk = hash(sk)` and its notes name the owner `hash(nk)`. A note's
+// commitment `hash(value, owner, randomness)` is appended to the note
+// commitment tree when the note is created. Spending it reveals only the
+// nullifier `hash(nk, commitment, position)`, which the nullifier set rejects
+// a second time, and a proof of knowing `sk` and that the commitment is in the
+// tree under a recent root (the anchor). All hashes are MiMC (see `mimc`).
+//
+// Since owners and nullifiers only need `nk`, it serves as a full viewing key
+// that can follow the key's notes without spending them (see `viewing`).
+
+use crate::block::Block;
+use crate::blockchain::{TransactionError, ValidationError};
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::mimc;
+use crate::transaction::Transaction;
+use crate::viewing::{FullViewingKey, PaymentAddress};
+use blstrs::Scalar as Fr;
+use ff::Field;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// Depth of the note commitment tree, which holds up to 2^16 notes.
+pub const TREE_DEPTH: usize = 16;
+
+/// Number of most recent blocks whose note roots a spend may use as its anchor.
+pub const MAX_ANCHOR_AGE: usize = 100;
+
+/// Canonical little-endian encoding of a field element.
+pub fn field_to_bytes(element: &Fr) -> Vec<u8> {
+    element.to_bytes_le().to_vec()
+}
+
+/// Hashes `domain` and `data` into a field element. Clearing the top three bits
+/// keeps the hash below 2^253, and so below the field modulus.
+pub(crate) fn hash_to_field(domain: &[u8], data: &[u8]) -> Fr {
+    let mut bytes: [u8; 32] = Sha256::new().chain_update(domain).chain_update(data).finalize().into();
+    bytes[31] &= 0x1f;
+    Fr::from_bytes_le(&bytes).unwrap()
+}
+
+/// Parses a canonical 32-byte field element.
+pub fn field_from_bytes(bytes: &[u8]) -> Option<Fr> {
+    let bytes = <[u8; 32]>::try_from(bytes).ok()?;
+    Option::from(Fr::from_bytes_le(&bytes))
+}
+
+/// Key authorizing spends of the notes sent to its address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpendingKey(pub Fr);
+
+impl SpendingKey {
+    pub fn random() -> Self {
+        SpendingKey(Fr::random(&mut OsRng))
+    }
+
+    /// Spending key determined by `secret`, such as a wallet's signing key.
+    pub fn derive(secret: &[u8]) -> Self {
+        SpendingKey(hash_to_field(b"privacy_blockchain/spending key", secret))
+    }
+
+    /// Key that finds the notes sent to this key and tells when they are
+    /// spent, but cannot spend them.
+    pub fn full_viewing_key(&self) -> FullViewingKey {
+        FullViewingKey::new(mimc::hash(&[self.0]))
+    }
+
+    /// Shielded address that notes are sent to.
+    pub fn address(&self) -> PaymentAddress {
+        self.full_viewing_key().address()
+    }
+
+    /// Nullifier revealed when spending the note with `commitment` at `position`.
+    pub fn nullifier(&self, commitment: Fr, position: u64) -> Fr {
+        self.full_viewing_key().nullifier(commitment, position)
+    }
+}
+
+/// A shielded note: `value` sent to the shielded address `recipient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub value: u64,
+    /// The commitment only covers its owner; its transmission key is what
+    /// the note is encrypted to.
+    pub recipient: PaymentAddress,
+    /// Hides the value and owner inside the commitment.
+    pub randomness: Fr,
+}
+
+impl Note {
+    /// A note for `value` to `recipient` under fresh randomness.
+    pub fn new(value: u64, recipient: PaymentAddress) -> Self {
+        Note { value, recipient, randomness: Fr::random(&mut OsRng) }
+    }
+
+    /// Commitment appended to the note commitment tree.
+    pub fn commitment(&self) -> Fr {
+        mimc::hash(&[Fr::from(self.value), self.recipient.owner, self.randomness])
+    }
+}
+
+/// Roots of empty subtrees of each height, starting with an empty leaf.
+fn empty_roots() -> &'static [Fr] {
+    static ROOTS: OnceLock<Vec<Fr>> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let mut roots = vec![Fr::ZERO];
+        for height in 0..TREE_DEPTH {
+            roots.push(mimc::hash(&[roots[height], roots[height]]));
+        }
+        roots
+    })
+}
+
+/// Authentication path from a leaf to the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerklePath {
+    pub position: u64,
+    /// Sibling at each height, starting next to the leaf.
+    pub siblings: Vec<Fr>,
+}
+
+impl MerklePath {
+    /// Root reached by hashing `leaf` up along this path.
+    pub fn root(&self, leaf: Fr) -> Fr {
+        self.siblings.iter().enumerate().fold(leaf, |node, (height, sibling)| {
+            if (self.position >> height) & 1 == 0 {
+                mimc::hash(&[node, *sibling])
+            } else {
+                mimc::hash(&[*sibling, node])
+            }
+        })
+    }
+}
+
+/// Append-only Merkle tree of note commitments.
+///
+/// The root of every complete subtree is kept by height, so appending,
+/// truncating and building an authentication path each cost about
+/// `TREE_DEPTH` hashes regardless of the number of notes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteCommitmentTree {
+    /// Roots of the complete subtrees at each height, from left to right.
+    /// Height 0 holds the leaves.
+    nodes: Vec<Vec<Fr>>,
+    root: Fr,
+}
+
+impl Default for NoteCommitmentTree {
+    fn default() -> Self {
+        NoteCommitmentTree { nodes: vec![vec![]; TREE_DEPTH + 1], root: empty_roots()[TREE_DEPTH] }
+    }
+}
+
+impl NoteCommitmentTree {
+    pub fn root(&self) -> Fr {
+        self.root
+    }
+
+    pub fn len(&self) -> u64 {
+        self.nodes[0].len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes[0].is_empty()
+    }
+
+    /// Appends a commitment, returning its position, or `None` if the tree is full.
+    pub fn append(&mut self, commitment: Fr) -> Option<u64> {
+        let position = self.len();
+        if position >> TREE_DEPTH != 0 {
+            return None;
+        }
+        self.nodes[0].push(commitment);
+        // Each completed pair completes a subtree one level up
+        for height in 0..TREE_DEPTH {
+            let level = &self.nodes[height];
+            if !level.len().is_multiple_of(2) {
+                break;
+            }
+            let parent = mimc::hash(&[level[level.len() - 2], level[level.len() - 1]]);
+            self.nodes[height + 1].push(parent);
+        }
+        self.update_root();
+        Some(position)
+    }
+
+    /// Position of the first leaf equal to `commitment`.
+    pub fn position_of(&self, commitment: Fr) -> Option<u64> {
+        self.nodes[0].iter().position(|leaf| *leaf == commitment).map(|p| p as u64)
+    }
+
+    /// Authentication path for the leaf at `position` under the current root.
+    pub fn path(&self, position: u64) -> Option<MerklePath> {
+        if position >= self.len() {
+            return None;
+        }
+        let partial = self.partial_nodes();
+        let siblings = (0..TREE_DEPTH)
+            .map(|height| {
+                let index = (position >> height) as usize ^ 1;
+                let complete = &self.nodes[height];
+                match index.cmp(&complete.len()) {
+                    Ordering::Less => complete[index],
+                    Ordering::Equal => partial[height],
+                    Ordering::Greater => empty_roots()[height],
+                }
+            })
+            .collect();
+        Some(MerklePath { position, siblings })
+    }
+
+    /// Drops every leaf from `len` onwards.
+    pub fn truncate(&mut self, len: u64) {
+        if len >= self.len() {
+            return;
+        }
+        for (height, level) in self.nodes.iter_mut().enumerate() {
+            level.truncate((len >> height) as usize);
+        }
+        self.update_root();
+    }
+
+    /// The node at each height just right of the complete subtrees, covering
+    /// the last leaves and empty ones.
+    fn partial_nodes(&self) -> Vec<Fr> {
+        let empty = empty_roots();
+        let mut partial = vec![empty[0]];
+        for height in 0..TREE_DEPTH {
+            let complete = &self.nodes[height];
+            let node = if !complete.len().is_multiple_of(2) {
+                mimc::hash(&[complete[complete.len() - 1], partial[height]])
+            } else {
+                mimc::hash(&[partial[height], empty[height]])
+            };
+            partial.push(node);
+        }
+        partial
+    }
+
+    fn update_root(&mut self) {
+        self.root = match self.nodes[TREE_DEPTH].first() {
+            Some(full) => *full,
+            None => self.partial_nodes()[TREE_DEPTH],
+        };
+    }
+}
+
+/// Note commitment tree and spent nullifiers at some point in the chain.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShieldedState {
+    pub tree: NoteCommitmentTree,
+    nullifiers: HashSet<Vec<u8>>,
+}
+
+impl ShieldedState {
+    pub fn is_spent(&self, nullifier: &[u8]) -> bool {
+        self.nullifiers.contains(nullifier)
+    }
+
+    /// Hex-encoded root of the note commitment tree, as recorded in block headers.
+    pub fn note_root(&self) -> String {
+        hex::encode(field_to_bytes(&self.tree.root()))
+    }
+
+    /// Records a transaction's nullifiers and note commitments, failing without
+    /// side effects on a double spend.
+    pub(crate) fn apply(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        let Some(bundle) = &tx.shielded else {
+            return Ok(());
+        };
+        let mut seen = HashSet::new();
+        if bundle
+            .nullifiers
+            .iter()
+            .any(|nullifier| self.is_spent(nullifier) || !seen.insert(nullifier))
+        {
+            return Err(TransactionError::DoubleSpend);
+        }
+        let commitments = bundle
+            .commitments
+            .iter()
+            .map(|commitment| field_from_bytes(commitment))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(TransactionError::InvalidProof)?;
+        if self.tree.len() + commitments.len() as u64 > 1 << TREE_DEPTH {
+            return Err(TransactionError::NoteTreeFull);
+        }
+
+        self.nullifiers.extend(bundle.nullifiers.iter().cloned());
+        for commitment in commitments {
+            self.tree.append(commitment);
+        }
+        Ok(())
+    }
+
+    /// Undoes `apply` for the most recently applied transaction.
+    pub(crate) fn revert(&mut self, tx: &Transaction) {
+        let Some(bundle) = &tx.shielded else {
+            return;
+        };
+        for nullifier in &bundle.nullifiers {
+            self.nullifiers.remove(nullifier);
+        }
+        self.tree.truncate(self.tree.len() - bundle.commitments.len() as u64);
+    }
+
+    /// Applies every transaction of a block, naming the first one that fails.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), ValidationError> {
+        for (i, tx) in block.transactions.iter().enumerate() {
+            self.apply(tx)
+                .map_err(|e| ValidationError::new(block.header.index, e.in_block(i)))?;
+        }
+        Ok(())
+    }
+
+    /// Disconnects a block. It must be the last block applied to this state.
+    pub fn revert_block(&mut self, block: &Block) {
+        let mut created = 0;
+        for bundle in block.transactions.iter().filter_map(|tx| tx.shielded.as_ref()) {
+            for nullifier in &bundle.nullifiers {
+                self.nullifiers.remove(nullifier);
+            }
+            created += bundle.commitments.len() as u64;
+        }
+        self.tree.truncate(self.tree.len() - created);
+    }
+}
+
+impl Encode for NoteCommitmentTree {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let leaves: Vec<Vec<u8>> = self.nodes[0].iter().map(field_to_bytes).collect();
+        leaves.encode(out);
+    }
+}
+
+impl Decode for NoteCommitmentTree {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let leaves: Vec<Vec<u8>> = Vec::decode(reader)?;
+        let mut tree = NoteCommitmentTree::default();
+        for leaf in leaves {
+            let leaf = field_from_bytes(&leaf).ok_or(DecodeError::InvalidValue)?;
+            tree.append(leaf).ok_or(DecodeError::InvalidValue)?;
+        }
+        Ok(tree)
+    }
+}
+
+impl Encode for ShieldedState {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.tree.encode(out);
+        let mut nullifiers: Vec<&Vec<u8>> = self.nullifiers.iter().collect();
+        nullifiers.sort();
+        (nullifiers.len() as u32).encode(out);
+        for nullifier in nullifiers {
+            nullifier.encode(out);
+        }
+    }
+}
+
+impl Decode for ShieldedState {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(ShieldedState {
+            tree: NoteCommitmentTree::decode(reader)?,
+            nullifiers: Vec::<Vec<u8>>::decode(reader)?.into_iter().collect(),
+        })
+    }
+}
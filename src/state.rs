@@ -0,0 +1,179 @@
+// src/state.rs
+
+use crate::block::Block;
+use crate::blockchain::{TransactionError, ValidationError};
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::merkle::merkle_root;
+use crate::transaction::Transaction;
+use std::collections::BTreeMap;
+
+/// Balance and next expected nonce of one address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64,
+}
+
+impl Account {
+    fn is_empty(&self) -> bool {
+        self.balance == 0 && self.nonce == 0
+    }
+}
+
+/// Account balances and nonces at some point in the chain.
+///
+/// Only non-empty accounts are stored, so applying and then reverting a block
+/// restores exactly the previous map and therefore the previous state root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountState {
+    accounts: BTreeMap<String, Account>,
+}
+
+impl AccountState {
+    pub fn account(&self, address: &str) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.account(address).balance
+    }
+
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.account(address).nonce
+    }
+
+    /// Number of non-empty accounts.
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    fn set(&mut self, address: &str, account: Account) {
+        if account.is_empty() {
+            self.accounts.remove(address);
+        } else {
+            self.accounts.insert(address.to_string(), account);
+        }
+    }
+
+    /// Overrides one account, e.g. to project a sender's pending spends.
+    pub(crate) fn with_account(address: &str, account: Account) -> Self {
+        let mut state = AccountState::default();
+        state.set(address, account);
+        state
+    }
+
+    /// Applies a transaction, failing without side effects if it is out of
+    /// nonce order or overspends the sender.
+    pub(crate) fn apply(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        if !tx.is_reward() {
+            let sender = self.account(&tx.sender);
+            if tx.uses_nonce() && tx.nonce < sender.nonce {
+                return Err(TransactionError::NonceReused { expected: sender.nonce, found: tx.nonce });
+            }
+            if tx.uses_nonce() && tx.nonce > sender.nonce {
+                return Err(TransactionError::NonceGap { expected: sender.nonce, found: tx.nonce });
+            }
+            let required = tx.total_cost().unwrap_or(u64::MAX);
+            if sender.balance < required {
+                return Err(TransactionError::InsufficientFunds { available: sender.balance, required });
+            }
+            let nonce = sender.nonce + tx.uses_nonce() as u64;
+            self.set(&tx.sender, Account { balance: sender.balance - required, nonce });
+        }
+        let mut recipient = self.account(&tx.recipient);
+        recipient.balance += tx.amount;
+        self.set(&tx.recipient, recipient);
+        Ok(())
+    }
+
+    /// Undoes `apply` for the most recently applied transaction.
+    fn revert(&mut self, tx: &Transaction) {
+        let mut recipient = self.account(&tx.recipient);
+        recipient.balance = recipient
+            .balance
+            .checked_sub(tx.amount)
+            .expect("reverted transaction was applied");
+        self.set(&tx.recipient, recipient);
+        if !tx.is_reward() {
+            let mut sender = self.account(&tx.sender);
+            sender.balance += tx.total_cost().expect("reverted transaction was applied");
+            sender.nonce -= tx.uses_nonce() as u64;
+            self.set(&tx.sender, sender);
+        }
+    }
+
+    /// Applies every transaction of a block, naming the first one that fails.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), ValidationError> {
+        for (i, tx) in block.transactions.iter().enumerate() {
+            self.apply(tx)
+                .map_err(|e| ValidationError::new(block.header.index, e.in_block(i)))?;
+        }
+        Ok(())
+    }
+
+    /// Disconnects a block. It must be the last block applied to this state.
+    pub fn revert_block(&mut self, block: &Block) {
+        for tx in block.transactions.iter().rev() {
+            self.revert(tx);
+        }
+    }
+
+    /// Merkle root over the accounts in address order, committed to by each
+    /// block header so peers can compare state without replaying the chain.
+    pub fn state_root(&self) -> String {
+        let leaves: Vec<String> = self
+            .accounts
+            .iter()
+            .map(|(address, account)| {
+                let mut leaf = vec![];
+                address.encode(&mut leaf);
+                account.encode(&mut leaf);
+                hex::encode(leaf)
+            })
+            .collect();
+        merkle_root(&leaves)
+    }
+}
+
+impl Encode for Account {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.balance.encode(out);
+        self.nonce.encode(out);
+    }
+}
+
+impl Decode for Account {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Account {
+            balance: u64::decode(reader)?,
+            nonce: u64::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for AccountState {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.accounts.len() as u32).encode(out);
+        for (address, account) in &self.accounts {
+            address.encode(out);
+            account.encode(out);
+        }
+    }
+}
+
+impl Decode for AccountState {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let len = u32::decode(reader)?;
+        let mut state = AccountState::default();
+        for _ in 0..len {
+            let address = String::decode(reader)?;
+            let account = Account::decode(reader)?;
+            state.set(&address, account);
+        }
+        Ok(state)
+    }
+}
@@ -0,0 +1,347 @@
+// src/storage.rs
+//
+// Append-only block storage.
+//
+// Blocks are appended to segment files (`blk00000.dat`, `blk00001.dat`, ...) as
+// `[u32 length][4-byte checksum][encoded block]` records. A separate `index.dat`
+// holds one fixed-size record per height pointing at the block's segment and
+// offset, together with its hash, so blocks can be looked up by height or hash
+// without scanning the segments.
+//
+// Writes are ordered so that a crash never leaves the index pointing at missing
+// data: the block record is synced before its index record is appended. On open,
+// torn index records and segment bytes past the last indexed block are cut off.
+
+use crate::block::Block;
+use crate::encoding::{from_bytes, to_bytes, Decode, DecodeError, Encode, Reader};
+use crate::mempool::Mempool;
+use crate::shielded::ShieldedState;
+use crate::state::AccountState;
+use log::warn;
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Segments roll over once they would grow past this size.
+pub const MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const INDEX_FILE: &str = "index.dat";
+const MEMPOOL_FILE: &str = "mempool.dat";
+const STATE_FILE: &str = "state.dat";
+const RECORD_HEADER_SIZE: u64 = 8;
+const INDEX_RECORD_SIZE: u64 = 52;
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(data);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// Replaces `path` with `data` so that readers see either the old or the new contents.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// Saved account and shielded state together with the tip they were computed for.
+struct StateSnapshot {
+    tip_hash: String,
+    accounts: AccountState,
+    shielded: ShieldedState,
+}
+
+impl Encode for StateSnapshot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.tip_hash.encode(out);
+        self.accounts.encode(out);
+        self.shielded.encode(out);
+    }
+}
+
+impl Decode for StateSnapshot {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(StateSnapshot {
+            tip_hash: String::decode(reader)?,
+            accounts: AccountState::decode(reader)?,
+            shielded: ShieldedState::decode(reader)?,
+        })
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Location of one block within the segment files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexEntry {
+    segment: u32,
+    offset: u64,
+    len: u32,
+    hash: [u8; 32],
+}
+
+impl IndexEntry {
+    fn to_bytes(&self) -> [u8; INDEX_RECORD_SIZE as usize] {
+        let mut record = [0u8; INDEX_RECORD_SIZE as usize];
+        record[0..4].copy_from_slice(&self.segment.to_le_bytes());
+        record[4..12].copy_from_slice(&self.offset.to_le_bytes());
+        record[12..16].copy_from_slice(&self.len.to_le_bytes());
+        record[16..48].copy_from_slice(&self.hash);
+        let sum = checksum(&record[..48]);
+        record[48..52].copy_from_slice(&sum);
+        record
+    }
+
+    fn from_bytes(record: &[u8]) -> Option<Self> {
+        if checksum(&record[..48]) != record[48..52] {
+            return None;
+        }
+        Some(IndexEntry {
+            segment: u32::from_le_bytes(record[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(record[4..12].try_into().unwrap()),
+            len: u32::from_le_bytes(record[12..16].try_into().unwrap()),
+            hash: record[16..48].try_into().unwrap(),
+        })
+    }
+
+    /// Offset just past this block's record in its segment.
+    fn end(&self) -> u64 {
+        self.offset + RECORD_HEADER_SIZE + self.len as u64
+    }
+}
+
+/// Append-only store of the best chain, indexed by height and by hash.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    index: Vec<IndexEntry>,
+    by_hash: HashMap<[u8; 32], u64>,
+}
+
+impl BlockStore {
+    /// Opens (or creates) a store in `dir`, repairing any torn writes left by a crash.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut store = BlockStore {
+            dir,
+            index: Vec::new(),
+            by_hash: HashMap::new(),
+        };
+        store.load_index()?;
+        store.trim_segments()?;
+        Ok(store)
+    }
+
+    fn segment_path(&self, segment: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", segment))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(INDEX_FILE)
+    }
+
+    /// Reads index records up to the first torn or corrupt one, which is cut off
+    /// together with everything after it.
+    fn load_index(&mut self) -> io::Result<()> {
+        let mut data = vec![];
+        if let Ok(mut file) = File::open(self.index_path()) {
+            file.read_to_end(&mut data)?;
+        }
+
+        for record in data.chunks(INDEX_RECORD_SIZE as usize) {
+            let Some(entry) = (record.len() == INDEX_RECORD_SIZE as usize)
+                .then(|| IndexEntry::from_bytes(record))
+                .flatten()
+            else {
+                break;
+            };
+            let segment_len = fs::metadata(self.segment_path(entry.segment)).map(|m| m.len()).unwrap_or(0);
+            if entry.end() > segment_len {
+                break;
+            }
+            self.by_hash.insert(entry.hash, self.index.len() as u64);
+            self.index.push(entry);
+        }
+
+        let valid_len = self.index.len() as u64 * INDEX_RECORD_SIZE;
+        if valid_len != data.len() as u64 {
+            warn!("Discarding {} bytes of damaged block index", data.len() as u64 - valid_len);
+            let file = OpenOptions::new().write(true).create(true).truncate(false).open(self.index_path())?;
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Removes segment data past the last indexed block: partial records from an
+    /// interrupted append, or blocks disconnected by a reorganization.
+    fn trim_segments(&mut self) -> io::Result<()> {
+        let (last_segment, end) = self
+            .index
+            .last()
+            .map_or((0, 0), |entry| (entry.segment, entry.end()));
+
+        let path = self.segment_path(last_segment);
+        if path.exists() {
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() > end {
+                file.set_len(end)?;
+                file.sync_all()?;
+            }
+        }
+        let mut segment = last_segment + 1;
+        while self.segment_path(segment).exists() {
+            fs::remove_file(self.segment_path(segment))?;
+            segment += 1;
+        }
+        Ok(())
+    }
+
+    /// Number of stored blocks.
+    pub fn len(&self) -> u64 {
+        self.index.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Hex-encoded hash of the last stored block.
+    pub fn tip_hash(&self) -> Option<String> {
+        self.index.last().map(|entry| hex::encode(entry.hash))
+    }
+
+    /// Hex-encoded hash of the block at `height`, read from the index.
+    pub fn hash_at(&self, height: u64) -> Option<String> {
+        self.index.get(height as usize).map(|entry| hex::encode(entry.hash))
+    }
+
+    /// Height of the block with the given hex-encoded hash.
+    pub fn height_of(&self, hash: &str) -> Option<u64> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(hash, &mut bytes).ok()?;
+        self.by_hash.get(&bytes).copied()
+    }
+
+    /// Appends the block at height `len()`. The block is durable once this returns.
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        if block.header.index != self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected block {}, got block {}", self.len(), block.header.index),
+            ));
+        }
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(&block.hash, &mut hash).map_err(|_| invalid_data("block hash is not 32 bytes of hex"))?;
+
+        let payload = to_bytes(block);
+        let record_len = RECORD_HEADER_SIZE + payload.len() as u64;
+        let (mut segment, mut offset) = self
+            .index
+            .last()
+            .map_or((0, 0), |entry| (entry.segment, entry.end()));
+        if offset > 0 && offset + record_len > MAX_SEGMENT_SIZE {
+            segment += 1;
+            offset = 0;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(self.segment_path(segment))?;
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+        file.write_all(&record)?;
+        file.sync_data()?;
+
+        let entry = IndexEntry { segment, offset, len: payload.len() as u32, hash };
+        let mut index = OpenOptions::new().create(true).append(true).open(self.index_path())?;
+        index.write_all(&entry.to_bytes())?;
+        index.sync_data()?;
+
+        self.by_hash.insert(hash, self.len());
+        self.index.push(entry);
+        Ok(())
+    }
+
+    /// Reads the block at `height`, verifying its checksum.
+    pub fn read_block(&self, height: u64) -> io::Result<Block> {
+        let entry = self
+            .index
+            .get(height as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no block at height {}", height)))?;
+
+        let mut file = File::open(self.segment_path(entry.segment))?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut record = vec![0u8; (RECORD_HEADER_SIZE + entry.len as u64) as usize];
+        file.read_exact(&mut record)?;
+
+        let payload = &record[RECORD_HEADER_SIZE as usize..];
+        if record[0..4] != entry.len.to_le_bytes() || record[4..8] != checksum(payload) {
+            return Err(invalid_data(format!("block {} is corrupt", height)));
+        }
+        Ok(from_bytes(payload)?)
+    }
+
+    /// Reads the block with the given hex-encoded hash, if stored.
+    pub fn block_by_hash(&self, hash: &str) -> io::Result<Option<Block>> {
+        self.height_of(hash).map(|height| self.read_block(height)).transpose()
+    }
+
+    /// Drops every block from `height` upwards, e.g. when a reorganization
+    /// disconnects them.
+    pub fn truncate(&mut self, height: u64) -> io::Result<()> {
+        if height >= self.len() {
+            return Ok(());
+        }
+        let file = OpenOptions::new().write(true).open(self.index_path())?;
+        file.set_len(height * INDEX_RECORD_SIZE)?;
+        file.sync_all()?;
+
+        for entry in self.index.drain(height as usize..) {
+            self.by_hash.remove(&entry.hash);
+        }
+        self.trim_segments()
+    }
+
+    /// Replaces the saved mempool snapshot atomically.
+    pub fn save_mempool(&self, mempool: &Mempool) -> io::Result<()> {
+        write_atomic(&self.dir.join(MEMPOOL_FILE), &to_bytes(mempool))
+    }
+
+    /// Loads the saved mempool snapshot, if there is one.
+    pub fn load_mempool(&self) -> io::Result<Option<Mempool>> {
+        match fs::read(self.dir.join(MEMPOOL_FILE)) {
+            Ok(data) => Ok(Some(from_bytes(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the saved account and shielded state, recording the tip they belong to.
+    pub fn save_state(&self, tip_hash: &str, accounts: &AccountState, shielded: &ShieldedState) -> io::Result<()> {
+        let snapshot = StateSnapshot {
+            tip_hash: tip_hash.to_string(),
+            accounts: accounts.clone(),
+            shielded: shielded.clone(),
+        };
+        write_atomic(&self.dir.join(STATE_FILE), &to_bytes(&snapshot))
+    }
+
+    /// Loads the saved account and shielded state and the tip hash they were computed for.
+    pub fn load_state(&self) -> io::Result<Option<(String, AccountState, ShieldedState)>> {
+        match fs::read(self.dir.join(STATE_FILE)) {
+            Ok(data) => {
+                let snapshot: StateSnapshot = from_bytes(&data)?;
+                Ok(Some((snapshot.tip_hash, snapshot.accounts, snapshot.shielded)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
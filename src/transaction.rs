@@ -3,34 +3,121 @@
 use serde::{Serialize, Deserialize};
 use ed25519_zebra::{VerificationKey, SigningKey, Signature};
 use sha2::{Sha256, Digest};
-use crate::zk_proofs::{generate_transaction_proof, ProofData};
+use crate::address::{Address, AddressError};
+use crate::shielded::Note;
+use crate::zk_proofs::{create_shielded_bundle, verify_shielded_bundle, BatchVerifier, ShieldedBundle, SpendInput};
+use bellman::SynthesisError;
+use blstrs::Scalar as Fr;
 use std::convert::{TryFrom, TryInto};
+use crate::encoding::{to_bytes, Decode, DecodeError, Encode, Reader, ENCODING_VERSION};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub sender: String,
     pub recipient: String,
     pub amount: u64,
+    /// Paid by the sender on top of `amount` and collected by the miner.
+    pub fee: u64,
+    /// Per-sender sequence number, starting at 0, that makes each signed payload unique.
+    /// Always 0 for spends from `SHIELDED_POOL`, whose nullifiers keep them unique.
+    pub nonce: u64,
     pub signature: Option<String>,
-    pub proof: ProofData,
+    /// Notes spent and created when value moves into, within or out of the
+    /// shielded pool.
+    pub shielded: Option<ShieldedBundle>,
 }
 
 impl Transaction {
-    pub fn new(sender: String, recipient: String, amount: u64) -> Self {
-        let proof = generate_transaction_proof(amount);
-        Transaction { sender, recipient, amount, signature: None, proof }
+    /// Creates an unsigned transfer to `recipient`, which must be a valid
+    /// address. The transaction names the recipient by its public key.
+    pub fn new(sender: String, recipient: &str, amount: u64, fee: u64, nonce: u64) -> Result<Self, AddressError> {
+        let recipient = Address::parse(recipient)?.public_key_hex();
+        Ok(Self::unsigned(sender, recipient, amount, fee, nonce))
     }
 
-    const MINING_REWARD: u64 = 50;
+    /// Transfer between two accounts named as they are on chain. Its amount
+    /// is public, as both balances show it anyway; only transfers within the
+    /// shielded pool (see `new_shielded`) hide what they move.
+    fn unsigned(sender: String, recipient: String, amount: u64, fee: u64, nonce: u64) -> Self {
+        Transaction { sender, recipient, amount, fee, nonce, signature: None, shielded: None }
+    }
+
+    pub const MINING_REWARD: u64 = 50;
+
+    /// Account holding the transparent total of all shielded notes. Deposits
+    /// credit it and shielded spends debit it, so the pool can never pay out
+    /// more than was put in.
+    pub const SHIELDED_POOL: &'static str = "Shielded";
+
+    /// Moves `amount` from the sender's account into new shielded `outputs`.
+    /// The sender still signs the transaction and pays the fee in the clear.
+    /// `anchor` must be a recent note root even though nothing is spent.
+    pub fn new_deposit(
+        sender: String,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+        outputs: &[Note],
+        anchor: Fr,
+    ) -> Result<Self, SynthesisError> {
+        let binding = proof_binding(&sender, Self::SHIELDED_POOL, amount, fee, nonce);
+        let bundle = create_shielded_bundle(&[], outputs, anchor, amount, 0, binding)?;
+        let mut tx = Self::unsigned(sender, Self::SHIELDED_POOL.to_string(), amount, fee, nonce);
+        tx.shielded = Some(bundle);
+        Ok(tx)
+    }
+
+    /// Spends shielded `inputs`, proven against the note root `anchor`, into
+    /// new shielded `outputs`, paying only `fee` to the miner. Nothing but the
+    /// fee is public: the transaction moves no amount in the clear.
+    ///
+    /// The transaction is unsigned: only the proof authorizes it.
+    pub fn new_shielded(inputs: &[SpendInput], outputs: &[Note], anchor: Fr, fee: u64) -> Result<Self, SynthesisError> {
+        Self::pool_spend(inputs, outputs, anchor, Self::SHIELDED_POOL.to_string(), 0, fee)
+    }
 
-    pub fn new_reward(recipient: String) -> Self {
-        let proof = generate_transaction_proof(Self::MINING_REWARD); // Use constant
+    /// Spends shielded `inputs` like `new_shielded`, also paying `amount` out
+    /// of the pool to a transparent `recipient`. The amount is public, since
+    /// the recipient's balance shows it anyway.
+    pub fn new_withdrawal(
+        inputs: &[SpendInput],
+        outputs: &[Note],
+        anchor: Fr,
+        recipient: &Address,
+        amount: u64,
+        fee: u64,
+    ) -> Result<Self, SynthesisError> {
+        Self::pool_spend(inputs, outputs, anchor, recipient.public_key_hex(), amount, fee)
+    }
+
+    fn pool_spend(
+        inputs: &[SpendInput],
+        outputs: &[Note],
+        anchor: Fr,
+        recipient: String,
+        amount: u64,
+        fee: u64,
+    ) -> Result<Self, SynthesisError> {
+        let public_out = amount.checked_add(fee).ok_or(SynthesisError::Unsatisfiable)?;
+        let binding = proof_binding(Self::SHIELDED_POOL, &recipient, amount, fee, 0);
+        let bundle = create_shielded_bundle(inputs, outputs, anchor, 0, public_out, binding)?;
+        let mut tx = Self::unsigned(Self::SHIELDED_POOL.to_string(), recipient, amount, fee, 0);
+        tx.shielded = Some(bundle);
+        Ok(tx)
+    }
+
+    /// Creates the coinbase for the block at `height`, paying the block subsidy plus
+    /// the fees collected from the block's transactions. The height doubles as its nonce.
+    pub fn new_reward(recipient: String, height: u64, fees: u64) -> Self {
+        let amount = Self::MINING_REWARD + fees;
         Transaction {
             sender: String::from("System"),
             recipient,
-            amount: Self::MINING_REWARD,
+            amount,
+            fee: 0,
+            nonce: height,
             signature: None,
-            proof,
+            shielded: None,
         }
     }
 
@@ -42,10 +129,81 @@ impl Transaction {
         self.signature = Some(hex::encode(signature_bytes));
     }
 
+    /// Returns true for the coinbase transaction paying the miner.
+    pub fn is_reward(&self) -> bool {
+        self.sender == "System"
+    }
+
+    /// Returns true if the transaction takes the sender's next nonce. Rewards
+    /// use their height instead, and pool spends are kept from replaying by
+    /// their nullifiers.
+    pub fn uses_nonce(&self) -> bool {
+        !self.is_reward() && self.sender != Self::SHIELDED_POOL
+    }
+
+    /// Returns true if value moves into or out of the shielded pool.
+    pub fn is_shielded(&self) -> bool {
+        self.sender == Self::SHIELDED_POOL || self.recipient == Self::SHIELDED_POOL
+    }
+
+    /// Checks the shielded bundle against the value the transaction moves into
+    /// and out of the pool. Transparent transactions must not carry a bundle.
+    pub fn verify_shielded(&self) -> bool {
+        match &self.shielded {
+            None => !self.is_shielded(),
+            Some(bundle) => self.pool_values().is_some_and(|(public_in, public_out)| {
+                verify_shielded_bundle(bundle, public_in, public_out, self.binding())
+            }),
+        }
+    }
+
+    /// Public input tying the transaction's shielded proof to it: a hash of its
+    /// sender, recipient, amount, fee and nonce. Proofs copied onto a
+    /// transaction with any of these changed no longer verify.
+    pub fn binding(&self) -> Fr {
+        proof_binding(&self.sender, &self.recipient, self.amount, self.fee, self.nonce)
+    }
+
+    /// Queues the transaction's shielded proof, if any, for
+    /// `BatchVerifier::verify`, which then agrees with `verify_shielded`.
+    /// Returns false if the transaction is invalid regardless of its proof.
+    pub fn queue_proofs(&self, batch: &mut BatchVerifier) -> bool {
+        match &self.shielded {
+            None => !self.is_shielded(),
+            Some(bundle) => self
+                .pool_values()
+                .map(|(public_in, public_out)| batch.queue_shielded_bundle(bundle, public_in, public_out, self.binding()))
+                .is_some(),
+        }
+    }
+
+    /// Value a shielded bundle must prove entering and leaving the pool, or
+    /// `None` if the transaction cannot carry a bundle.
+    fn pool_values(&self) -> Option<(u64, u64)> {
+        if self.sender == Self::SHIELDED_POOL {
+            // A pool paying itself would burn note value without creating notes
+            if self.recipient == Self::SHIELDED_POOL && self.amount != 0 {
+                return None;
+            }
+            self.total_cost().map(|public_out| (0, public_out))
+        } else if self.recipient == Self::SHIELDED_POOL {
+            Some((self.amount, 0))
+        } else {
+            None
+        }
+    }
+
     pub fn is_valid(&self) -> bool {
-        if self.sender == "System" {
+        if !self.has_valid_recipient() {
+            return false;
+        }
+        if self.is_reward() {
             return true; // Reward transaction
         }
+        if self.sender == Self::SHIELDED_POOL {
+            // Authorized by the shielded proof rather than a signature
+            return self.signature.is_none() && self.shielded.is_some() && self.nonce == 0;
+        }
 
         if let Some(sig_hex) = &self.signature {
             let signature_bytes = match hex::decode(sig_hex) {
@@ -85,11 +243,88 @@ impl Transaction {
         }
     }
 
+    /// Returns true if the recipient is an account key in lowercase hex, as
+    /// `Address::public_key_hex` writes it, or the shielded pool for a
+    /// transaction that creates notes there. Nothing can be sent to the
+    /// reward sender, and a reward cannot go into the pool.
+    fn has_valid_recipient(&self) -> bool {
+        if self.recipient == Self::SHIELDED_POOL {
+            return !self.is_reward() && self.shielded.is_some();
+        }
+        Address::from_public_key_hex(&self.recipient).is_ok_and(|address| address.public_key_hex() == self.recipient)
+    }
+
+    /// Amount plus fee, i.e. everything debited from the sender.
+    pub fn total_cost(&self) -> Option<u64> {
+        self.amount.checked_add(self.fee)
+    }
+
+    /// Encoded size in bytes, used to rank transactions by fee rate.
+    pub fn size(&self) -> usize {
+        to_bytes(self).len()
+    }
+
+    /// Identifier covering every field, including the signature.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(to_bytes(self));
+        hex::encode(hasher.finalize())
+    }
+
+    /// Hash of the signed fields, i.e. everything except the signature. The
+    /// shielded bundle is signed so its notes cannot be swapped.
     fn calculate_hash(&self) -> String {
-        let data = format!("{}{}{}", self.sender, self.recipient, self.amount);
+        let mut data = vec![ENCODING_VERSION];
+        self.sender.encode(&mut data);
+        self.recipient.encode(&mut data);
+        self.amount.encode(&mut data);
+        self.fee.encode(&mut data);
+        self.nonce.encode(&mut data);
+        self.shielded.encode(&mut data);
         let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
+        hasher.update(&data);
         let result = hasher.finalize();
         hex::encode(result)
     }
 }
+
+/// Hashes the fields a transaction's shielded proof is bound to into a field element.
+fn proof_binding(sender: &str, recipient: &str, amount: u64, fee: u64, nonce: u64) -> Fr {
+    let mut data = vec![ENCODING_VERSION];
+    data.extend_from_slice(b"proof binding");
+    sender.encode(&mut data);
+    recipient.encode(&mut data);
+    amount.encode(&mut data);
+    fee.encode(&mut data);
+    nonce.encode(&mut data);
+    let mut bytes: [u8; 32] = Sha256::digest(&data).into();
+    // Clearing the top three bits keeps the hash below 2^253, and so below the field modulus
+    bytes[31] &= 0x1f;
+    Fr::from_bytes_le(&bytes).unwrap()
+}
+
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.sender.encode(out);
+        self.recipient.encode(out);
+        self.amount.encode(out);
+        self.fee.encode(out);
+        self.nonce.encode(out);
+        self.signature.encode(out);
+        self.shielded.encode(out);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Transaction {
+            sender: String::decode(reader)?,
+            recipient: String::decode(reader)?,
+            amount: u64::decode(reader)?,
+            fee: u64::decode(reader)?,
+            nonce: u64::decode(reader)?,
+            signature: Option::decode(reader)?,
+            shielded: Option::decode(reader)?,
+        })
+    }
+}
@@ -0,0 +1,598 @@
+// src/viewing.rs
+//
+// Viewing keys, for disclosing a wallet's shielded notes without giving up
+// the authority to spend them.
+//
+// A spending key `sk` has two viewing keys:
+//
+// - the full viewing key is its nullifier key `nk = hash(sk)`. It yields the
+//   owner `hash(nk)` named by the key's notes and the nullifier of each of
+//   them, so it sees both the notes received and when they are spent. It
+//   cannot spend them, as the transfer circuit takes `sk` itself as witness.
+// - the incoming viewing key is that owner together with a Jubjub scalar
+//   `ivk` hashed from `nk`. It only sees the notes received.
+//
+// A payment address is the owner and the transmission key `ivk * G`. Each
+// note a shielded bundle creates is encrypted to its recipient's transmission
+// key: the sender picks an ephemeral scalar `esk` and publishes
+// `epk = esk * G`, and both sides hash the shared point
+// `esk * (ivk * G) = ivk * epk` into an AES-256-CTR key and an HMAC-SHA256
+// key. The plaintext is the note's value and randomness, and a decrypted
+// note is only accepted if it opens the commitment it was published with.
+//
+// Viewing keys are exchanged as Bech32m strings with the prefix "pbivk" or
+// "pbfvk", and a view-only wallet file holds one such string. Payment
+// addresses are Bech32m strings with the prefix "pbzs" over the owner and
+// the transmission key.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::history::{rewind_entries, Direction, HistoryEntry, ScannedBlocks};
+use crate::mimc;
+use crate::shielded::{field_from_bytes, field_to_bytes, Note};
+use crate::transaction::Transaction;
+use crate::zk_proofs::ShieldedBundle;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use aes::Aes256;
+use bech32::primitives::decode::{CheckedHrpstring, CheckedHrpstringError};
+use bech32::{Bech32m, Hrp};
+use blstrs::Scalar as Fr;
+use ff::Field;
+use group::{Group, GroupEncoding};
+use hmac::{Hmac, Mac};
+use jubjub::SubgroupPoint;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256, Sha512};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Prefix of encoded incoming viewing keys.
+pub const INCOMING_VIEWING_KEY_HRP: &str = "pbivk";
+
+/// Prefix of encoded full viewing keys.
+pub const FULL_VIEWING_KEY_HRP: &str = "pbfvk";
+
+/// Prefix of encoded payment addresses.
+pub const PAYMENT_ADDRESS_HRP: &str = "pbzs";
+
+/// Value and randomness of a note.
+const NOTE_PLAINTEXT_LEN: usize = 8 + 32;
+
+/// Length of an encrypted note: the ephemeral key, the encrypted plaintext
+/// and the MAC.
+pub const ENCRYPTED_NOTE_LEN: usize = 32 + NOTE_PLAINTEXT_LEN + 32;
+
+const INCOMING_KEY_DOMAIN: &[u8] = b"privacy_blockchain/incoming viewing key";
+const NOTE_KEY_DOMAIN: &[u8] = b"privacy_blockchain/note encryption";
+
+/// Reason a string is not a viewing key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViewingKeyError {
+    /// Not a Bech32m string at all.
+    Malformed,
+    /// A Bech32m string whose checksum does not match, usually a typo.
+    InvalidChecksum,
+    /// A Bech32m string with this prefix, which is not a viewing key's.
+    UnknownPrefix(String),
+    /// The data has the wrong length or does not encode a key.
+    InvalidKey,
+}
+
+impl fmt::Display for ViewingKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViewingKeyError::Malformed => write!(f, "not a viewing key"),
+            ViewingKeyError::InvalidChecksum => write!(f, "viewing key checksum does not match; check it for typos"),
+            ViewingKeyError::UnknownPrefix(hrp) => write!(
+                f,
+                "'{}' is not a viewing key prefix; expected '{}' or '{}'",
+                hrp, INCOMING_VIEWING_KEY_HRP, FULL_VIEWING_KEY_HRP
+            ),
+            ViewingKeyError::InvalidKey => write!(f, "viewing key data is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for ViewingKeyError {}
+
+/// Reason a string is not a payment address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentAddressError {
+    /// Not a Bech32m string at all.
+    Malformed,
+    /// A Bech32m string whose checksum does not match, usually a typo.
+    InvalidChecksum,
+    /// A Bech32m string with this prefix, which is not a payment address's.
+    UnknownPrefix(String),
+    /// The data has the wrong length, or does not hold an owner and a
+    /// transmission key.
+    InvalidAddress,
+}
+
+impl fmt::Display for PaymentAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentAddressError::Malformed => write!(f, "not a payment address"),
+            PaymentAddressError::InvalidChecksum => {
+                write!(f, "payment address checksum does not match; check it for typos")
+            }
+            PaymentAddressError::UnknownPrefix(hrp) => {
+                write!(f, "'{}' is not a payment address prefix; expected '{}'", hrp, PAYMENT_ADDRESS_HRP)
+            }
+            PaymentAddressError::InvalidAddress => write!(f, "payment address data is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for PaymentAddressError {}
+
+/// Shielded address that notes are sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentAddress {
+    /// Owner named in the commitments of the notes sent to the address.
+    pub owner: Fr,
+    /// Point the notes are encrypted to.
+    pub transmission_key: SubgroupPoint,
+}
+
+impl PaymentAddress {
+    /// Parses and validates a payment address, in lower or upper case.
+    pub fn parse(s: &str) -> Result<Self, PaymentAddressError> {
+        let checked = CheckedHrpstring::new::<Bech32m>(s).map_err(|e| match e {
+            CheckedHrpstringError::Checksum(_) => PaymentAddressError::InvalidChecksum,
+            _ => PaymentAddressError::Malformed,
+        })?;
+        checked.validate_segwit_padding().map_err(|_| PaymentAddressError::Malformed)?;
+        let hrp = checked.hrp().to_lowercase();
+        if hrp != PAYMENT_ADDRESS_HRP {
+            return Err(PaymentAddressError::UnknownPrefix(hrp));
+        }
+        let data: Vec<u8> = checked.byte_iter().collect();
+        if data.len() != 64 {
+            return Err(PaymentAddressError::InvalidAddress);
+        }
+        let owner = field_from_bytes(&data[..32]).ok_or(PaymentAddressError::InvalidAddress)?;
+        let transmission_key = Option::<SubgroupPoint>::from(SubgroupPoint::from_bytes(&data[32..].try_into().unwrap()))
+            .ok_or(PaymentAddressError::InvalidAddress)?;
+        // Notes encrypted to the identity could be read by anyone
+        if bool::from(transmission_key.is_identity()) {
+            return Err(PaymentAddressError::InvalidAddress);
+        }
+        Ok(PaymentAddress { owner, transmission_key })
+    }
+}
+
+impl fmt::Display for PaymentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut data = field_to_bytes(&self.owner);
+        data.extend_from_slice(&self.transmission_key.to_bytes());
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, Hrp::parse_unchecked(PAYMENT_ADDRESS_HRP), &data)
+            .map_err(|_| fmt::Error)
+    }
+}
+
+impl FromStr for PaymentAddress {
+    type Err = PaymentAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// Key that finds a spending key's notes and tells when they are spent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullViewingKey {
+    nullifier_key: Fr,
+}
+
+impl FullViewingKey {
+    pub fn new(nullifier_key: Fr) -> Self {
+        FullViewingKey { nullifier_key }
+    }
+
+    pub fn nullifier_key(&self) -> Fr {
+        self.nullifier_key
+    }
+
+    /// Owner named by the notes of the key.
+    pub fn owner(&self) -> Fr {
+        mimc::hash(&[self.nullifier_key])
+    }
+
+    /// The part of this key that only decrypts received notes.
+    pub fn incoming(&self) -> IncomingViewingKey {
+        let digest = Sha512::new()
+            .chain_update(INCOMING_KEY_DOMAIN)
+            .chain_update(field_to_bytes(&self.nullifier_key))
+            .finalize();
+        let mut wide = Zeroizing::new([0u8; 64]);
+        wide.copy_from_slice(&digest);
+        IncomingViewingKey { owner: self.owner(), key: jubjub::Fr::from_bytes_wide(&wide) }
+    }
+
+    pub fn address(&self) -> PaymentAddress {
+        self.incoming().address()
+    }
+
+    /// Nullifier revealed when spending the note with `commitment` at `position`.
+    pub fn nullifier(&self, commitment: Fr, position: u64) -> Fr {
+        mimc::hash(&[self.nullifier_key, commitment, Fr::from(position)])
+    }
+}
+
+/// Key that decrypts the notes sent to one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncomingViewingKey {
+    owner: Fr,
+    key: jubjub::Fr,
+}
+
+impl IncomingViewingKey {
+    pub fn address(&self) -> PaymentAddress {
+        PaymentAddress { owner: self.owner, transmission_key: SubgroupPoint::generator() * self.key }
+    }
+
+    /// The note `encrypted` holds, if it was sent to this key and opens
+    /// `commitment`.
+    pub fn decrypt_note(&self, encrypted: &[u8], commitment: Fr) -> Option<Note> {
+        if encrypted.len() != ENCRYPTED_NOTE_LEN {
+            return None;
+        }
+        let (ephemeral_key, rest) = encrypted.split_at(32);
+        let (ciphertext, mac) = rest.split_at(NOTE_PLAINTEXT_LEN);
+        let ephemeral_key: [u8; 32] = ephemeral_key.try_into().unwrap();
+        let point = Option::<SubgroupPoint>::from(SubgroupPoint::from_bytes(&ephemeral_key))?;
+        let (encryption_key, mac_key) = note_keys(&(point * self.key), &ephemeral_key);
+        if note_mac(&mac_key, &ephemeral_key, ciphertext).verify_slice(mac).is_err() {
+            return None;
+        }
+        let mut plaintext = Zeroizing::new(ciphertext.to_vec());
+        note_cipher(&encryption_key).apply_keystream(&mut plaintext);
+        let note = Note {
+            value: u64::from_le_bytes(plaintext[..8].try_into().unwrap()),
+            recipient: self.address(),
+            randomness: field_from_bytes(&plaintext[8..])?,
+        };
+        (note.commitment() == commitment).then_some(note)
+    }
+}
+
+/// Encrypts `note` to the transmission key of its recipient, under a fresh
+/// ephemeral key.
+pub fn encrypt_note(note: &Note) -> Vec<u8> {
+    let ephemeral_secret = jubjub::Fr::random(&mut OsRng);
+    let ephemeral_key = (SubgroupPoint::generator() * ephemeral_secret).to_bytes();
+    let (encryption_key, mac_key) = note_keys(&(note.recipient.transmission_key * ephemeral_secret), &ephemeral_key);
+    let mut ciphertext = note.value.to_le_bytes().to_vec();
+    ciphertext.extend_from_slice(&field_to_bytes(&note.randomness));
+    note_cipher(&encryption_key).apply_keystream(&mut ciphertext);
+    let mac = note_mac(&mac_key, &ephemeral_key, &ciphertext).finalize().into_bytes();
+
+    let mut out = ephemeral_key.to_vec();
+    out.extend_from_slice(&ciphertext);
+    out.extend_from_slice(&mac);
+    out
+}
+
+/// Encryption and MAC keys of a note from the shared point and the
+/// ephemeral key.
+fn note_keys(shared: &SubgroupPoint, ephemeral_key: &[u8; 32]) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let digest = Sha512::new()
+        .chain_update(NOTE_KEY_DOMAIN)
+        .chain_update(shared.to_bytes())
+        .chain_update(ephemeral_key)
+        .finalize();
+    let mut encryption_key = Zeroizing::new([0u8; 32]);
+    let mut mac_key = Zeroizing::new([0u8; 32]);
+    encryption_key.copy_from_slice(&digest[..32]);
+    mac_key.copy_from_slice(&digest[32..]);
+    (encryption_key, mac_key)
+}
+
+/// AES-256-CTR under a note's encryption key. Every note gets its own key,
+/// so the keystream may always start at counter zero.
+fn note_cipher(encryption_key: &[u8; 32]) -> ctr::Ctr128BE<Aes256> {
+    ctr::Ctr128BE::<Aes256>::new(encryption_key.into(), &[0; 16].into())
+}
+
+/// HMAC-SHA256 over the ephemeral key and ciphertext of a note, ready to
+/// finalize or verify.
+fn note_mac(mac_key: &[u8; 32], ephemeral_key: &[u8; 32], ciphertext: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC takes keys of any length");
+    mac.update(ephemeral_key);
+    mac.update(ciphertext);
+    mac
+}
+
+/// A viewing key of either kind, as handed to an auditor or imported into
+/// a view-only wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewingKey {
+    Incoming(IncomingViewingKey),
+    Full(FullViewingKey),
+}
+
+impl ViewingKey {
+    /// Parses and validates a viewing key, in lower or upper case.
+    pub fn parse(s: &str) -> Result<Self, ViewingKeyError> {
+        let checked = CheckedHrpstring::new::<Bech32m>(s).map_err(|e| match e {
+            CheckedHrpstringError::Checksum(_) => ViewingKeyError::InvalidChecksum,
+            _ => ViewingKeyError::Malformed,
+        })?;
+        checked.validate_segwit_padding().map_err(|_| ViewingKeyError::Malformed)?;
+        let data: Vec<u8> = checked.byte_iter().collect();
+        let hrp = checked.hrp().to_lowercase();
+        match (hrp.as_str(), data.len()) {
+            (INCOMING_VIEWING_KEY_HRP, 64) => {
+                let owner = field_from_bytes(&data[..32]).ok_or(ViewingKeyError::InvalidKey)?;
+                let key = Option::from(jubjub::Fr::from_bytes(&data[32..].try_into().unwrap()))
+                    .ok_or(ViewingKeyError::InvalidKey)?;
+                Ok(ViewingKey::Incoming(IncomingViewingKey { owner, key }))
+            }
+            (FULL_VIEWING_KEY_HRP, 32) => field_from_bytes(&data)
+                .map(|nullifier_key| ViewingKey::Full(FullViewingKey::new(nullifier_key)))
+                .ok_or(ViewingKeyError::InvalidKey),
+            (INCOMING_VIEWING_KEY_HRP | FULL_VIEWING_KEY_HRP, _) => Err(ViewingKeyError::InvalidKey),
+            _ => Err(ViewingKeyError::UnknownPrefix(hrp)),
+        }
+    }
+
+    pub fn incoming(&self) -> IncomingViewingKey {
+        match self {
+            ViewingKey::Incoming(key) => *key,
+            ViewingKey::Full(key) => key.incoming(),
+        }
+    }
+
+    /// The full viewing key, if this is one.
+    pub fn full(&self) -> Option<&FullViewingKey> {
+        match self {
+            ViewingKey::Incoming(_) => None,
+            ViewingKey::Full(key) => Some(key),
+        }
+    }
+
+    pub fn address(&self) -> PaymentAddress {
+        self.incoming().address()
+    }
+
+    /// Writes the key to `filename` as a view-only wallet, readable only by
+    /// the owner. The file is only replaced once the new one is complete.
+    pub fn save_to_file(&self, filename: impl AsRef<Path>) -> io::Result<()> {
+        let filename = filename.as_ref();
+        let mut tmp = filename.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        writeln!(file, "{}", self)?;
+        file.sync_all()?;
+        fs::rename(tmp, filename)
+    }
+
+    /// Reads a view-only wallet written by `save_to_file`.
+    pub fn load_from_file(filename: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = Zeroizing::new(fs::read_to_string(filename)?);
+        Ok(Self::parse(contents.trim())?)
+    }
+
+    /// Returns true if `filename` holds a view-only wallet.
+    pub fn is_viewing_file(filename: impl AsRef<Path>) -> io::Result<bool> {
+        Ok(Self::is_viewing_key(&fs::read(filename)?))
+    }
+
+    /// Returns true if `data` starts like an encoded viewing key.
+    pub fn is_viewing_key(data: &[u8]) -> bool {
+        [INCOMING_VIEWING_KEY_HRP, FULL_VIEWING_KEY_HRP]
+            .iter()
+            .any(|hrp| data.starts_with(hrp.as_bytes()) && data.get(hrp.len()) == Some(&b'1'))
+    }
+}
+
+impl fmt::Display for ViewingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (hrp, data) = match self {
+            ViewingKey::Incoming(key) => {
+                let mut data = Zeroizing::new(field_to_bytes(&key.owner));
+                data.extend_from_slice(&key.key.to_bytes());
+                (INCOMING_VIEWING_KEY_HRP, data)
+            }
+            ViewingKey::Full(key) => (FULL_VIEWING_KEY_HRP, Zeroizing::new(field_to_bytes(&key.nullifier_key))),
+        };
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, Hrp::parse_unchecked(hrp), &data).map_err(|_| fmt::Error)
+    }
+}
+
+impl FromStr for ViewingKey {
+    type Err = ViewingKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// A note received by a viewing key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedNote {
+    pub note: Note,
+    /// Position of its commitment in the note commitment tree.
+    pub position: u64,
+    /// Height of the block creating it.
+    pub height: u64,
+    /// Height of the block spending it. Always `None` under an incoming
+    /// viewing key, which cannot tell.
+    pub spent_at: Option<u64>,
+}
+
+/// Shielded notes and history of a viewing key, rebuilt from the chain.
+///
+/// Scanning is incremental and rescans from the fork after a reorganization,
+/// as for `WalletHistory`. A transaction is in the history if it creates a
+/// note the key can decrypt or, under a full viewing key, spends one.
+#[derive(Debug, Clone)]
+pub struct ShieldedHistory {
+    key: ViewingKey,
+    notes: Vec<ReceivedNote>,
+    entries: Vec<HistoryEntry>,
+    /// Scanned blocks, with the size of the note commitment tree after each.
+    scanned: ScannedBlocks<u64>,
+}
+
+impl ShieldedHistory {
+    /// History of the notes of `key`. Nothing is scanned yet.
+    pub fn new(key: ViewingKey) -> Self {
+        ShieldedHistory { key, notes: vec![], entries: vec![], scanned: ScannedBlocks::default() }
+    }
+
+    pub fn key(&self) -> &ViewingKey {
+        &self.key
+    }
+
+    /// Brings the history up to date with the chain of `blockchain`, reading
+    /// only the blocks after the last scanned one still on the chain.
+    pub fn sync(&mut self, blockchain: &Blockchain) -> io::Result<()> {
+        let fork = self.scanned.fork_point(blockchain);
+        let blocks = blockchain.blocks_from(fork as u64)?;
+        self.scan_from(fork, &blocks);
+        Ok(())
+    }
+
+    /// Brings the history up to date with `chain`, which starts at genesis.
+    pub fn scan(&mut self, chain: &[Block]) {
+        let fork = self.scanned.fork_point_in(chain);
+        self.scan_from(fork, &chain[fork..]);
+    }
+
+    /// Scans `blocks`, which start at height `fork`, after dropping
+    /// everything scanned from there on.
+    fn scan_from(&mut self, fork: usize, blocks: &[Block]) {
+        self.scanned.rewind(fork);
+        rewind_entries(&mut self.entries, fork);
+        let fork_height = fork as u64;
+        self.notes.retain(|received| received.height < fork_height);
+        for received in &mut self.notes {
+            if received.spent_at.is_some_and(|height| height >= fork_height) {
+                received.spent_at = None;
+            }
+        }
+
+        let mut tree_len = self.scanned.last().copied().unwrap_or(0);
+        for block in blocks {
+            let height = block.header.index;
+            for tx in &block.transactions {
+                let Some(bundle) = &tx.shielded else {
+                    continue;
+                };
+                let (received, spent) = self.find(bundle);
+                if let Some(mut entry) = self.entry(tx, &received, &spent) {
+                    entry.height = Some(height);
+                    entry.timestamp = Some(block.header.timestamp);
+                    self.entries.push(entry);
+                }
+                for i in spent {
+                    self.notes[i].spent_at = Some(height);
+                }
+                for (output, note) in received {
+                    self.notes.push(ReceivedNote { note, position: tree_len + output as u64, height, spent_at: None });
+                }
+                tree_len += bundle.commitments.len() as u64;
+            }
+            self.scanned.push(block, tree_len);
+        }
+    }
+
+    /// Notes received in the scanned blocks, oldest first.
+    pub fn notes(&self) -> &[ReceivedNote] {
+        &self.notes
+    }
+
+    /// Confirmed transactions, oldest first.
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// Transactions waiting in the mempool of `blockchain`.
+    pub fn pending(&self, blockchain: &Blockchain) -> Vec<HistoryEntry> {
+        blockchain
+            .pending_transactions
+            .iter()
+            .filter_map(|tx| {
+                let (received, spent) = self.find(tx.shielded.as_ref()?);
+                self.entry(tx, &received, &spent)
+            })
+            .collect()
+    }
+
+    /// Height of the last scanned block.
+    pub fn tip_height(&self) -> Option<u64> {
+        self.scanned.tip_height()
+    }
+
+    /// Sum of `net_change` over the confirmed transactions. Under a full
+    /// viewing key this is the value of the unspent notes; an incoming
+    /// viewing key sees no spends, so it is the value of every note received.
+    pub fn balance(&self) -> i128 {
+        self.entries.iter().map(HistoryEntry::net_change).sum()
+    }
+
+    /// Notes of `bundle` sent to the key, by output index, and the indices in
+    /// `notes` of the unspent notes it spends.
+    fn find(&self, bundle: &ShieldedBundle) -> (Vec<(usize, Note)>, Vec<usize>) {
+        let incoming = self.key.incoming();
+        let received = bundle
+            .commitments
+            .iter()
+            .zip(&bundle.encrypted_notes)
+            .enumerate()
+            .filter_map(|(output, (commitment, encrypted))| {
+                Some((output, incoming.decrypt_note(encrypted, field_from_bytes(commitment)?)?))
+            })
+            .collect();
+        let spent = match self.key.full() {
+            Some(full) => (0..self.notes.len())
+                .filter(|&i| {
+                    let received = &self.notes[i];
+                    let nullifier = full.nullifier(received.note.commitment(), received.position);
+                    received.spent_at.is_none() && bundle.nullifiers.contains(&field_to_bytes(&nullifier))
+                })
+                .collect(),
+            None => vec![],
+        };
+        (received, spent)
+    }
+
+    /// Entry for `tx`, which creates the `received` notes and spends the
+    /// `spent` ones, not yet placed in a block.
+    fn entry(&self, tx: &Transaction, received: &[(usize, Note)], spent: &[usize]) -> Option<HistoryEntry> {
+        if received.is_empty() && spent.is_empty() {
+            return None;
+        }
+        let received_value = received.iter().fold(0u64, |sum, (_, note)| sum.saturating_add(note.value));
+        let spent_value = spent.iter().fold(0u64, |sum, &i| sum.saturating_add(self.notes[i].note.value));
+        let (direction, counterparty, amount, fee) = if spent_value == 0 {
+            (Direction::Incoming, &tx.sender, received_value, 0)
+        } else {
+            // The spender pays the fee, and whatever else left the notes went to the recipient
+            let fee = tx.fee.min(spent_value.saturating_sub(received_value));
+            let amount = spent_value.saturating_sub(received_value) - fee;
+            let direction = if amount == 0 { Direction::SelfTransfer } else { Direction::Outgoing };
+            (direction, &tx.recipient, amount, fee)
+        };
+        Some(HistoryEntry {
+            height: None,
+            timestamp: None,
+            tx_id: tx.id(),
+            direction,
+            counterparty: counterparty.clone(),
+            amount,
+            fee,
+        })
+    }
+}
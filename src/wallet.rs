@@ -2,55 +2,200 @@
 
 use ed25519_zebra::{SigningKey, VerificationKey, Signature};
 use rand::rngs::OsRng;
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::path::Path;
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
+use zeroize::Zeroizing;
+use crate::address::Address;
+use crate::hd::{mnemonic_to_seed, ExtendedKey, InvalidAccount, Mnemonic, HARDENED};
+use crate::keystore::{KdfParams, Keystore, KeystoreError, SecretKind};
+use crate::shielded::SpendingKey;
+use crate::viewing::ViewingKey;
 
 pub struct Wallet {
     pub signing_key: SigningKey,
+    /// Seed of an HD wallet, from which `signing_key` was derived.
+    seed: Option<Zeroizing<[u8; 64]>>,
+    account: u32,
 }
 
 impl Wallet {
+    /// A wallet with a single random key, which has no mnemonic backup.
     pub fn new() -> Self {
-        let mut rng = OsRng;
-        let signing_key = SigningKey::new(&mut rng);
-        Wallet { signing_key }
+        let rng = OsRng;
+        let signing_key = SigningKey::new(rng);
+        Wallet::from_signing_key(signing_key)
     }
 
     pub fn from_signing_key(signing_key: SigningKey) -> Self {
-        Wallet { signing_key }
+        Wallet { signing_key, seed: None, account: 0 }
+    }
+
+    /// HD wallet signing with the key of `account`, which must be below
+    /// `HARDENED`.
+    pub fn from_seed(seed: Zeroizing<[u8; 64]>, account: u32) -> Result<Self, InvalidAccount> {
+        if account >= HARDENED {
+            return Err(InvalidAccount(account));
+        }
+        let signing_key = ExtendedKey::master(&seed[..]).account_key(account);
+        Ok(Wallet { signing_key, seed: Some(seed), account })
+    }
+
+    /// Rebuilds the HD wallet backed up as `mnemonic`. `passphrase` is the
+    /// optional mnemonic passphrase, not the one encrypting the wallet file.
+    pub fn from_mnemonic(mnemonic: &Mnemonic, passphrase: &str, account: u32) -> Result<Self, InvalidAccount> {
+        Self::from_seed(mnemonic_to_seed(mnemonic, passphrase), account)
+    }
+
+    /// The same HD wallet signing with the key of `account`, or `None` if
+    /// this wallet has no seed.
+    pub fn with_account(&self, account: u32) -> Result<Option<Self>, InvalidAccount> {
+        self.seed.clone().map(|seed| Self::from_seed(seed, account)).transpose()
+    }
+
+    pub fn is_hd(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    /// Account whose key signs, always 0 for a single-key wallet.
+    pub fn account(&self) -> u32 {
+        self.account
     }
 
     pub fn sign(&self, message: &[u8]) -> Signature {
         self.signing_key.sign(message)
     }
 
-    pub fn save_to_file(&self, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let verification_key = VerificationKey::from(&self.signing_key);
-        let public_key_hex = hex::encode(verification_key.as_ref());
-        let secret_key_hex = hex::encode(self.signing_key.as_ref());
-        let data = format!("{}\n{}", public_key_hex, secret_key_hex);
-        fs::write(filename, data)?;
+    /// Encrypts the secret key under `passphrase` and writes it to
+    /// `filename`. The file is only replaced once the new one is complete.
+    pub fn save_to_file(&self, filename: impl AsRef<Path>, passphrase: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_with_params(filename, passphrase, KdfParams::default())
+    }
+
+    /// Like `save_to_file`, with explicit key derivation costs.
+    pub fn save_with_params(
+        &self,
+        filename: impl AsRef<Path>,
+        passphrase: &str,
+        params: KdfParams,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let filename = filename.as_ref();
+        let mut tmp = filename.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&self.to_keystore(passphrase, params).to_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, filename)?;
         Ok(())
     }
-    
-    pub fn load_from_file(filename: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let contents = fs::read_to_string(filename)?;
+
+    /// Reads and decrypts a wallet file written by `save_to_file`.
+    pub fn load_from_file(filename: impl AsRef<Path>, passphrase: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let data = fs::read(filename)?;
+        if !Keystore::is_keystore(&data) {
+            return Err("wallet file is not encrypted; unlock it to encrypt it".into());
+        }
+        Ok(Self::from_keystore(&Keystore::from_bytes(&data)?, passphrase)?)
+    }
+
+    pub fn to_keystore(&self, passphrase: &str, params: KdfParams) -> Keystore {
+        let verification_key = VerificationKey::from(&self.signing_key);
+        let public_key: [u8; 32] = verification_key.as_ref().try_into().unwrap();
+        match &self.seed {
+            Some(seed) => {
+                let kind = SecretKind::Seed { account: self.account };
+                Keystore::seal(&seed[..], kind, public_key, passphrase, params)
+            }
+            None => Keystore::seal(self.signing_key.as_ref(), SecretKind::SigningKey, public_key, passphrase, params),
+        }
+    }
+
+    pub fn from_keystore(keystore: &Keystore, passphrase: &str) -> Result<Self, KeystoreError> {
+        let secret = keystore.open(passphrase)?;
+        match keystore.kind() {
+            SecretKind::SigningKey => {
+                let secret_key: [u8; 32] = secret[..].try_into().map_err(|_| KeystoreError::Malformed)?;
+                Ok(Wallet::from_signing_key(SigningKey::from(secret_key)))
+            }
+            SecretKind::Seed { account } => {
+                let mut seed = Zeroizing::new([0u8; 64]);
+                seed.copy_from_slice(&secret);
+                Wallet::from_seed(seed, account).map_err(|_| KeystoreError::Malformed)
+            }
+        }
+    }
+
+    /// Returns true if `filename` holds a wallet in the old plaintext format,
+    /// with the public and secret keys as hex on separate lines.
+    pub fn is_plaintext_file(filename: impl AsRef<Path>) -> io::Result<bool> {
+        let data = fs::read(filename)?;
+        Ok(!Keystore::is_keystore(&data) && !ViewingKey::is_viewing_key(&data))
+    }
+
+    /// Encrypts a plaintext wallet file in place under `passphrase`.
+    pub fn migrate_plaintext_file(filename: impl AsRef<Path>, passphrase: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let wallet = Self::load_plaintext(filename.as_ref())?;
+        wallet.save_to_file(filename, passphrase)?;
+        Ok(wallet)
+    }
+
+    fn load_plaintext(filename: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = Zeroizing::new(fs::read_to_string(filename)?);
         let lines: Vec<&str> = contents.lines().collect();
         if lines.len() < 2 {
             return Err("Invalid wallet file format".into());
         }
-        let secret_key_bytes = hex::decode(lines[1])?;
+        let secret_key_bytes = Zeroizing::new(hex::decode(lines[1])?);
         let signing_key = SigningKey::try_from(secret_key_bytes.as_slice())?;
         Ok(Wallet::from_signing_key(signing_key))
-    }    
+    }
+
+    /// Public key of the wallet in `filename`, which needs no passphrase.
+    pub fn public_key_from_file(filename: impl AsRef<Path>) -> Result<String, Box<dyn std::error::Error>> {
+        let filename = filename.as_ref();
+        let data = fs::read(filename)?;
+        if Keystore::is_keystore(&data) {
+            Ok(hex::encode(Keystore::from_bytes(&data)?.public_key()))
+        } else if ViewingKey::is_viewing_key(&data) {
+            Err("wallet is view-only and has no account".into())
+        } else {
+            Ok(Self::load_plaintext(filename)?.public_key_hex())
+        }
+    }
+
+    /// Address to give out for payments to this wallet.
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&VerificationKey::from(&self.signing_key))
+    }
+
+    /// Key of the wallet's shielded notes, derived from the signing key, so
+    /// each account of an HD wallet has its own.
+    pub fn spending_key(&self) -> SpendingKey {
+        SpendingKey::derive(self.signing_key.as_ref())
+    }
+
+    /// Key that shows the wallet's shielded notes without spending them.
+    pub fn viewing_key(&self) -> ViewingKey {
+        ViewingKey::Full(self.spending_key().full_viewing_key())
+    }
 
     pub fn public_key_hex(&self) -> String {
         let verification_key = VerificationKey::from(&self.signing_key);
         hex::encode(verification_key.as_ref())
     }
 
-    pub fn exists(filename: &str) -> bool {
-        Path::new(filename).exists()
+    pub fn exists(filename: impl AsRef<Path>) -> bool {
+        filename.as_ref().exists()
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,229 @@
+// src/wallet_dir.rs
+//
+// A directory of named wallets.
+//
+// Each wallet is a keystore file `<name>.dat`. The wallet that commands use
+// when none is named is recorded in a `current` file next to them. Renames
+// and imports go through a hard link, which fails instead of replacing a
+// wallet that already has the target name. Where no link can be made, e.g.
+// when importing from another file system, the file is copied to a new file
+// instead, which fails the same way.
+
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// Extension of the wallet files in the directory.
+pub const WALLET_EXTENSION: &str = "dat";
+
+/// Longest accepted wallet name.
+pub const MAX_NAME_LEN: usize = 64;
+
+const CURRENT_FILE: &str = "current";
+
+/// Reason a wallet directory operation failed.
+#[derive(Debug)]
+pub enum WalletDirError {
+    /// The name is empty, too long or has characters other than ASCII
+    /// letters, digits, `-` and `_`.
+    InvalidName(String),
+    NotFound(String),
+    AlreadyExists(String),
+    /// No wallet was named and none is selected.
+    NoneSelected,
+    Io(io::Error),
+}
+
+impl fmt::Display for WalletDirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletDirError::InvalidName(name) => write!(
+                f,
+                "invalid wallet name '{}': use up to {} letters, digits, '-' or '_'",
+                name, MAX_NAME_LEN
+            ),
+            WalletDirError::NotFound(name) => write!(f, "no wallet named '{}'", name),
+            WalletDirError::AlreadyExists(name) => write!(f, "a wallet named '{}' already exists", name),
+            WalletDirError::NoneSelected => write!(f, "no wallet selected; create one or pick one with 'wallet use'"),
+            WalletDirError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalletDirError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WalletDirError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WalletDirError {
+    fn from(e: io::Error) -> Self {
+        WalletDirError::Io(e)
+    }
+}
+
+/// Returns true if `name` can name a wallet.
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Named wallets stored in one directory.
+pub struct WalletDir {
+    dir: PathBuf,
+}
+
+impl WalletDir {
+    /// Opens `dir`, creating it readable only by the owner if it is missing.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir)?;
+        Ok(WalletDir { dir })
+    }
+
+    /// File of the wallet `name`, whether or not it exists.
+    pub fn path(&self, name: &str) -> Result<PathBuf, WalletDirError> {
+        if !is_valid_name(name) {
+            return Err(WalletDirError::InvalidName(name.to_string()));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, WALLET_EXTENSION)))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.path(name).is_ok_and(|path| path.exists())
+    }
+
+    /// Names of the wallets in the directory, sorted.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == WALLET_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()).filter(|n| is_valid_name(n)) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The selected wallet, if one is selected and still exists.
+    pub fn current(&self) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.dir.join(CURRENT_FILE)) {
+            Ok(name) => Ok(Some(name.trim().to_string()).filter(|name| self.contains(name))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Selects `name` for commands that do not name a wallet.
+    pub fn set_current(&self, name: &str) -> Result<(), WalletDirError> {
+        if !self.contains(name) {
+            return Err(WalletDirError::NotFound(name.to_string()));
+        }
+        let path = self.dir.join(CURRENT_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{}", name)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// The wallet named by `selector`, or the selected one if there is no
+    /// selector. The wallet must exist.
+    pub fn resolve(&self, selector: Option<&str>) -> Result<String, WalletDirError> {
+        match selector {
+            Some(name) => {
+                self.path(name)?;
+                if !self.contains(name) {
+                    return Err(WalletDirError::NotFound(name.to_string()));
+                }
+                Ok(name.to_string())
+            }
+            None => self.current()?.ok_or(WalletDirError::NoneSelected),
+        }
+    }
+
+    /// Renames wallet `from` to `to`, keeping it selected if it was.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), WalletDirError> {
+        let source = self.path(from)?;
+        if !source.exists() {
+            return Err(WalletDirError::NotFound(from.to_string()));
+        }
+        let was_current = self.current()?.as_deref() == Some(from);
+        self.link(&source, to)?;
+        fs::remove_file(source)?;
+        if was_current {
+            self.set_current(to)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes wallet `name`, clearing the selection if it was selected.
+    pub fn delete(&self, name: &str) -> Result<(), WalletDirError> {
+        let path = self.path(name)?;
+        let was_current = self.current()?.as_deref() == Some(name);
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(WalletDirError::NotFound(name.to_string())),
+            Err(e) => return Err(e.into()),
+        }
+        if was_current {
+            fs::remove_file(self.dir.join(CURRENT_FILE))?;
+        }
+        Ok(())
+    }
+
+    /// Moves the wallet file `file` into the directory as `name`.
+    pub fn import(&self, file: impl AsRef<Path>, name: &str) -> Result<(), WalletDirError> {
+        self.link(file.as_ref(), name)?;
+        fs::remove_file(file)?;
+        Ok(())
+    }
+
+    /// Links `source` as wallet `name`, failing if `name` exists. Falls back
+    /// to a copy where the file system cannot link `source` there.
+    fn link(&self, source: &Path, name: &str) -> Result<(), WalletDirError> {
+        let target = self.path(name)?;
+        let linked = match fs::hard_link(source, &target) {
+            Err(e) if matches!(e.kind(), io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported) => {
+                copy_new(source, &target)
+            }
+            result => result,
+        };
+        match linked {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(WalletDirError::AlreadyExists(name.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Copies `source` to `target`, which must not exist yet, and syncs the copy
+/// so that the source can be removed afterwards. A wallet imported from an
+/// older version may still hold its key in plaintext, so the contents are
+/// wiped from memory once written.
+fn copy_new(source: &Path, target: &Path) -> io::Result<()> {
+    let data = Zeroizing::new(fs::read(source)?);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(target)?;
+    if let Err(e) = file.write_all(&data).and_then(|()| file.sync_all()) {
+        let _ = fs::remove_file(target);
+        return Err(e);
+    }
+    Ok(())
+}
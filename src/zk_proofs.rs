@@ -1,98 +1,548 @@
 // src/zk_proofs.rs
 
-use bellman::{Circuit, ConstraintSystem, SynthesisError};
+use bellman::{Circuit, ConstraintSystem, LinearCombination, SynthesisError};
+use bellman::gadgets::boolean::{AllocatedBit, Boolean};
+use bellman::gadgets::num::AllocatedNum;
 use blstrs::{Bls12, Scalar as Fr};
+use bellman::groth16::batch;
 use bellman::groth16::{
-    create_random_proof, generate_random_parameters, prepare_verifying_key, verify_proof, Proof,
-    VerifyingKey,
+    create_random_proof, generate_random_parameters, prepare_verifying_key, verify_proof,
+    Parameters, PreparedVerifyingKey, Proof,
 };
-use rand_core::OsRng; // Use rand_core's OsRng
+use ff::Field;
+use rand_core::{OsRng, RngCore}; // Use rand_core's OsRng
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use sha2::{Sha256, Digest};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+use crate::ceremony::{Ceremony, CEREMONY_MAGIC};
+use crate::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::mimc::{hash_gadget, Expression};
+use crate::powers::{domain_size, PowersOfTau};
+use crate::shielded::{field_from_bytes, field_to_bytes, hash_to_field, MerklePath, Note, SpendingKey, TREE_DEPTH};
+use crate::viewing::{encrypt_note, ENCRYPTED_NOTE_LEN};
 
+/// Number of bits each note value is decomposed into, i.e. the range proven.
+pub const AMOUNT_BITS: usize = 64;
+
+/// Allocates the little-endian bits of `bytes`, each constrained to 0 or 1.
+fn alloc_bits<CS: ConstraintSystem<Fr>>(mut cs: CS, bytes: Option<&[u8]>, count: usize) -> Result<Vec<Boolean>, SynthesisError> {
+    (0..count)
+        .map(|i| {
+            let bit = bytes.map(|bytes| (bytes[i / 8] >> (i % 8)) & 1 == 1);
+            Ok(Boolean::from(AllocatedBit::alloc(cs.namespace(|| format!("bit {}", i)), bit)?))
+        })
+        .collect()
+}
+
+/// Number of notes a shielded transfer spends; unused slots hold zero-value notes.
+pub const TRANSFER_INPUTS: usize = 2;
+/// Number of notes a shielded transfer creates; unused slots hold zero-value notes.
+pub const TRANSFER_OUTPUTS: usize = 2;
+
+/// A note being spent, with the key that owns it and its path to the anchor.
+#[derive(Debug, Clone)]
+pub struct SpendInput {
+    pub note: Note,
+    pub key: SpendingKey,
+    pub path: MerklePath,
+}
+
+impl SpendInput {
+    /// Zero-value spend filling an unused input slot. Zero-value notes are
+    /// exempt from the membership check, so it needs no place in the tree.
+    fn dummy() -> Self {
+        let key = SpendingKey::random();
+        SpendInput {
+            note: Note::new(0, key.address()),
+            key,
+            path: MerklePath { position: 0, siblings: vec![Fr::ZERO; TREE_DEPTH] },
+        }
+    }
+
+    /// Nullifier this spend reveals.
+    pub fn nullifier(&self) -> Fr {
+        self.key.nullifier(self.note.commitment(), self.path.position)
+    }
+}
+
+/// Exposes the transaction binding as a public input. No constraint uses it,
+/// but bellman adds `input * 0 = 0` for every public input, so the proof
+/// still only verifies under the binding it was made for.
+fn inputize_binding<CS: ConstraintSystem<Fr>>(mut cs: CS, binding: Option<Fr>) -> Result<(), SynthesisError> {
+    alloc_field(cs.namespace(|| "value"), binding)?.inputize(cs.namespace(|| "input"))
+}
+
+fn alloc_field<CS: ConstraintSystem<Fr>>(cs: CS, value: Option<Fr>) -> Result<AllocatedNum<Fr>, SynthesisError> {
+    AllocatedNum::alloc(cs, || value.ok_or(SynthesisError::AssignmentMissing))
+}
+
+/// The number whose little-endian bits are `bits`.
+fn pack_bits<CS: ConstraintSystem<Fr>>(bits: &[Boolean]) -> Expression {
+    let mut packed = Expression::zero();
+    let mut coeff = Fr::ONE;
+    for bit in bits {
+        packed.lc = packed.lc + &bit.lc(CS::one(), coeff);
+        packed.value = packed.value.zip(bit.get_value()).map(|(sum, bit)| if bit { sum + coeff } else { sum });
+        coeff = coeff.double();
+    }
+    packed
+}
+
+/// Allocates a note's opening and recomputes its commitment. Returns the
+/// value, range-checked to 64 bits, and the commitment.
+fn commit_note<CS: ConstraintSystem<Fr>>(
+    mut cs: CS,
+    note: Option<&Note>,
+    owner: Expression,
+) -> Result<(Expression, AllocatedNum<Fr>), SynthesisError> {
+    let value_bytes = note.map(|note| note.value.to_le_bytes());
+    let value_bits = alloc_bits(cs.namespace(|| "value"), value_bytes.as_ref().map(|b| &b[..]), AMOUNT_BITS)?;
+    let value = pack_bits::<CS>(&value_bits);
+    let randomness = alloc_field(cs.namespace(|| "randomness"), note.map(|note| note.randomness))?;
+    let commitment = hash_gadget(
+        cs.namespace(|| "commitment"),
+        &[value.clone(), owner, Expression::from(&randomness)],
+    )?;
+    Ok((value, commitment))
+}
+
+/// Proves that a spend's note is owned by its spending key and sits in the
+/// tree under `anchor`, and derives its nullifier. Returns the note's value and
+/// the nullifier.
+fn spend_note<CS: ConstraintSystem<Fr>>(
+    mut cs: CS,
+    input: Option<&SpendInput>,
+    anchor: &AllocatedNum<Fr>,
+) -> Result<(Expression, AllocatedNum<Fr>), SynthesisError> {
+    let key = alloc_field(cs.namespace(|| "spending key"), input.map(|input| input.key.0))?;
+    let nullifier_key = hash_gadget(cs.namespace(|| "nullifier key"), &[Expression::from(&key)])?;
+    let owner = hash_gadget(cs.namespace(|| "owner"), &[Expression::from(&nullifier_key)])?;
+    let (value, commitment) = commit_note(
+        cs.namespace(|| "note"),
+        input.map(|input| &input.note),
+        Expression::from(&owner),
+    )?;
+
+    let position_bytes = input.map(|input| input.path.position.to_le_bytes());
+    let position_bits = alloc_bits(cs.namespace(|| "position"), position_bytes.as_ref().map(|b| &b[..]), TREE_DEPTH)?;
+    let mut node = Expression::from(&commitment);
+    for (height, bit) in position_bits.iter().enumerate() {
+        let mut cs = cs.namespace(|| format!("height {}", height));
+        let sibling = alloc_field(cs.namespace(|| "sibling"), input.map(|input| input.path.siblings[height]))?;
+
+        // With swap = bit * (sibling - node) the children are
+        // (node + swap, sibling - swap), i.e. swapped exactly when the bit is set
+        let swap_value = bit
+            .get_value()
+            .zip(sibling.get_value())
+            .zip(node.value)
+            .map(|((bit, sibling), node)| if bit { sibling - node } else { Fr::ZERO });
+        let swap = alloc_field(cs.namespace(|| "swap"), swap_value)?;
+        cs.enforce(
+            || "swap computation",
+            |_| bit.lc(CS::one(), Fr::ONE),
+            |lc| lc + sibling.get_variable() - &node.lc,
+            |lc| lc + swap.get_variable(),
+        );
+        let left = Expression {
+            lc: node.lc.clone() + swap.get_variable(),
+            value: node.value.zip(swap.get_value()).map(|(node, swap)| node + swap),
+        };
+        let right = Expression {
+            lc: LinearCombination::zero() + sibling.get_variable() - swap.get_variable(),
+            value: sibling.get_value().zip(swap.get_value()).map(|(sibling, swap)| sibling - swap),
+        };
+        node = Expression::from(&hash_gadget(cs.namespace(|| "parent"), &[left, right])?);
+    }
+    cs.enforce(
+        || "root is the anchor unless the value is zero",
+        |_| node.lc - anchor.get_variable(),
+        |_| value.lc.clone(),
+        |lc| lc,
+    );
+
+    let position = pack_bits::<CS>(&position_bits);
+    let nullifier = hash_gadget(
+        cs.namespace(|| "nullifier"),
+        &[Expression::from(&nullifier_key), Expression::from(&commitment), position],
+    )?;
+    Ok((value, nullifier))
+}
+
+/// Proves a shielded transfer without revealing which notes it spends:
+///
+/// - each spent note is owned by the prover's spending key and its commitment
+///   is in the note commitment tree under `anchor` (zero-value notes, which pad
+///   unused slots, are exempt);
+/// - each nullifier is derived from the spent note's nullifier key, commitment
+///   and position;
+/// - each output commitment opens to a note;
+/// - `sum(inputs) + public_in = sum(outputs) + public_out`.
+///
+/// Every note value is range-checked to 64 bits, so the sums cannot wrap
+/// around the field. `public_in` is value entering the pool from a
+/// transparent account; `public_out` is value leaving it, including the fee.
+///
+/// Public inputs are the anchor, the nullifier of each input, the commitment
+/// of each output, `public_in`, `public_out`, then the binding of the bundle
+/// (see `bundle_binding`).
 #[derive(Clone)]
-pub struct TransactionProof {
-    pub amount: Option<Fr>,
+pub struct TransferCircuit {
+    pub anchor: Option<Fr>,
+    pub inputs: [Option<SpendInput>; TRANSFER_INPUTS],
+    pub outputs: [Option<Note>; TRANSFER_OUTPUTS],
+    pub public_in: Option<u64>,
+    pub public_out: Option<u64>,
+    /// See `bundle_binding`.
+    pub binding: Option<Fr>,
 }
 
-impl Circuit<Fr> for TransactionProof {
+impl Circuit<Fr> for TransferCircuit {
     fn synthesize<CS: ConstraintSystem<Fr>>(
         self,
         cs: &mut CS,
     ) -> Result<(), SynthesisError> {
-        // Allocate the private input (amount)
-        let amount_value = self.amount;
-        let amount_allocated = cs.alloc(
-            || "amount",
-            || amount_value.ok_or(SynthesisError::AssignmentMissing),
-        )?;
-
-        // Enforce that amount * 1 = amount (simple constraint)
+        let anchor = alloc_field(cs.namespace(|| "anchor"), self.anchor)?;
+        anchor.inputize(cs.namespace(|| "anchor input"))?;
+
+        let mut total_in = LinearCombination::zero();
+        for (i, input) in self.inputs.iter().enumerate() {
+            let (value, nullifier) = spend_note(cs.namespace(|| format!("input {}", i)), input.as_ref(), &anchor)?;
+            nullifier.inputize(cs.namespace(|| format!("input {} nullifier", i)))?;
+            total_in = total_in + &value.lc;
+        }
+
+        let mut total_out = LinearCombination::zero();
+        for (i, note) in self.outputs.iter().enumerate() {
+            let mut cs = cs.namespace(|| format!("output {}", i));
+            let owner = alloc_field(cs.namespace(|| "owner"), note.map(|note| note.recipient.owner))?;
+            let (value, commitment) = commit_note(cs.namespace(|| "note"), note.as_ref(), Expression::from(&owner))?;
+            commitment.inputize(cs.namespace(|| "commitment input"))?;
+            total_out = total_out + &value.lc;
+        }
+
+        let public_in = AllocatedNum::alloc(cs.namespace(|| "public in"), || {
+            self.public_in.map(Fr::from).ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        public_in.inputize(cs.namespace(|| "public in input"))?;
+        let public_out = AllocatedNum::alloc(cs.namespace(|| "public out"), || {
+            self.public_out.map(Fr::from).ok_or(SynthesisError::AssignmentMissing)
+        })?;
+        public_out.inputize(cs.namespace(|| "public out input"))?;
+
         cs.enforce(
-            || "amount consistency",
-            |lc| lc + amount_allocated,
+            || "value balance",
+            |_| total_in + public_in.get_variable(),
             |lc| lc + CS::one(),
-            |lc| lc + amount_allocated,
+            |_| total_out + public_out.get_variable(),
         );
-
-        Ok(())
+        inputize_binding(cs.namespace(|| "binding"), self.binding)
     }
 }
 
+/// Shielded part of a transaction: nullifiers of the notes it spends,
+/// commitments to the notes it creates, the notes encrypted to their
+/// recipients, and a proof tying them to the anchor and to the transaction's
+/// public values. Field elements are 32 bytes, little-endian.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ProofData {
+pub struct ShieldedBundle {
+    /// Note commitment tree root the spent notes are proven against. It must
+    /// be the `note_root` of one of the last `MAX_ANCHOR_AGE` blocks.
+    pub anchor: Vec<u8>,
+    pub nullifiers: Vec<Vec<u8>>,
+    /// Commitments of the created notes, appended to the tree in order.
+    pub commitments: Vec<Vec<u8>>,
+    /// Each created note encrypted to its recipient (see `viewing`), in the
+    /// order of `commitments`.
+    pub encrypted_notes: Vec<Vec<u8>>,
     pub proof: Vec<u8>,
-    pub vk: Vec<u8>,
 }
 
-pub fn generate_transaction_proof(amount: u64) -> ProofData {
-    let mut rng = OsRng; // Initialize OsRng from rand_core
-    let amount_fr = Fr::from(amount);
+impl Encode for ShieldedBundle {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.anchor.encode(out);
+        self.nullifiers.encode(out);
+        self.commitments.encode(out);
+        self.encrypted_notes.encode(out);
+        self.proof.encode(out);
+    }
+}
 
-    // Generate parameters
-    let params = {
-        let circuit = TransactionProof { amount: None };
-        generate_random_parameters::<Bls12, _, _>(circuit, &mut rng).unwrap()
-    };
+impl Decode for ShieldedBundle {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(ShieldedBundle {
+            anchor: Vec::decode(reader)?,
+            nullifiers: Vec::decode(reader)?,
+            commitments: Vec::decode(reader)?,
+            encrypted_notes: Vec::decode(reader)?,
+            proof: Vec::decode(reader)?,
+        })
+    }
+}
 
-    // Create an instance of the circuit with the actual amount
-    let circuit = TransactionProof {
-        amount: Some(amount_fr),
-    };
+const PARAMETERS_MAGIC: &[u8; 8] = b"PBZKPRM2";
 
-    // Create a proof
-    let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+/// Groth16 parameters of the shielded transfer circuit, the only one
+/// transactions carry proofs for.
+///
+/// They come from a one-off trusted setup and every node of a chain must use
+/// the same ones: proofs are only ever checked against these verifying keys,
+/// never against a key supplied alongside the proof.
+#[derive(Clone)]
+pub struct SetupParameters {
+    pub transfer: Parameters<Bls12>,
+}
 
-    // Serialize the proof and vk using write methods
-    let mut proof_bytes = vec![];
-    proof.write(&mut proof_bytes).unwrap();
+/// The transfer circuit without witnesses, as used for parameter generation.
+fn blank_circuit() -> TransferCircuit {
+    TransferCircuit {
+        anchor: None,
+        inputs: std::array::from_fn(|_| None),
+        outputs: [None; TRANSFER_OUTPUTS],
+        public_in: None,
+        public_out: None,
+        binding: None,
+    }
+}
+
+impl SetupParameters {
+    /// Runs the setup for the transfer circuit. Whoever runs it could forge proofs
+    /// with the randomness it used, so it must be discarded afterwards.
+    pub fn generate<R: RngCore>(rng: &mut R) -> Result<Self, SynthesisError> {
+        Ok(SetupParameters { transfer: generate_random_parameters::<Bls12, _, _>(blank_circuit(), rng)? })
+    }
 
-    let mut vk_bytes = vec![];
-    params.vk.write(&mut vk_bytes).unwrap();
+    /// Starting point of the second phase of a multi-party setup (see
+    /// `ceremony`), derived from the powers of tau of the first. Its `delta`
+    /// is the group generator, so these parameters are not safe to use until
+    /// someone who discards their randomness has contributed.
+    pub(crate) fn from_powers(powers: &PowersOfTau) -> Result<Self, SynthesisError> {
+        Ok(SetupParameters { transfer: powers.parameters(blank_circuit())? })
+    }
 
-    ProofData {
-        proof: proof_bytes,
-        vk: vk_bytes,
+    /// Returns true if these are the parameters `from_powers` derives.
+    pub(crate) fn derived_from(&self, powers: &PowersOfTau) -> Result<bool, SynthesisError> {
+        powers.derived(blank_circuit(), &self.transfer)
     }
+
+    /// Size of the powers of tau that the transfer circuit needs.
+    pub(crate) fn powers_size() -> Result<usize, SynthesisError> {
+        domain_size(blank_circuit())
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(PARAMETERS_MAGIC)?;
+        self.transfer.write(&mut writer)
+    }
+
+    /// Reads parameters written by `write`. The verifying keys are checked
+    /// to be valid curve points; the proving keys are not, since a bad
+    /// proving key can only make our own proofs fail.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != PARAMETERS_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a zk-SNARK parameters file"));
+        }
+        Ok(SetupParameters { transfer: Parameters::read(&mut reader, false)? })
+    }
+
+    /// SHA-256 of the verifying key, for operators to compare out of band.
+    pub fn fingerprint(&self) -> String {
+        let mut keys = vec![];
+        self.transfer.vk.write(&mut keys).unwrap();
+        hex::encode(Sha256::digest(&keys))
+    }
+}
+
+static PARAMETERS: OnceLock<SetupParameters> = OnceLock::new();
+
+/// Pins the chain's parameters for this process. Fails if parameters are
+/// already in use.
+pub fn install_parameters(params: SetupParameters) -> io::Result<()> {
+    PARAMETERS
+        .set(params)
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "zk-SNARK parameters are already in use"))
+}
+
+/// Reads a parameters file produced by the setup, or a ceremony transcript
+/// whose contributions all verify, and pins it.
+pub fn load_parameters(path: impl AsRef<Path>) -> io::Result<&'static SetupParameters> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let params = if reader.fill_buf()?.starts_with(CEREMONY_MAGIC) {
+        Ceremony::read(reader)?
+            .into_parameters()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    } else {
+        SetupParameters::read(reader)?
+    };
+    install_parameters(params)?;
+    Ok(parameters())
+}
+
+/// The pinned parameters.
+///
+/// # Panics
+///
+/// If none were installed: proofs made or checked under any other parameters
+/// would be worthless, so there is nothing sensible to fall back to.
+pub fn parameters() -> &'static SetupParameters {
+    PARAMETERS
+        .get()
+        .expect("no zk-SNARK parameters installed; call load_parameters or install_parameters first")
 }
 
-pub fn verify_transaction_proof(proof_data: &ProofData) -> bool {
-    // Deserialize the proof and vk using read methods
-    let mut proof_cursor = Cursor::new(&proof_data.proof);
-    let proof = match Proof::<Bls12>::read(&mut proof_cursor) {
-        Ok(p) => p,
-        Err(_) => return false,
+/// Proves a transfer spending `inputs` against `anchor` and creating
+/// `outputs`, with `public_in` entering and `public_out` leaving the shielded
+/// pool, for the transaction with `binding`. Each output is encrypted to its
+/// recipient. Fails with `Unsatisfiable` if there are too many notes, the
+/// values do not balance, or a spent note is not owned by its key or not under
+/// `anchor`.
+pub fn create_shielded_bundle(
+    inputs: &[SpendInput],
+    outputs: &[Note],
+    anchor: Fr,
+    public_in: u64,
+    public_out: u64,
+    binding: Fr,
+) -> Result<ShieldedBundle, SynthesisError> {
+    fn total(mut values: impl Iterator<Item = u64>, public: u64) -> Option<u128> {
+        values.try_fold(public as u128, |sum, value| sum.checked_add(value as u128))
+    }
+    if total(inputs.iter().map(|input| input.note.value), public_in)
+        != total(outputs.iter().map(|note| note.value), public_out)
+    {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    let spendable = |input: &SpendInput| {
+        input.key.address().owner == input.note.recipient.owner && input.path.root(input.note.commitment()) == anchor
     };
+    if inputs.len() > TRANSFER_INPUTS || outputs.len() > TRANSFER_OUTPUTS || !inputs.iter().all(spendable) {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    let inputs: [SpendInput; TRANSFER_INPUTS] =
+        std::array::from_fn(|i| inputs.get(i).cloned().unwrap_or_else(SpendInput::dummy));
+    let outputs: [Note; TRANSFER_OUTPUTS] =
+        std::array::from_fn(|i| outputs.get(i).copied().unwrap_or_else(|| Note::new(0, SpendingKey::random().address())));
+    // Padding notes go to throwaway addresses, so their ciphertexts look like any other
+    let encrypted_notes: Vec<Vec<u8>> = outputs.iter().map(encrypt_note).collect();
 
-    let mut vk_cursor = Cursor::new(&proof_data.vk);
-    let vk = match VerifyingKey::<Bls12>::read(&mut vk_cursor) {
-        Ok(v) => v,
-        Err(_) => return false,
+    let params = &parameters().transfer;
+    let circuit = TransferCircuit {
+        anchor: Some(anchor),
+        inputs: inputs.clone().map(Some),
+        outputs: outputs.map(Some),
+        public_in: Some(public_in),
+        public_out: Some(public_out),
+        binding: Some(bundle_binding(binding, &encrypted_notes)),
     };
-    let pvk = prepare_verifying_key(&vk);
+    let proof = create_random_proof(circuit, params, &mut OsRng)?;
+    let mut proof_bytes = vec![];
+    proof.write(&mut proof_bytes)?;
 
-    // No public inputs in this example
-    let public_inputs = [];
+    Ok(ShieldedBundle {
+        anchor: field_to_bytes(&anchor),
+        nullifiers: inputs.iter().map(|input| field_to_bytes(&input.nullifier())).collect(),
+        commitments: outputs.iter().map(|note| field_to_bytes(&note.commitment())).collect(),
+        encrypted_notes,
+        proof: proof_bytes,
+    })
+}
 
-    verify_proof(&pvk, &proof, &public_inputs).is_ok()
+/// Public input binding a transfer proof to the transaction's `binding` and
+/// to the bundle's note ciphertexts, so neither can be replaced without a new
+/// proof.
+fn bundle_binding(binding: Fr, encrypted_notes: &[Vec<u8>]) -> Fr {
+    let mut data = binding.to_bytes_le().to_vec();
+    for note in encrypted_notes {
+        note.encode(&mut data);
+    }
+    hash_to_field(b"privacy_blockchain/bundle binding", &data)
+}
+
+/// Checks a shielded bundle against the value entering and leaving the pool
+/// and the binding of the transaction carrying it. Whether the anchor is
+/// recent and the nullifiers unspent is up to the chain.
+pub fn verify_shielded_bundle(bundle: &ShieldedBundle, public_in: u64, public_out: u64, binding: Fr) -> bool {
+    bundle_inputs(bundle, public_in, public_out, binding)
+        .is_some_and(|inputs| verify_with_key(&bundle.proof, prepared_key(), &inputs))
+}
+
+/// Public inputs of the transfer circuit for a bundle, if it has the right
+/// shape and encodes valid field elements.
+fn bundle_inputs(bundle: &ShieldedBundle, public_in: u64, public_out: u64, binding: Fr) -> Option<Vec<Fr>> {
+    if bundle.nullifiers.len() != TRANSFER_INPUTS
+        || bundle.commitments.len() != TRANSFER_OUTPUTS
+        || bundle.encrypted_notes.len() != TRANSFER_OUTPUTS
+        || bundle.encrypted_notes.iter().any(|note| note.len() != ENCRYPTED_NOTE_LEN)
+    {
+        return None;
+    }
+    let mut public_inputs = std::iter::once(&bundle.anchor)
+        .chain(&bundle.nullifiers)
+        .chain(&bundle.commitments)
+        .map(|bytes| field_from_bytes(bytes))
+        .collect::<Option<Vec<_>>>()?;
+    public_inputs.extend([Fr::from(public_in), Fr::from(public_out), bundle_binding(binding, &bundle.encrypted_notes)]);
+    Some(public_inputs)
+}
+
+fn read_proof(proof: &[u8]) -> Option<Proof<Bls12>> {
+    Proof::read(Cursor::new(proof)).ok()
+}
+
+fn verify_with_key(proof: &[u8], pvk: &PreparedVerifyingKey<Bls12>, public_inputs: &[Fr]) -> bool {
+    read_proof(proof).is_some_and(|proof| verify_proof(pvk, &proof, public_inputs).is_ok())
+}
+
+/// Prepares the pinned verifying key on first use, instead of on every proof.
+fn prepared_key() -> &'static PreparedVerifyingKey<Bls12> {
+    static KEY: OnceLock<PreparedVerifyingKey<Bls12>> = OnceLock::new();
+    KEY.get_or_init(|| prepare_verifying_key(&parameters().transfer.vk))
+}
+
+/// Checks many proofs at once against the pinned verifying key.
+///
+/// The proofs are combined with random weights into a single
+/// pairing check, which costs far less than checking them one by one but only
+/// tells whether all of them are valid. Callers that need to know which proof
+/// failed check them individually once the batch fails.
+#[derive(Default)]
+pub struct BatchVerifier {
+    transfer: batch::Verifier<Bls12>,
+    transfers: usize,
+    /// Set once a proof or its public inputs could not be decoded.
+    malformed: bool,
+}
+
+impl BatchVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of proofs queued so far.
+    pub fn len(&self) -> usize {
+        self.transfers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues a shielded bundle, as `verify_shielded_bundle` would check it.
+    pub fn queue_shielded_bundle(&mut self, bundle: &ShieldedBundle, public_in: u64, public_out: u64, binding: Fr) {
+        self.transfers += 1;
+        match (read_proof(&bundle.proof), bundle_inputs(bundle, public_in, public_out, binding)) {
+            (Some(proof), Some(inputs)) => self.transfer.queue((proof, inputs)),
+            _ => self.malformed = true,
+        }
+    }
+
+    /// Returns true if every queued proof is valid.
+    pub fn verify(self) -> bool {
+        !self.malformed && (self.transfers == 0 || self.transfer.verify(OsRng, &parameters().transfer.vk).is_ok())
+    }
 }
@@ -1,32 +1,1513 @@
 // tests/tests.rs
 
-use privacy_blockchain::blockchain::Blockchain;
+use privacy_blockchain::address::{Address, AddressError};
+use privacy_blockchain::block::{Block, BlockHeader, GENESIS_TIMESTAMP};
+use privacy_blockchain::ceremony::{Ceremony, CeremonyError};
+use privacy_blockchain::difficulty::{
+    next_bits, target_from_compact, target_to_compact, work_for_target, POW_LIMIT_BITS, RETARGET_INTERVAL,
+    TARGET_BLOCK_TIME, U256,
+};
+use privacy_blockchain::blockchain::{BlockError, Blockchain, TransactionError};
+use privacy_blockchain::encoding::{from_bytes, to_bytes, DecodeError};
+use privacy_blockchain::history::{Direction, WalletHistory, ACCOUNT_GAP_LIMIT};
+use privacy_blockchain::hd::{
+    generate_mnemonic, mnemonic_to_seed, ExtendedKey, InvalidAccount, Mnemonic, MnemonicError, HARDENED,
+};
+use privacy_blockchain::keystore::{scrypt, KdfParams, Keystore, KeystoreError, SecretKind};
+use privacy_blockchain::state::AccountState;
+use privacy_blockchain::storage::BlockStore;
+use privacy_blockchain::merkle::{merkle_proof, merkle_root, verify_merkle_proof};
 use privacy_blockchain::transaction::Transaction;
+use privacy_blockchain::shielded::{
+    field_from_bytes, field_to_bytes, MerklePath, Note, NoteCommitmentTree, ShieldedState, SpendingKey, TREE_DEPTH,
+};
+use privacy_blockchain::viewing::{PaymentAddress, PaymentAddressError, ShieldedHistory, ViewingKey, ViewingKeyError};
+use privacy_blockchain::zk_proofs::{
+    create_shielded_bundle, install_parameters, load_parameters, parameters, verify_shielded_bundle, BatchVerifier,
+    SetupParameters, SpendInput, TransferCircuit,
+};
 use privacy_blockchain::wallet::Wallet;
+use privacy_blockchain::wallet_dir::{WalletDir, WalletDirError};
+use privacy_blockchain::prover::{ProverError, ProverPool};
+use privacy_blockchain::protocol::{read_message, write_message, Message, MAX_FRAME_SIZE};
+use tokio::io::AsyncWriteExt;
+
+/// Installs throwaway zk-SNARK parameters, once for the whole test process.
+fn use_test_parameters() {
+    static PARAMETERS: std::sync::Once = std::sync::Once::new();
+    PARAMETERS.call_once(|| {
+        install_parameters(SetupParameters::generate(&mut rand_core::OsRng).unwrap()).unwrap();
+    });
+}
+
+/// Address of a new wallet, for transfers whose recipient does not matter.
+fn recipient_address() -> String {
+    Wallet::new().address().to_string()
+}
+
+/// A transfer circuit moving only zero-value notes, which need no place in
+/// the tree, with the public inputs a proof of it verifies against.
+fn empty_transfer() -> (TransferCircuit, Vec<blstrs::Scalar>) {
+    let key = SpendingKey::random();
+    let spend = |position| SpendInput {
+        note: Note::new(0, key.address()),
+        key,
+        path: MerklePath { position, siblings: vec![blstrs::Scalar::from(0u64); TREE_DEPTH] },
+    };
+    let inputs = [spend(0), spend(1)];
+    let outputs = [Note::new(0, key.address()), Note::new(0, SpendingKey::random().address())];
+    let (anchor, binding) = (blstrs::Scalar::from(3u64), blstrs::Scalar::from(9u64));
+    let mut public_inputs = vec![anchor];
+    public_inputs.extend(inputs.iter().map(SpendInput::nullifier));
+    public_inputs.extend(outputs.iter().map(Note::commitment));
+    public_inputs.extend([blstrs::Scalar::from(0u64), blstrs::Scalar::from(0u64), binding]);
+    let circuit = TransferCircuit {
+        anchor: Some(anchor),
+        inputs: inputs.map(Some),
+        outputs: outputs.map(Some),
+        public_in: Some(0),
+        public_out: Some(0),
+        binding: Some(binding),
+    };
+    (circuit, public_inputs)
+}
+
+/// Redoes proof of work after a test has edited a block.
+fn remine(block: &mut Block) {
+    block.header.merkle_root = block.calculate_merkle_root();
+    block.hash = block.calculate_hash();
+    while !block.meets_difficulty() {
+        block.header.nonce += 1;
+        block.hash = block.calculate_hash();
+    }
+}
 
 #[test]
 fn test_transaction_creation() {
+    use_test_parameters();
     let wallet = Wallet::new();
     let mut tx = Transaction::new(
         wallet.public_key_hex(),
-        "recipient_address".to_string(),
+        &recipient_address(),
         100,
-    );
+        0,
+        0,
+    ).unwrap();
     tx.sign_transaction(&wallet.signing_key);
     assert!(tx.is_valid());
+
+    // Only account keys in lowercase hex can receive, even with a valid signature
+    let upper = tx.recipient.to_uppercase();
+    for recipient in ["recipient_address", "System", Transaction::SHIELDED_POOL, upper.as_str(), &"00".repeat(31)] {
+        let mut redirected = tx.clone();
+        redirected.recipient = recipient.to_string();
+        redirected.sign_transaction(&wallet.signing_key);
+        assert!(!redirected.is_valid(), "{}", recipient);
+    }
+    assert!(!Transaction::new_reward("miner".to_string(), 1, 0).is_valid());
+    assert!(Transaction::new_reward(wallet.public_key_hex(), 1, 0).is_valid());
+    let err = Blockchain::new().mine_pending_transactions("miner").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
 }
 
 #[test]
 fn test_blockchain() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
     let mut blockchain = Blockchain::new();
     let wallet = Wallet::new();
+    let recipient = Wallet::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
     let mut tx = Transaction::new(
         wallet.public_key_hex(),
-        "recipient_address".to_string(),
+        &recipient.address().to_string(),
         100,
+        0,
+        0,
+    ).unwrap();
+    tx.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(tx).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert_eq!(blockchain.block_count(), 4);
+    assert_eq!(blockchain.get_balance(&wallet.public_key_hex()), 0);
+    assert_eq!(blockchain.get_balance(&recipient.public_key_hex()), 100);
+}
+#[test]
+fn test_validate_chain() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert!(blockchain.validate_chain().is_ok());
+
+    // Tampering with a mined reward no longer matches the committed Merkle root
+    let mut tampered = blockchain.blocks_from(0).unwrap();
+    tampered[1].transactions[0].amount = 1_000;
+    let err = blockchain.validate_blocks(&tampered).unwrap_err();
+    assert_eq!(err.block_index, 1);
+    assert_eq!(err.kind, BlockError::MerkleRootMismatch);
+
+    // Tampering with the header invalidates the block hash
+    let mut tampered = blockchain.blocks_from(0).unwrap();
+    tampered[1].header.timestamp += 1;
+    let err = blockchain.validate_blocks(&tampered).unwrap_err();
+    assert_eq!(err.kind, BlockError::HashMismatch);
+
+    // Breaking the linkage is reported against the block that no longer links
+    let mut relinked = blockchain.blocks_from(0).unwrap();
+    relinked[2].header.previous_hash = "0".repeat(64);
+    let err = blockchain.validate_blocks(&relinked).unwrap_err();
+    assert_eq!(err.block_index, 2);
+    assert_eq!(err.kind, BlockError::PreviousHashMismatch);
+}
+
+#[test]
+fn test_fork_choice_prefers_most_work() {
+    use_test_parameters();
+    let local_miner = Wallet::new().public_key_hex();
+    let remote_miner = Wallet::new().public_key_hex();
+    let wallet = Wallet::new();
+    let mut local = Blockchain::new();
+    let mut remote = Blockchain::new();
+    assert_eq!(local.block(0).unwrap().hash, remote.block(0).unwrap().hash);
+
+    // Both nodes share a block funding the wallet
+    local.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    assert!(remote.try_reorganize(local.blocks_from(0).unwrap()).unwrap());
+
+    // The local branch includes a transaction the remote branch never saw
+    let mut tx = Transaction::new(wallet.public_key_hex(), &recipient_address(), 10, 0, 0).unwrap();
+    tx.sign_transaction(&wallet.signing_key);
+    local.add_transaction(tx.clone()).unwrap();
+    local.mine_pending_transactions(&local_miner).unwrap();
+
+    remote.mine_pending_transactions(&remote_miner).unwrap();
+    assert!(!local.try_reorganize(remote.blocks_from(0).unwrap()).unwrap());
+
+    remote.mine_pending_transactions(&remote_miner).unwrap();
+    assert!(local.try_reorganize(remote.blocks_from(0).unwrap()).unwrap());
+    assert_eq!(local.get_latest_block().hash, remote.get_latest_block().hash);
+    assert_eq!(local.pending_transactions.len(), 1);
+    assert_eq!(local.pending_transactions.iter().next().unwrap().id(), tx.id());
+}
+
+#[tokio::test]
+async fn test_protocol_round_trips_large_chain() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+
+    // Pad a block with copies of its reward until the chain is several megabytes
+    let mut block = blockchain.block(1).unwrap().clone();
+    let reward = block.transactions[0].clone();
+    block.transactions = vec![reward; 30_000];
+    let chain = vec![blockchain.block(0).unwrap().clone(), block];
+    let encoded_len = to_bytes(&chain).len();
+    assert!(encoded_len > 2 * 1024 * 1024);
+
+    let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+    let writer = tokio::spawn(async move {
+        write_message(&mut client, &Message::Blocks(chain)).await.unwrap();
+        write_message(&mut client, &Message::Ping(7)).await.unwrap();
+    });
+
+    match read_message(&mut server).await.unwrap() {
+        Some(Message::Blocks(blocks)) => {
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[1].transactions.len(), 30_000);
+            assert_eq!(blocks[1].hash, blockchain.block(1).unwrap().hash);
+        }
+        other => panic!("unexpected message: {:?}", other.map(|_| ())),
+    }
+    assert!(matches!(read_message(&mut server).await.unwrap(), Some(Message::Ping(7))));
+    writer.await.unwrap();
+    assert!(read_message(&mut server).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_protocol_rejects_oversized_frame() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    client.write_u32((MAX_FRAME_SIZE + 1) as u32).await.unwrap();
+    let err = read_message(&mut server).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_sync_pages_blocks_from_the_common_ancestor() {
+    use_test_parameters();
+    let mut local = Blockchain::new();
+    let mut remote = Blockchain::new();
+    local.mine_pending_transactions(&Wallet::new().public_key_hex()).unwrap();
+    assert!(remote.try_reorganize(local.blocks_from(0).unwrap()).unwrap());
+    local.mine_pending_transactions(&Wallet::new().public_key_hex()).unwrap();
+    let remote_miner = Wallet::new().public_key_hex();
+    for _ in 0..4 {
+        remote.mine_pending_transactions(&remote_miner).unwrap();
+    }
+    let locator = local.block_locator();
+    assert_eq!(locator.iter().map(|(height, _)| *height).collect::<Vec<_>>(), [2, 1, 0]);
+
+    // The remote branch arrives a page at a time from the shared block, until an empty page
+    let mut branch = vec![];
+    loop {
+        let mut locator = local.block_locator();
+        if let Some(last) = branch.last() {
+            let last: &Block = last;
+            locator.insert(0, (last.header.index, last.hash.clone()));
+        }
+        let page = remote.blocks_after(&locator, 2, MAX_FRAME_SIZE).unwrap();
+        if page.is_empty() {
+            break;
+        }
+        assert!(page.len() <= 2);
+        branch.extend(page);
+    }
+    assert_eq!(branch.iter().map(|block| block.header.index).collect::<Vec<_>>(), [2, 3, 4, 5]);
+    assert!(local.try_reorganize(branch).unwrap());
+    assert_eq!(local.get_latest_block().hash, remote.get_latest_block().hash);
+
+    // A page always holds one block, however small the byte budget
+    assert_eq!(remote.blocks_after(&locator, 10, 1).unwrap().len(), 1);
+    // A locator sharing nothing starts from genesis
+    let unknown = [(1, "ff".repeat(32))];
+    assert_eq!(remote.blocks_after(&unknown, 1, MAX_FRAME_SIZE).unwrap()[0].header.index, 0);
+}
+
+#[test]
+fn test_nonces_prevent_replay() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let mut blockchain = Blockchain::new();
+    let wallet = Wallet::new();
+    let sender = wallet.public_key_hex();
+
+    blockchain.mine_pending_transactions(&sender).unwrap();
+
+    let mut first = Transaction::new(sender.clone(), &recipient_address(), 5, 0, 0).unwrap();
+    first.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(first.clone()).unwrap();
+    assert_eq!(
+        blockchain.add_transaction(first.clone()),
+        Err(TransactionError::NonceReused { expected: 1, found: 0 })
+    );
+
+    let mut skipped = Transaction::new(sender.clone(), &recipient_address(), 5, 0, 2).unwrap();
+    skipped.sign_transaction(&wallet.signing_key);
+    assert_eq!(
+        blockchain.add_transaction(skipped),
+        Err(TransactionError::NonceGap { expected: 1, found: 2 })
+    );
+
+    // Once mined, the same signed payload can never be accepted again
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert_eq!(blockchain.next_nonce(&sender), 1);
+    assert!(blockchain.add_transaction(first.clone()).is_err());
+
+    // A block replaying the transaction is rejected by validation
+    let mut replayed = blockchain.blocks_from(0).unwrap();
+    replayed[2].transactions.insert(1, first);
+    remine(&mut replayed[2]);
+    let err = blockchain.validate_blocks(&replayed).unwrap_err();
+    assert_eq!(err.kind, BlockError::InvalidNonce(1));
+
+    // Tampering with the nonce breaks the signature
+    let mut tampered = Transaction::new(sender, &recipient_address(), 5, 0, 1).unwrap();
+    tampered.sign_transaction(&wallet.signing_key);
+    tampered.nonce = 3;
+    assert!(!tampered.is_valid());
+}
+
+#[test]
+fn test_overspending_is_rejected() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let mut blockchain = Blockchain::new();
+    let wallet = Wallet::new();
+    let sender = wallet.public_key_hex();
+    blockchain.mine_pending_transactions(&sender).unwrap();
+    assert_eq!(blockchain.get_balance(&sender), Transaction::MINING_REWARD);
+
+    let mut first = Transaction::new(sender.clone(), &recipient_address(), 30, 0, 0).unwrap();
+    first.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(first).unwrap();
+    assert_eq!(blockchain.spendable_balance(&sender), 20);
+
+    // The pending spend counts against the second transaction
+    let mut second = Transaction::new(sender.clone(), &recipient_address(), 30, 0, 1).unwrap();
+    second.sign_transaction(&wallet.signing_key);
+    assert_eq!(
+        blockchain.add_transaction(second.clone()),
+        Err(TransactionError::InsufficientFunds { available: 20, required: 30 })
+    );
+
+    // A block smuggling in the overspend fails validation
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    let mut overspent = blockchain.blocks_from(0).unwrap();
+    overspent[2].transactions.insert(1, second);
+    remine(&mut overspent[2]);
+    let err = blockchain.validate_blocks(&overspent).unwrap_err();
+    assert_eq!(err.kind, BlockError::InsufficientFunds(1));
+    assert_eq!(blockchain.get_balance(&sender), 20);
+}
+
+#[test]
+fn test_fee_priority_mempool() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let mut blockchain = Blockchain::new();
+    let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+    for wallet in &wallets {
+        blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    }
+    blockchain.set_mempool_size(2);
+
+    let send = |wallet: &Wallet, fee: u64, nonce: u64| {
+        let mut tx = Transaction::new(wallet.public_key_hex(), &recipient_address(), 10, fee, nonce).unwrap();
+        tx.sign_transaction(&wallet.signing_key);
+        tx
+    };
+    blockchain.add_transaction(send(&wallets[0], 1, 0)).unwrap();
+    blockchain.add_transaction(send(&wallets[1], 5, 0)).unwrap();
+
+    // A full pool evicts its cheapest entry for a better-paying one, and refuses worse ones
+    blockchain.add_transaction(send(&wallets[2], 3, 0)).unwrap();
+    assert!(blockchain.pending_transactions.iter().all(|tx| tx.sender != wallets[0].public_key_hex()));
+    assert_eq!(blockchain.add_transaction(send(&wallets[0], 2, 0)), Err(TransactionError::MempoolFull));
+
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    let block = blockchain.get_latest_block();
+    assert_eq!(block.transactions[0].sender, wallets[1].public_key_hex());
+    assert_eq!(block.transactions[1].sender, wallets[2].public_key_hex());
+    assert_eq!(blockchain.get_balance(&miner), Transaction::MINING_REWARD + 8);
+    assert_eq!(blockchain.get_balance(&wallets[1].public_key_hex()), Transaction::MINING_REWARD - 15);
+    assert!(blockchain.validate_chain().is_ok());
+
+    // A sender's queued transactions are never evicted for its own later ones
+    blockchain.add_transaction(send(&wallets[0], 1, 0)).unwrap();
+    blockchain.add_transaction(send(&wallets[0], 2, 1)).unwrap();
+    assert_eq!(blockchain.add_transaction(send(&wallets[0], 3, 2)), Err(TransactionError::MempoolFull));
+    assert_eq!(blockchain.pending_transactions.len(), 2);
+
+    // Shrinking the pool drops the cheapest tail first
+    blockchain.set_mempool_size(1);
+    let pending: Vec<_> = blockchain.pending_transactions.iter().collect();
+    assert_eq!((pending.len(), pending[0].nonce), (1, 0));
+}
+
+#[test]
+fn test_compact_target_round_trip() {
+    let limit = target_from_compact(POW_LIMIT_BITS).unwrap();
+    assert_eq!(target_to_compact(limit), POW_LIMIT_BITS);
+    assert_eq!(limit.to_be_bytes()[0], 0);
+    assert_eq!(limit.to_be_bytes()[1], 0xff);
+
+    let quarter = limit / U256::from_u64(4);
+    assert_eq!(target_to_compact(quarter), 0x1f3fffc0);
+    assert_eq!(work_for_target(quarter), 4 * work_for_target(limit));
+    assert!(target_from_compact(0x01800000).is_none());
+}
+
+#[test]
+fn test_difficulty_retargets_on_block_time() {
+    use_test_parameters();
+    let build = |spacing: i64, bits: u32| {
+        let mut chain = vec![Block::genesis()];
+        for height in 1..RETARGET_INTERVAL {
+            let mut block = Block::new(height, String::new(), vec![], bits);
+            block.header.timestamp = GENESIS_TIMESTAMP + spacing * height as i64;
+            chain.push(block);
+        }
+        chain
+    };
+
+    // Blocks arriving faster than the target time make the next target harder
+    let fast = build(1, POW_LIMIT_BITS);
+    let fast_bits = next_bits(&fast);
+    assert!(target_from_compact(fast_bits).unwrap() < target_from_compact(POW_LIMIT_BITS).unwrap());
+    assert_eq!(next_bits(&fast[..5]), POW_LIMIT_BITS);
+
+    // Slow blocks ease the target, but never past the limit
+    let slow = build(10 * TARGET_BLOCK_TIME, fast_bits);
+    assert!(target_from_compact(next_bits(&slow)).unwrap() > target_from_compact(fast_bits).unwrap());
+    assert_eq!(next_bits(&build(10 * TARGET_BLOCK_TIME, POW_LIMIT_BITS)), POW_LIMIT_BITS);
+}
+
+#[test]
+fn test_block_with_wrong_difficulty_is_rejected() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+
+    // A block claiming an easier target than the schedule allows fails validation
+    let mut easy = blockchain.blocks_from(0).unwrap();
+    easy[1].header.bits = 0x2100ffff;
+    remine(&mut easy[1]);
+    let err = blockchain.validate_blocks(&easy).unwrap_err();
+    assert_eq!(err.kind, BlockError::InvalidDifficulty { expected: POW_LIMIT_BITS, found: 0x2100ffff });
+}
+
+#[test]
+fn test_merkle_inclusion_proofs() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let ids: Vec<String> = (0..7).map(|i| format!("tx{}", i)).collect();
+    let root = merkle_root(&ids);
+    for (i, id) in ids.iter().enumerate() {
+        let proof = merkle_proof(&ids, i).unwrap();
+        assert!(verify_merkle_proof(id, &proof, &root));
+        assert!(!verify_merkle_proof("other", &proof, &root));
+    }
+    assert!(merkle_proof(&ids, 7).is_none());
+
+    // Proofs also work against a mined block's header
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    let block = blockchain.get_latest_block();
+    let proof = block.merkle_proof(0).unwrap();
+    assert!(verify_merkle_proof(&block.transactions[0].id(), &proof, &block.header.merkle_root));
+}
+
+#[test]
+fn test_canonical_encoding_golden_vectors() {
+    use_test_parameters();
+    let tx = Transaction {
+        sender: "alice".to_string(),
+        recipient: "bob".to_string(),
+        amount: 5,
+        fee: 1,
+        nonce: 2,
+        signature: Some("ab".to_string()),
+        shielded: None,
+    };
+    assert_eq!(
+        hex::encode(to_bytes(&tx)),
+        concat!(
+            "01",
+            "05000000616c696365",
+            "03000000626f62",
+            "0500000000000000",
+            "0100000000000000",
+            "0200000000000000",
+            "01020000006162",
+            "00",
+        )
     );
+
+    let header = BlockHeader {
+        index: 1,
+        timestamp: GENESIS_TIMESTAMP,
+        previous_hash: "00".to_string(),
+        merkle_root: "11".to_string(),
+        state_root: "22".to_string(),
+        note_root: "33".to_string(),
+        bits: POW_LIMIT_BITS,
+        nonce: 7,
+    };
+    assert_eq!(
+        hex::encode(to_bytes(&header)),
+        concat!(
+            "01",
+            "0100000000000000",
+            "00f1536500000000",
+            "020000003030",
+            "020000003131",
+            "020000003232",
+            "020000003333",
+            "ffff0020",
+            "0700000000000000",
+        )
+    );
+    // The genesis hash depends on the parameters it pins, the rest is fixed
+    let mut genesis = Block::genesis();
+    assert_eq!(genesis.hash, genesis.calculate_hash());
+    genesis.header.previous_hash = "0".to_string();
+    assert_eq!(genesis.calculate_hash(), "9885d9aee555e484ca901af478f9c0e17ebb8283dcc0473772530ba0d09d0632");
+
+    let decoded: Transaction = from_bytes(&to_bytes(&tx)).unwrap();
+    assert_eq!(decoded.id(), tx.id());
+    let mut versioned = to_bytes(&tx);
+    versioned[0] = 2;
+    assert_eq!(from_bytes::<Transaction>(&versioned).unwrap_err(), DecodeError::UnsupportedVersion(2));
+    let mut trailing = to_bytes(&tx);
+    trailing.push(0);
+    assert_eq!(from_bytes::<Transaction>(&trailing).unwrap_err(), DecodeError::TrailingBytes);
+}
+
+#[test]
+fn test_blockchain_file_round_trip() {
+    use_test_parameters();
+    let path = std::env::temp_dir().join(format!("blockchain-{}.dat", std::process::id()));
+    let path = path.to_str().unwrap();
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let mut tx = Transaction::new(wallet.public_key_hex(), &recipient_address(), 10, 1, 0).unwrap();
+    tx.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(tx).unwrap();
+    blockchain.save_to_file(path).unwrap();
+
+    let loaded = Blockchain::load_from_file(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.get_latest_block().hash, blockchain.get_latest_block().hash);
+    assert_eq!(loaded.pending_transactions.len(), 1);
+    assert_eq!(loaded.get_balance(&wallet.public_key_hex()), Transaction::MINING_REWARD);
+}
+
+/// Fresh directory under the system temp dir for one test.
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_block_store_survives_restart_and_torn_writes() {
+    use_test_parameters();
+    let dir = temp_dir("block-store");
+    let wallet = Wallet::new();
+    let recipient = Wallet::new();
+    let tip = {
+        let mut blockchain = Blockchain::open(&dir).unwrap();
+        blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+        let mut tx = Transaction::new(wallet.public_key_hex(), &recipient.address().to_string(), 10, 1, 0).unwrap();
+        tx.sign_transaction(&wallet.signing_key);
+        blockchain.add_transaction(tx).unwrap();
+        blockchain.flush().unwrap();
+        blockchain.get_latest_block().hash.clone()
+    };
+
+    // Simulate a crash halfway through appending the next block and its index record
+    use std::io::Write;
+    let append = |file: &str, bytes: &[u8]| {
+        let mut f = std::fs::OpenOptions::new().append(true).open(dir.join(file)).unwrap();
+        f.write_all(bytes).unwrap();
+    };
+    append("blk00000.dat", &[0xab; 100]);
+    append("index.dat", &[0xcd; 20]);
+
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.height_of(&tip), Some(1));
+    assert_eq!(store.block_by_hash(&tip).unwrap().unwrap().hash, tip);
+    drop(store);
+
+    let mut blockchain = Blockchain::open(&dir).unwrap();
+    assert_eq!(blockchain.get_latest_block().hash, tip);
+    assert_eq!(blockchain.pending_transactions.len(), 1);
+    assert_eq!(blockchain.get_balance(&wallet.public_key_hex()), Transaction::MINING_REWARD);
+
+    // Blocks appended after recovery land where the torn record was
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let reopened = Blockchain::open(&dir).unwrap();
+    assert_eq!(reopened.block_count(), 3);
+    assert_eq!(reopened.get_balance(&recipient.public_key_hex()), 10);
+    assert!(reopened.validate_chain().is_ok());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_block_store_follows_reorganization() {
+    use_test_parameters();
+    let local_miner = Wallet::new().public_key_hex();
+    let remote_miner = Wallet::new().public_key_hex();
+    let dir = temp_dir("block-store-reorg");
+    let mut local = Blockchain::open(&dir).unwrap();
+    local.mine_pending_transactions(&local_miner).unwrap();
+    let orphaned = local.get_latest_block().hash.clone();
+
+    let mut remote = Blockchain::new();
+    remote.mine_pending_transactions(&remote_miner).unwrap();
+    remote.mine_pending_transactions(&remote_miner).unwrap();
+    assert!(local.try_reorganize(remote.blocks_from(0).unwrap()).unwrap());
+
+    let reopened = Blockchain::open(&dir).unwrap();
+    assert_eq!(reopened.get_latest_block().hash, remote.get_latest_block().hash);
+    assert_eq!(reopened.get_balance(&local_miner), 0);
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.height_of(&orphaned), None);
+    assert_eq!(store.height_of(&remote.block(1).unwrap().hash), Some(1));
+    drop(store);
+
+    // A peer may send just the blocks that extend our tip
+    remote.mine_pending_transactions(&remote_miner).unwrap();
+    assert!(local.try_reorganize(vec![remote.get_latest_block().clone()]).unwrap());
+    assert_eq!(Blockchain::open(&dir).unwrap().block_count(), 4);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_import_legacy_json_chain() {
+    use_test_parameters();
+    // Saved by the first versions of the node: two mined blocks, one transfer and one pending
+    let legacy = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/legacy_blockchain.json");
+    let first_miner = "501658d12720348c74d377d3072e2c015847764b52c173a79a008438f3278213";
+    let second_miner = "9e3a9c80b6ea38641a9f9364493f278b7b2e3ae1d37ac124b5792e1eab384d90";
+    let dir = temp_dir("block-store-import");
+
+    // The old transfers cannot be replayed, so importing them needs consent
+    let err = Blockchain::import_legacy(&dir, legacy, false).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(!dir.exists());
+
+    // Every block is then mined again with the same miner, and the transfers are gone
+    let imported = Blockchain::import_legacy(&dir, legacy, true).unwrap();
+    assert_eq!(imported.block_count(), 3);
+    assert_eq!(imported.get_balance(first_miner), Transaction::MINING_REWARD);
+    assert_eq!(imported.get_balance(second_miner), Transaction::MINING_REWARD);
+    assert!(imported.pending_transactions.is_empty());
+    assert!(imported.validate_chain().is_ok());
+
+    let opened = Blockchain::open(&dir).unwrap();
+    assert_eq!(opened.get_latest_block().hash, imported.get_latest_block().hash);
+    assert_eq!(opened.get_balance(second_miner), Transaction::MINING_REWARD);
+    assert!(Blockchain::import_legacy(&dir, legacy, true).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Without transfers, the import keeps every balance and needs no consent
+    let mut mined_only: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(legacy).unwrap()).unwrap();
+    mined_only["chain"][2]["transactions"].as_array_mut().unwrap().remove(0);
+    mined_only["pending_transactions"] = serde_json::json!([]);
+    let mined_only_file = std::env::temp_dir().join(format!("blockchain-mined-{}.json", std::process::id()));
+    std::fs::write(&mined_only_file, mined_only.to_string()).unwrap();
+    let dir = temp_dir("block-store-import-mined");
+    let imported = Blockchain::import_legacy(&dir, &mined_only_file, false).unwrap();
+    assert_eq!(imported.get_balance(first_miner), Transaction::MINING_REWARD);
+    assert_eq!(imported.get_balance(second_miner), Transaction::MINING_REWARD);
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&mined_only_file).unwrap();
+
+    // Blocks that do not link up are refused
+    let broken = std::fs::read_to_string(legacy).unwrap().replacen("\"index\": 2", "\"index\": 3", 1);
+    let broken_file = std::env::temp_dir().join(format!("blockchain-{}.json", std::process::id()));
+    std::fs::write(&broken_file, broken).unwrap();
+    let err = Blockchain::import_legacy(temp_dir("block-store-import-broken"), &broken_file, true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::remove_file(&broken_file).unwrap();
+}
+
+#[test]
+fn test_account_state_root_and_revert() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let before = blockchain.accounts.clone();
+
+    let mut tx = Transaction::new(wallet.public_key_hex(), &recipient_address(), 10, 2, 0).unwrap();
+    tx.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(tx).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    let tip = blockchain.get_latest_block().clone();
+    assert_eq!(tip.header.state_root, blockchain.accounts.state_root());
+    assert_eq!(blockchain.accounts.nonce(&wallet.public_key_hex()), 1);
+    assert_eq!(blockchain.accounts.balance(&miner), Transaction::MINING_REWARD + 2);
+
+    // Disconnecting the block restores the previous state exactly
+    let mut reverted = blockchain.accounts.clone();
+    reverted.revert_block(&tip);
+    assert_eq!(reverted, before);
+    assert_eq!(reverted.state_root(), blockchain.block(1).unwrap().header.state_root);
+    assert_eq!(AccountState::default().state_root(), blockchain.block(0).unwrap().header.state_root);
+
+    // A block committing to a different state is rejected even with valid work
+    let mut forged = blockchain.blocks_from(0).unwrap();
+    forged[2].header.state_root = before.state_root();
+    remine(&mut forged[2]);
+    let err = blockchain.validate_blocks(&forged).unwrap_err();
+    assert_eq!(err.block_index, 2);
+    assert_eq!(err.kind, BlockError::StateRootMismatch);
+}
+
+#[test]
+fn test_account_state_is_persisted_with_the_chain() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let dir = temp_dir("account-state");
+    let mut blockchain = Blockchain::open(&dir).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    let accounts = blockchain.accounts.clone();
+
+    let store = BlockStore::open(&dir).unwrap();
+    let (tip, saved, shielded) = store.load_state().unwrap().unwrap();
+    assert_eq!(tip, blockchain.get_latest_block().hash);
+    assert_eq!(saved, accounts);
+    assert_eq!(shielded, blockchain.shielded);
+
+    // A state saved for another tip is ignored and rebuilt from the blocks
+    store.save_state(&blockchain.block(0).unwrap().hash, &AccountState::default(), &ShieldedState::default()).unwrap();
+    let reopened = Blockchain::open(&dir).unwrap();
+    assert_eq!(reopened.accounts, accounts);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_proofs_only_verify_against_pinned_parameters() {
+    use_test_parameters();
+    use bellman::groth16::{create_random_proof, prepare_verifying_key, verify_proof};
+
+    // A proof made under parameters from another setup does not verify
+    // against the pinned key, though it does against its own
+    let other = SetupParameters::generate(&mut rand_core::OsRng).unwrap();
+    let (circuit, inputs) = empty_transfer();
+    let proof = create_random_proof(circuit.clone(), &other.transfer, &mut rand_core::OsRng).unwrap();
+    assert!(verify_proof(&prepare_verifying_key(&other.transfer.vk), &proof, &inputs).is_ok());
+    let pinned = prepare_verifying_key(&parameters().transfer.vk);
+    assert!(verify_proof(&pinned, &proof, &inputs).is_err());
+    let proof = create_random_proof(circuit, &parameters().transfer, &mut rand_core::OsRng).unwrap();
+    assert!(verify_proof(&pinned, &proof, &inputs).is_ok());
+
+    // The parameters file round-trips, and the pinned parameters cannot be replaced
+    let path = std::env::temp_dir().join(format!("params-{}.bin", std::process::id()));
+    parameters().write(std::fs::File::create(&path).unwrap()).unwrap();
+    let read = SetupParameters::read(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(read.fingerprint(), parameters().fingerprint());
+    assert!(matches!(load_parameters(&path), Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists));
+    std::fs::write(&path, b"not parameters").unwrap();
+    assert!(SetupParameters::read(std::fs::File::open(&path).unwrap()).is_err());
+    std::fs::remove_file(&path).unwrap();
+
+    // The genesis block pins the parameters, so chain data created under
+    // other ones is refused
+    assert_eq!(Block::genesis().header.previous_hash, parameters().fingerprint());
+    let dir = temp_dir("other-parameters");
+    let mut other = Block::genesis();
+    other.header.previous_hash = "0".repeat(64);
+    other.hash = other.calculate_hash();
+    BlockStore::open(&dir).unwrap().append(&other).unwrap();
+    let err = Blockchain::open(&dir).err().unwrap();
+    assert!(err.to_string().contains("zk-SNARK parameters"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_setup_ceremony_verifies_every_contribution() {
+    use bellman::groth16::{create_random_proof, prepare_verifying_key, verify_proof};
+
+    // The powers of tau need a contribution before parameters come from them
+    let mut ceremony = Ceremony::new().unwrap();
+    assert_eq!(ceremony.verify(), Err(CeremonyError::NoContributions));
+    assert!(ceremony.prepare().is_err());
+    let powers = ceremony.contribute(&mut rand_core::OsRng).unwrap();
+    assert_eq!(ceremony.verify(), Err(CeremonyError::PowersOpen));
+    assert_eq!(ceremony.powers_contributions()[0].hash(), powers);
+
+    let mut transcript = vec![];
+    ceremony.write(&mut transcript).unwrap();
+    let last = transcript.len() - 1;
+    transcript[last] ^= 1;
+    let tampered = Ceremony::read(&transcript[..]).unwrap();
+    assert_eq!(tampered.verify(), Err(CeremonyError::InvalidPowersContribution(0)));
+
+    ceremony.prepare().unwrap();
+    assert_eq!(ceremony.verify(), Err(CeremonyError::NoContributions));
+    let first = ceremony.contribute(&mut rand_core::OsRng).unwrap();
+    let second = ceremony.contribute(&mut rand_core::OsRng).unwrap();
+    assert_ne!(first, second);
+    assert_eq!(ceremony.powers_contributions().len(), 1);
+    assert_eq!(ceremony.verify(), Ok(()));
+
+    // The transcript round-trips, and any change to a contribution is caught
+    let mut transcript = vec![];
+    ceremony.write(&mut transcript).unwrap();
+    let read = Ceremony::read(&transcript[..]).unwrap();
+    assert_eq!(read.powers_contributions(), ceremony.powers_contributions());
+    assert_eq!(read.contributions(), ceremony.contributions());
+    let last = transcript.len() - 1;
+    transcript[last] ^= 1;
+    let tampered = Ceremony::read(&transcript[..]).unwrap();
+    assert_eq!(tampered.verify(), Err(CeremonyError::InvalidContribution(1)));
+
+    // Proofs made under the final parameters verify against their key
+    let params = ceremony.into_parameters().unwrap();
+    let (circuit, inputs) = empty_transfer();
+    let proof = create_random_proof(circuit, &params.transfer, &mut rand_core::OsRng).unwrap();
+    assert!(verify_proof(&prepare_verifying_key(&params.transfer.vk), &proof, &inputs).is_ok());
+}
+
+#[test]
+fn test_batch_verification_finds_the_invalid_proof() {
+    use_test_parameters();
+    let sender = Wallet::new().public_key_hex();
+    let anchor = NoteCommitmentTree::default().root();
+    let deposit = |amount| {
+        let note = Note::new(amount, SpendingKey::random().address());
+        Transaction::new_deposit(sender.clone(), amount, 0, amount, &[note], anchor).unwrap()
+    };
+    // Transparent transfers carry no proof, so only the deposits are queued
+    let transfer = Transaction::new(sender.clone(), &recipient_address(), 1, 0, 0).unwrap();
+    let mut transactions = vec![transfer, deposit(2), deposit(3)];
+    assert_eq!(Blockchain::first_invalid_proof(&transactions), None);
+    assert_eq!(Blockchain::first_invalid_proof(&[]), None);
+
+    // A valid proof made for another deposit fails the batch
+    let proof = transactions[1].shielded.as_ref().unwrap().proof.clone();
+    transactions[2].shielded.as_mut().unwrap().proof = proof;
+    assert_eq!(Blockchain::first_invalid_proof(&transactions), Some(2));
+    let mut batch = BatchVerifier::new();
+    assert!(transactions.iter().all(|tx| tx.queue_proofs(&mut batch)));
+    assert_eq!(batch.len(), 2);
+    assert!(!batch.verify());
+}
+
+#[tokio::test]
+async fn test_prover_pool_queues_and_cancels_jobs() {
+    use_test_parameters();
+    let pool = ProverPool::new(1, 1);
+
+    // Hold the only worker until the queue has been checked
+    let (started, worker_started) = std::sync::mpsc::channel();
+    let (release, gate) = std::sync::mpsc::channel::<()>();
+    let note = Note::new(5, SpendingKey::random().address());
+    let anchor = NoteCommitmentTree::default().root();
+    let busy = pool
+        .submit(move || {
+            started.send(()).unwrap();
+            gate.recv().unwrap();
+            create_shielded_bundle(&[], &[note], anchor, 5, 0, blstrs::Scalar::from(1u64)).unwrap()
+        })
+        .await;
+    worker_started.recv().unwrap();
+    let queued = pool.try_submit(|| 2).unwrap();
+    assert_eq!(pool.queued(), 1);
+    assert_eq!(pool.try_submit(|| 3).err(), Some(ProverError::QueueFull));
+
+    queued.cancel();
+    release.send(()).unwrap();
+    assert!(verify_shielded_bundle(&busy.await.unwrap(), 5, 0, blstrs::Scalar::from(1u64)));
+    assert_eq!(queued.await, Err(ProverError::Cancelled));
+
+    // A panicking job fails on its own and leaves the worker running
+    assert_eq!(pool.submit(|| -> u64 { panic!("proving failed") }).await.await, Err(ProverError::Panicked));
+    assert_eq!(pool.submit(|| 4).await.await, Ok(4));
+}
+
+#[test]
+fn test_block_template_takes_a_reward_proven_elsewhere() {
+    use_test_parameters();
+    let mut blockchain = Blockchain::new();
+    let wallet = Wallet::new();
+    let sender = wallet.public_key_hex();
+    blockchain.mine_pending_transactions(&sender).unwrap();
+
+    let mut tx = Transaction::new(sender.clone(), &recipient_address(), 5, 2, 0).unwrap();
     tx.sign_transaction(&wallet.signing_key);
-    blockchain.add_transaction(tx);
-    blockchain.mine_pending_transactions("miner_address");
-    assert_eq!(blockchain.chain.len(), 2);
-}
\ No newline at end of file
+    blockchain.add_transaction(tx.clone()).unwrap();
+
+    // A reward for the wrong fees is refused
+    let template = blockchain.block_template().unwrap();
+    assert_eq!((template.height(), template.fees()), (2, 2));
+    let wrong = Transaction::new_reward(sender.clone(), template.height(), 0);
+    let err = blockchain.mine_block(template, wrong).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // A block mined off the chain is refused once the chain has moved past
+    // its template, and its transactions stay pending
+    let template = blockchain.block_template().unwrap();
+    let reward = Transaction::new_reward(sender.clone(), template.height(), template.fees());
+    let mined = template.mine(reward).unwrap();
+    assert!(mined.block().meets_difficulty());
+    let mut other = Blockchain::new();
+    std::mem::swap(&mut other.pending_transactions, &mut blockchain.pending_transactions);
+    blockchain.mine_pending_transactions(&sender).unwrap();
+    std::mem::swap(&mut other.pending_transactions, &mut blockchain.pending_transactions);
+    let err = blockchain.submit_block(mined).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    assert_eq!(blockchain.pending_transactions.len(), 1);
+
+    let template = blockchain.block_template().unwrap();
+    let reward = Transaction::new_reward(sender.clone(), template.height(), template.fees());
+    blockchain.mine_block(template, reward).unwrap();
+    assert!(blockchain.pending_transactions.is_empty());
+    assert_eq!(blockchain.get_latest_block().transactions[0].id(), tx.id());
+    assert_eq!(blockchain.get_balance(&sender), 3 * Transaction::MINING_REWARD - 5);
+}
+
+#[test]
+fn test_proofs_are_bound_to_their_transaction() {
+    use_test_parameters();
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let note = Note::new(5, SpendingKey::random().address());
+    let anchor = blockchain.shielded.tree.root();
+    let mut tx = Transaction::new_deposit(wallet.public_key_hex(), 5, 1, 0, &[note], anchor).unwrap();
+    tx.sign_transaction(&wallet.signing_key);
+
+    // The sender's own proof, copied onto a different deposit, is rejected
+    let changes: [fn(&mut Transaction); 2] = [|tx| tx.fee = 2, |tx| tx.nonce = 1];
+    for change in changes {
+        let mut copied = tx.clone();
+        change(&mut copied);
+        copied.sign_transaction(&wallet.signing_key);
+        assert_ne!(copied.binding(), tx.binding());
+        assert_eq!(blockchain.add_transaction(copied.clone()), Err(TransactionError::InvalidProof));
+        assert_eq!(Blockchain::first_invalid_proof(&[tx.clone(), copied]), Some(1));
+    }
+
+    // A bundle proving another amount fails even under the right binding
+    let mut mismatched = tx.clone();
+    let other = Note::new(6, SpendingKey::random().address());
+    mismatched.shielded = Some(create_shielded_bundle(&[], &[other], anchor, 6, 0, tx.binding()).unwrap());
+    mismatched.sign_transaction(&wallet.signing_key);
+    assert!(!mismatched.verify_shielded());
+    assert_eq!(blockchain.add_transaction(mismatched), Err(TransactionError::InvalidProof));
+    blockchain.add_transaction(tx).unwrap();
+}
+
+#[test]
+fn test_scrypt_matches_reference_vectors() {
+    let mut out = [0u8; 64];
+    scrypt(b"", b"", KdfParams { log_n: 4, r: 1, p: 1 }, &mut out);
+    assert_eq!(
+        hex::encode(out),
+        "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+         fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+    );
+    let mut out = [0u8; 32];
+    scrypt(b"password", b"NaCl", KdfParams { log_n: 4, r: 2, p: 2 }, &mut out);
+    assert_eq!(hex::encode(out), "80a54a798dfc8fbba744f293ab0429201fc00b3a785ce835794bc4bdf4a80681");
+}
+
+#[test]
+fn test_wallet_keystore_encrypts_and_migrates() {
+    let params = KdfParams { log_n: 10, r: 8, p: 1 };
+    let wallet = Wallet::new();
+    let keystore = wallet.to_keystore("correct horse", params);
+    let bytes = keystore.to_bytes();
+    assert!(!bytes.windows(32).any(|window| window == wallet.signing_key.as_ref()));
+    assert_eq!(hex::encode(keystore.public_key()), wallet.public_key_hex());
+
+    let opened = Wallet::from_keystore(&Keystore::from_bytes(&bytes).unwrap(), "correct horse").unwrap();
+    assert_eq!(opened.public_key_hex(), wallet.public_key_hex());
+    assert_eq!(Wallet::from_keystore(&keystore, "wrong horse").err(), Some(KeystoreError::WrongPassphrase));
+
+    // Every header byte is authenticated, and unknown versions are refused
+    let mut tampered = bytes.clone();
+    tampered[bytes.len() - 70] ^= 1;
+    let tampered = Keystore::from_bytes(&tampered).unwrap();
+    assert_eq!(Wallet::from_keystore(&tampered, "correct horse").err(), Some(KeystoreError::WrongPassphrase));
+    let mut future = bytes.clone();
+    future[8] = 3;
+    assert_eq!(Keystore::from_bytes(&future), Err(KeystoreError::UnsupportedVersion(3)));
+    assert_eq!(Keystore::from_bytes(&bytes[..bytes.len() - 1]), Err(KeystoreError::Malformed));
+
+    // A plaintext wallet file is encrypted in place
+    let path = std::env::temp_dir().join(format!("wallet-{}.dat", std::process::id()));
+    let path = path.to_str().unwrap();
+    let secret = hex::encode(wallet.signing_key.as_ref());
+    std::fs::write(path, format!("{}\n{}", wallet.public_key_hex(), secret)).unwrap();
+    assert!(Wallet::is_plaintext_file(path).unwrap());
+    assert_eq!(Wallet::public_key_from_file(path).unwrap(), wallet.public_key_hex());
+    assert!(Wallet::load_from_file(path, "anything").is_err());
+    Wallet::migrate_plaintext_file(path, "correct horse").unwrap();
+    assert!(!Wallet::is_plaintext_file(path).unwrap());
+    assert!(!std::fs::read_to_string(path).unwrap_or_default().contains(&secret));
+    assert_eq!(Wallet::public_key_from_file(path).unwrap(), wallet.public_key_hex());
+    let loaded = Wallet::load_from_file(path, "correct horse").unwrap();
+    assert_eq!(loaded.public_key_hex(), wallet.public_key_hex());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_hd_wallet_restores_accounts_from_mnemonic() {
+    // BIP39 reference vector
+    let phrase = format!("{} about", ["abandon"; 11].join(" "));
+    let mnemonic = Mnemonic::parse(phrase.as_str()).unwrap();
+    assert_eq!(
+        hex::encode(&mnemonic_to_seed(&mnemonic, "TREZOR")[..]),
+        "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+    );
+    let bad_checksum = ["abandon"; 12].join(" ");
+    assert_eq!(Mnemonic::parse(bad_checksum.as_str()), Err(MnemonicError::InvalidChecksum));
+    assert!(generate_mnemonic(13).is_err());
+
+    // SLIP-0010 ed25519 test vector 1, chain m/0'
+    let master = ExtendedKey::master(&hex::decode("000102030405060708090a0b0c0d0e0f").unwrap());
+    assert_eq!(hex::encode(master.secret_key()), "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7");
+    assert_eq!(hex::encode(master.chain_code()), "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb");
+    let child = master.derive(0);
+    assert_eq!(hex::encode(child.secret_key()), "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3");
+    assert_eq!(hex::encode(child.chain_code()), "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69");
+
+    // A generated phrase rebuilds the same accounts, each with its own key
+    let mnemonic = generate_mnemonic(24).unwrap();
+    let wallet = Wallet::from_mnemonic(&mnemonic, "", 0).unwrap();
+    let restored = Wallet::from_mnemonic(&Mnemonic::parse(mnemonic.to_string().as_str()).unwrap(), "", 0).unwrap();
+    assert_eq!(restored.public_key_hex(), wallet.public_key_hex());
+    let second = wallet.with_account(1).unwrap().unwrap();
+    assert_ne!(second.public_key_hex(), wallet.public_key_hex());
+    assert_ne!(Wallet::from_mnemonic(&mnemonic, "extra words", 0).unwrap().public_key_hex(), wallet.public_key_hex());
+    assert!(Wallet::new().with_account(1).unwrap().is_none());
+
+    // Hardened account numbers would alias lower accounts, so they are refused
+    assert_eq!(wallet.with_account(HARDENED + 1).err(), Some(InvalidAccount(HARDENED + 1)));
+    assert!(Wallet::from_mnemonic(&mnemonic, "", u32::MAX).is_err());
+    let last = wallet.with_account(HARDENED - 1).unwrap().unwrap();
+    assert_eq!(last.account(), HARDENED - 1);
+
+    // The keystore keeps the seed and the account in use
+    let keystore = second.to_keystore("correct horse", KdfParams { log_n: 10, r: 8, p: 1 });
+    assert_eq!(keystore.kind(), SecretKind::Seed { account: 1 });
+    let opened = Wallet::from_keystore(&Keystore::from_bytes(&keystore.to_bytes()).unwrap(), "correct horse").unwrap();
+    assert_eq!((opened.account(), opened.public_key_hex()), (1, second.public_key_hex()));
+    assert_eq!(opened.with_account(0).unwrap().unwrap().public_key_hex(), wallet.public_key_hex());
+    let params = KdfParams { log_n: 10, r: 8, p: 1 };
+    let hardened = Keystore::seal(&[7; 64], SecretKind::Seed { account: HARDENED }, [0; 32], "pw", params);
+    assert_eq!(Wallet::from_keystore(&hardened, "pw").err(), Some(KeystoreError::Malformed));
+}
+
+#[test]
+fn test_addresses_reject_typos_and_other_networks() {
+    use_test_parameters();
+    // The ed25519 base point, encoded independently per BIP350
+    let base_point = format!("58{}", "66".repeat(31));
+    let address = Address::from_public_key_hex(&base_point).unwrap();
+    let encoded = "pb1qpvxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxv82z27g";
+    assert_eq!(address.to_string(), encoded);
+    assert_eq!(Address::parse(encoded), Ok(address));
+    assert_eq!(Address::parse(&encoded.to_uppercase()), Ok(address));
+    assert_eq!(address.public_key_hex(), base_point);
+
+    let typo = encoded.replacen("qpvx", "qpwx", 1);
+    assert_eq!(Address::parse(&typo), Err(AddressError::InvalidChecksum));
+    assert_eq!(Address::parse("recipient_address"), Err(AddressError::Malformed));
+    assert_eq!(Address::parse(&base_point), Err(AddressError::Malformed));
+    let testnet = "tpb1qpvxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvxnsd08";
+    assert_eq!(Address::parse(testnet), Err(AddressError::WrongNetwork("tpb".to_string())));
+    let version_1 = "pb1q9vxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxvenxva4h4al";
+    assert_eq!(Address::parse(version_1), Err(AddressError::UnsupportedVersion(1)));
+
+    // Transfers name the recipient by key, and refuse anything but an address
+    let wallet = Wallet::new();
+    let recipient = Wallet::new();
+    let tx = Transaction::new(wallet.public_key_hex(), &recipient.address().to_string(), 1, 0, 0).unwrap();
+    assert_eq!(tx.recipient, recipient.public_key_hex());
+    let mistyped = recipient.address().to_string().replacen('q', "p", 1);
+    assert!(Transaction::new(wallet.public_key_hex(), &mistyped, 1, 0, 0).is_err());
+    assert!(Transaction::new(wallet.public_key_hex(), &recipient.public_key_hex(), 1, 0, 0).is_err());
+}
+
+#[test]
+fn test_wallet_history_tracks_transfers_and_reorgs() {
+    use_test_parameters();
+    let mut blockchain = Blockchain::new();
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    blockchain.mine_pending_transactions(&alice.public_key_hex()).unwrap();
+    let mut tx = Transaction::new(alice.public_key_hex(), &bob.address().to_string(), 10, 2, 0).unwrap();
+    tx.sign_transaction(&alice.signing_key);
+    let tx_id = tx.id();
+    blockchain.add_transaction(tx).unwrap();
+
+    let mut history = WalletHistory::new([alice.public_key_hex()]);
+    history.sync(&blockchain).unwrap();
+    assert_eq!(history.entries().len(), 1);
+    assert_eq!(history.entries()[0].direction, Direction::Mined);
+    let pending = history.pending(&blockchain);
+    assert_eq!((pending[0].direction, pending[0].confirmations(1)), (Direction::Outgoing, 0));
+
+    blockchain.mine_pending_transactions(&bob.public_key_hex()).unwrap();
+    blockchain.mine_pending_transactions(&bob.public_key_hex()).unwrap();
+    history.sync(&blockchain).unwrap();
+    assert!(history.pending(&blockchain).is_empty());
+    let sent = &history.entries()[1];
+    assert_eq!(sent.tx_id, tx_id);
+    assert_eq!((sent.height, sent.confirmations(history.tip_height().unwrap())), (Some(2), 2));
+    assert_eq!((sent.counterparty.as_str(), sent.amount, sent.fee), (bob.public_key_hex().as_str(), 10, 2));
+    assert_eq!(history.balance(), blockchain.get_balance(&alice.public_key_hex()) as i128);
+
+    let mut bob_history = WalletHistory::new([bob.public_key_hex()]);
+    bob_history.sync(&blockchain).unwrap();
+    let directions: Vec<Direction> = bob_history.entries().iter().map(|e| e.direction).collect();
+    assert_eq!(directions, [Direction::Incoming, Direction::Mined, Direction::Mined]);
+    assert_eq!(bob_history.balance(), blockchain.get_balance(&bob.public_key_hex()) as i128);
+
+    // Blocks that leave the chain take their entries with them
+    let chain = blockchain.blocks_from(0).unwrap();
+    bob_history.scan(&chain[..2]);
+    assert!(bob_history.entries().is_empty());
+    let mut replaced = chain.clone();
+    replaced[2].hash = "replaced".to_string();
+    replaced[2].transactions.truncate(1);
+    bob_history.scan(&replaced);
+    assert_eq!(bob_history.entries().len(), 2);
+    bob_history.scan(&chain);
+    assert_eq!(bob_history.entries().len(), 3);
+
+    // Watching another account rescans from genesis
+    assert!(history.watch(bob.public_key_hex()));
+    history.scan(&chain);
+    assert_eq!(history.entries()[1].direction, Direction::SelfTransfer);
+}
+
+#[test]
+fn test_wallet_history_discovers_hd_accounts() {
+    use_test_parameters();
+    let wallet = Wallet::from_mnemonic(&generate_mnemonic(12).unwrap(), "", 0).unwrap();
+    let key = |account: u32| Some(wallet.with_account(account).ok()??.public_key_hex());
+    let mut blockchain = Blockchain::new();
+
+    // Accounts 0 and 2 are in use, and 8 lies beyond the gap after 2
+    for account in [0, 2, 8] {
+        blockchain.mine_pending_transactions(&key(account).unwrap()).unwrap();
+    }
+    let sender = wallet.with_account(2).unwrap().unwrap();
+    let recipient = Address::from_public_key_hex(&key(8).unwrap()).unwrap().to_string();
+    let mut tx = Transaction::new(sender.public_key_hex(), &recipient, 20, 1, 0).unwrap();
+    tx.sign_transaction(&sender.signing_key);
+    blockchain.add_transaction(tx).unwrap();
+    blockchain.mine_pending_transactions(&Wallet::new().public_key_hex()).unwrap();
+    let mut history = WalletHistory::default();
+    history.discover_accounts(&blockchain, key).unwrap();
+    assert!(history.is_used(&key(2).unwrap()) && history.is_watching(&key(2 + ACCOUNT_GAP_LIMIT).unwrap()));
+    assert!(!history.is_watching(&key(8).unwrap()));
+    assert_eq!(history.entries().len(), 3);
+    assert_eq!(history.entries()[2].direction, Direction::Outgoing);
+    assert_eq!(history.balance(), 2 * Transaction::MINING_REWARD as i128 - 21);
+
+    // Using the next account extends discovery up to account 8, whose
+    // transfer from account 2 becomes a self-transfer
+    blockchain.mine_pending_transactions(&key(5).unwrap()).unwrap();
+    history.discover_accounts(&blockchain, key).unwrap();
+    assert_eq!(history.entries().len(), 5);
+    assert_eq!(history.balance(), 4 * Transaction::MINING_REWARD as i128 - 1);
+    let mut rescanned = WalletHistory::new((0..=8 + ACCOUNT_GAP_LIMIT).map(|account| key(account).unwrap()));
+    rescanned.sync(&blockchain).unwrap();
+    assert_eq!(history.entries(), rescanned.entries());
+
+    // A single-key wallet has no other accounts to find
+    let single = Wallet::new();
+    let mut history = WalletHistory::new([single.public_key_hex()]);
+    history.discover_accounts(&blockchain, |account| Some(single.with_account(account).ok()??.public_key_hex())).unwrap();
+    assert!(history.entries().is_empty());
+}
+
+#[test]
+fn test_wallet_dir_manages_named_wallets() {
+    let dir = std::env::temp_dir().join(format!("wallets-{}", std::process::id()));
+    let wallets = WalletDir::open(&dir).unwrap();
+    assert!(matches!(wallets.resolve(None), Err(WalletDirError::NoneSelected)));
+    assert!(matches!(wallets.path("../escape"), Err(WalletDirError::InvalidName(_))));
+    assert!(matches!(wallets.path(""), Err(WalletDirError::InvalidName(_))));
+
+    let params = KdfParams { log_n: 4, r: 1, p: 1 };
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    alice.save_with_params(wallets.path("alice").unwrap(), "pass", params).unwrap();
+    bob.save_with_params(wallets.path("bob").unwrap(), "pass", params).unwrap();
+    assert_eq!(wallets.list().unwrap(), ["alice", "bob"]);
+    assert!(matches!(wallets.set_current("carol"), Err(WalletDirError::NotFound(_))));
+    wallets.set_current("alice").unwrap();
+    assert_eq!(wallets.resolve(None).unwrap(), "alice");
+    assert_eq!(wallets.resolve(Some("bob")).unwrap(), "bob");
+
+    // Renaming never replaces another wallet, and the selection follows it
+    assert!(matches!(wallets.rename("alice", "bob"), Err(WalletDirError::AlreadyExists(_))));
+    let key = Wallet::public_key_from_file(wallets.path("bob").unwrap()).unwrap();
+    assert_eq!(key, bob.public_key_hex());
+    wallets.rename("alice", "savings").unwrap();
+    assert_eq!(wallets.current().unwrap().as_deref(), Some("savings"));
+    let key = Wallet::public_key_from_file(wallets.path("savings").unwrap()).unwrap();
+    assert_eq!(key, alice.public_key_hex());
+
+    wallets.delete("savings").unwrap();
+    assert_eq!(wallets.list().unwrap(), ["bob"]);
+    assert_eq!(wallets.current().unwrap(), None);
+    assert!(matches!(wallets.delete("savings"), Err(WalletDirError::NotFound(_))));
+
+    // A lone wallet file is imported without replacing an existing wallet
+    let legacy = dir.with_extension("legacy.dat");
+    alice.save_with_params(&legacy, "pass", params).unwrap();
+    assert!(matches!(wallets.import(&legacy, "bob"), Err(WalletDirError::AlreadyExists(_))));
+    wallets.import(&legacy, "default").unwrap();
+    assert!(!legacy.exists());
+    assert_eq!(wallets.list().unwrap(), ["bob", "default"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Spends `note` against the current tip of `blockchain`.
+fn spend_input(blockchain: &Blockchain, note: Note, key: SpendingKey) -> SpendInput {
+    let position = blockchain.shielded.tree.position_of(note.commitment()).unwrap();
+    SpendInput { note, key, path: blockchain.shielded.tree.path(position).unwrap() }
+}
+
+#[test]
+fn test_shielded_transfer_conserves_value() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let recipient = Wallet::new().address();
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let pool = Transaction::SHIELDED_POOL;
+    let key = SpendingKey::random();
+
+    // Deposit 30 into two notes and 5 into a third; the pool account tracks the shielded total
+    let deposited = [Note::new(20, key.address()), Note::new(10, key.address())];
+    let extra = Note::new(5, key.address());
+    let anchor = blockchain.shielded.tree.root();
+    let mut deposit = Transaction::new_deposit(wallet.public_key_hex(), 30, 1, 0, &deposited, anchor).unwrap();
+    deposit.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(deposit).unwrap();
+    let mut deposit = Transaction::new_deposit(wallet.public_key_hex(), 5, 0, 1, &[extra], anchor).unwrap();
+    deposit.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(deposit).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert_eq!(blockchain.get_balance(pool), 35);
+    assert_eq!(blockchain.get_balance(&wallet.public_key_hex()), Transaction::MINING_REWARD - 36);
+
+    // Spend both notes into a 25 change note, withdrawing 3 and paying a fee of 2
+    let inputs: Vec<SpendInput> = deposited.iter().map(|note| spend_input(&blockchain, *note, key)).collect();
+    let anchor = blockchain.shielded.tree.root();
+    let change = Note::new(25, key.address());
+    let spend =
+        Transaction::new_withdrawal(&inputs, &[change], anchor, &recipient, 3, 2).unwrap();
+    assert!(spend.is_valid());
+    assert_eq!(spend.nonce, 0);
+    assert!(spend.verify_shielded());
+
+    // The proof no longer verifies if the public values or the notes change
+    let mut inflated = spend.clone();
+    inflated.amount = 4;
+    assert_eq!(blockchain.add_transaction(inflated), Err(TransactionError::InvalidProof));
+    let mut redirected = spend.clone();
+    redirected.recipient = Wallet::new().public_key_hex();
+    assert!(!redirected.verify_shielded());
+    let mut swapped = spend.clone();
+    swapped.shielded.as_mut().unwrap().commitments[0] = field_to_bytes(&Note::new(26, key.address()).commitment());
+    assert_eq!(blockchain.add_transaction(swapped), Err(TransactionError::InvalidProof));
+
+    // Pool spends take no nonce, so an unrelated fully shielded transfer can
+    // wait in the mempool and be mined alongside it
+    let moved = Note::new(4, SpendingKey::random().address());
+    let transfer = Transaction::new_shielded(&[spend_input(&blockchain, extra, key)], &[moved], anchor, 1).unwrap();
+    assert_eq!((transfer.recipient.as_str(), transfer.amount, transfer.nonce), (pool, 0, 0));
+    blockchain.add_transaction(spend).unwrap();
+    blockchain.add_transaction(transfer).unwrap();
+    assert_eq!(blockchain.pending_transactions.len(), 2);
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert_eq!(blockchain.get_balance(pool), 29);
+    assert_eq!(blockchain.accounts.nonce(pool), 0);
+    assert_eq!(blockchain.get_balance(&recipient.public_key_hex()), 3);
+    assert!(blockchain.shielded.tree.position_of(change.commitment()).is_some());
+    assert!(blockchain.shielded.tree.position_of(moved.commitment()).is_some());
+    assert!(blockchain.validate_chain().is_ok());
+
+    // Unbalanced transfers cannot be proven at all
+    let anchor = blockchain.shielded.tree.root();
+    let binding = blstrs::Scalar::from(1u64);
+    assert!(create_shielded_bundle(&[], &[Note::new(6, key.address())], anchor, 5, 0, binding).is_err());
+    let bundle = create_shielded_bundle(&[], &[Note::new(4, key.address())], anchor, 5, 1, binding).unwrap();
+    assert!(verify_shielded_bundle(&bundle, 5, 1, binding));
+    assert!(!verify_shielded_bundle(&bundle, 6, 2, binding));
+    assert!(!verify_shielded_bundle(&bundle, 5, 1, binding + binding));
+}
+
+#[test]
+fn test_note_commitment_tree_paths() {
+    use_test_parameters();
+    let mut tree = NoteCommitmentTree::default();
+    let empty_root = tree.root();
+    let leaves: Vec<_> = (0..5u64).map(|i| blstrs::Scalar::from(i + 100)).collect();
+    for (i, leaf) in leaves.iter().enumerate() {
+        assert_eq!(tree.append(*leaf), Some(i as u64));
+    }
+    for (i, leaf) in leaves.iter().enumerate() {
+        let path = tree.path(i as u64).unwrap();
+        assert_eq!(path.root(*leaf), tree.root());
+        assert_ne!(path.root(blstrs::Scalar::from(7u64)), tree.root());
+    }
+    assert!(tree.path(5).is_none());
+
+    // Truncating leaves the tree as if the dropped leaves were never appended
+    let mut truncated = tree.clone();
+    for len in (0..5).rev() {
+        truncated.truncate(len);
+        let mut shorter = NoteCommitmentTree::default();
+        for leaf in &leaves[..len as usize] {
+            shorter.append(*leaf);
+        }
+        assert_eq!(truncated, shorter);
+    }
+    assert_eq!(truncated.root(), empty_root);
+    let decoded: ShieldedState = from_bytes(&to_bytes(&ShieldedState::default())).unwrap();
+    assert_eq!(decoded.note_root(), Block::genesis().header.note_root);
+}
+
+#[test]
+fn test_shielded_spends_need_membership_and_fresh_nullifiers() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let recipient = Wallet::new().address();
+    use bellman::gadgets::test::TestConstraintSystem;
+    use bellman::Circuit;
+
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let key = SpendingKey::random();
+
+    // A deposit must be anchored to a recent note root
+    let note = Note::new(10, key.address());
+    let stale = Transaction::new_deposit(wallet.public_key_hex(), 10, 0, 0, &[note], blstrs::Scalar::from(7u64));
+    let mut stale = stale.unwrap();
+    stale.sign_transaction(&wallet.signing_key);
+    assert_eq!(blockchain.add_transaction(stale), Err(TransactionError::InvalidAnchor));
+
+    let anchor = blockchain.shielded.tree.root();
+    let mut deposit = Transaction::new_deposit(wallet.public_key_hex(), 10, 0, 0, &[note], anchor).unwrap();
+    deposit.sign_transaction(&wallet.signing_key);
+    blockchain.add_transaction(deposit).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert_eq!(blockchain.get_latest_block().header.note_root, blockchain.shielded.note_root());
+
+    // A note that is not in the tree, or spent with the wrong key, does not satisfy the circuit
+    let input = spend_input(&blockchain, note, key);
+    let anchor = blockchain.shielded.tree.root();
+    let forged = SpendInput { note: Note::new(10, key.address()), ..input.clone() };
+    let thief = SpendInput { key: SpendingKey::random(), ..input.clone() };
+    for spend in [forged.clone(), thief] {
+        let circuit = TransferCircuit {
+            anchor: Some(anchor),
+            inputs: [Some(spend), Some(SpendInput { note: Note::new(0, key.address()), ..input.clone() })],
+            outputs: [Some(Note::new(10, key.address())), Some(Note::new(0, key.address()))],
+            public_in: Some(0),
+            public_out: Some(0),
+            binding: Some(blstrs::Scalar::from(1u64)),
+        };
+        let mut cs = TestConstraintSystem::new();
+        circuit.synthesize(&mut cs).unwrap();
+        assert!(!cs.is_satisfied());
+    }
+    let binding = blstrs::Scalar::from(1u64);
+    assert!(create_shielded_bundle(&[forged], &[], anchor, 0, 10, binding).is_err());
+    let nowhere = SpendInput { path: MerklePath { position: 1, ..input.path.clone() }, ..input.clone() };
+    assert!(create_shielded_bundle(&[nowhere], &[], anchor, 0, 10, binding).is_err());
+
+    // Two spends of one note share a nullifier, whether the first is pending or mined
+    let first =
+        Transaction::new_withdrawal(std::slice::from_ref(&input), &[], anchor, &recipient, 10, 0);
+    let moved = Note::new(10, SpendingKey::random().address());
+    let second = Transaction::new_shielded(&[input], &[moved], anchor, 0);
+    let (first, second) = (first.unwrap(), second.unwrap());
+    blockchain.add_transaction(first).unwrap();
+    assert_eq!(blockchain.add_transaction(second.clone()), Err(TransactionError::DoubleSpend));
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    assert_eq!(blockchain.get_balance(&recipient.public_key_hex()), 10);
+    assert_eq!(blockchain.add_transaction(second.clone()), Err(TransactionError::DoubleSpend));
+
+    // A block including the double spend is rejected
+    let mut forged_chain = blockchain.blocks_from(0).unwrap();
+    let tip = forged_chain.last_mut().unwrap();
+    let mut accounts = blockchain.accounts.clone();
+    accounts.revert_block(tip);
+    tip.transactions.insert(1, second);
+    accounts.apply_block(tip).unwrap();
+    tip.header.state_root = accounts.state_root();
+    remine(tip);
+    let err = blockchain.validate_blocks(&forged_chain).unwrap_err();
+    assert_eq!(err.kind, BlockError::DoubleSpend(1));
+}
+
+#[test]
+fn test_viewing_keys_disclose_shielded_notes() {
+    use_test_parameters();
+    let miner = Wallet::new().public_key_hex();
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_pending_transactions(&wallet.public_key_hex()).unwrap();
+    let key = wallet.spending_key();
+    let full_key = wallet.viewing_key();
+    let incoming_key = ViewingKey::Incoming(full_key.incoming());
+    assert_eq!(full_key.address(), key.address());
+    assert_eq!(incoming_key.address(), key.address());
+
+    // Keys survive their encoding, which catches typos
+    for viewing_key in [full_key, incoming_key] {
+        assert_eq!(ViewingKey::parse(&viewing_key.to_string()), Ok(viewing_key));
+    }
+    let mut mistyped = full_key.to_string();
+    let last = if mistyped.pop() == Some('q') { 'p' } else { 'q' };
+    mistyped.push(last);
+    assert_eq!(ViewingKey::parse(&mistyped), Err(ViewingKeyError::InvalidChecksum));
+    assert_eq!(ViewingKey::parse(&recipient_address()), Err(ViewingKeyError::UnknownPrefix("pb".to_string())));
+
+    // So do payment addresses, which is how a sender learns where to shield funds
+    let encoded = key.address().to_string();
+    assert!(encoded.starts_with("pbzs1"));
+    let address = PaymentAddress::parse(&encoded.to_uppercase()).unwrap();
+    assert_eq!(address, key.address());
+    let mut mistyped = encoded.clone();
+    let last = if mistyped.pop() == Some('q') { 'p' } else { 'q' };
+    mistyped.push(last);
+    assert_eq!(PaymentAddress::parse(&mistyped), Err(PaymentAddressError::InvalidChecksum));
+    let err = PaymentAddress::parse(&full_key.to_string());
+    assert_eq!(err, Err(PaymentAddressError::UnknownPrefix("pbfvk".to_string())));
+    let identity = PaymentAddress { transmission_key: <jubjub::SubgroupPoint as group::Group>::identity(), ..address };
+    assert_eq!(PaymentAddress::parse(&identity.to_string()), Err(PaymentAddressError::InvalidAddress));
+
+    // Deposited notes can only be decrypted with the wallet's viewing keys
+    let deposited = [Note::new(20, address), Note::new(10, address)];
+    let anchor = blockchain.shielded.tree.root();
+    let mut deposit = Transaction::new_deposit(wallet.public_key_hex(), 30, 0, 0, &deposited, anchor).unwrap();
+    deposit.sign_transaction(&wallet.signing_key);
+    let bundle = deposit.shielded.clone().unwrap();
+    let commitment = field_from_bytes(&bundle.commitments[0]).unwrap();
+    assert_eq!(full_key.incoming().decrypt_note(&bundle.encrypted_notes[0], commitment), Some(deposited[0]));
+    let stranger = SpendingKey::random().full_viewing_key().incoming();
+    assert_eq!(stranger.decrypt_note(&bundle.encrypted_notes[0], commitment), None);
+    // The proof covers the ciphertexts, so they cannot be swapped out
+    let mut swapped = deposit.clone();
+    swapped.shielded.as_mut().unwrap().encrypted_notes.swap(0, 1);
+    assert!(!swapped.verify_shielded());
+    blockchain.add_transaction(deposit).unwrap();
+    blockchain.mine_pending_transactions(&miner).unwrap();
+
+    let mut full = ShieldedHistory::new(full_key);
+    let mut incoming = ShieldedHistory::new(incoming_key);
+    full.sync(&blockchain).unwrap();
+    incoming.sync(&blockchain).unwrap();
+    assert_eq!(full.balance(), 30);
+    assert_eq!(full.entries()[0].direction, Direction::Incoming);
+    assert_eq!(full.entries()[0].counterparty, wallet.public_key_hex());
+    let received = &full.notes()[0];
+    assert_eq!(Some(received.position), blockchain.shielded.tree.position_of(deposited[0].commitment()));
+
+    // A decrypted note is all the spender needs; the full viewing key sees the spend
+    let input = spend_input(&blockchain, received.note, key);
+    let anchor = blockchain.shielded.tree.root();
+    let change = Note::new(7, key.address());
+    let spend = Transaction::new_withdrawal(&[input], &[change], anchor, &wallet.address(), 12, 1).unwrap();
+    blockchain.add_transaction(spend).unwrap();
+    assert_eq!(full.pending(&blockchain)[0].net_change(), -13);
+    blockchain.mine_pending_transactions(&miner).unwrap();
+    full.sync(&blockchain).unwrap();
+    incoming.sync(&blockchain).unwrap();
+    let spent_at = full.tip_height();
+    assert_eq!(full.notes()[0].spent_at, spent_at);
+    assert_eq!(full.balance(), 17);
+    let sent = full.entries().last().unwrap();
+    assert_eq!((sent.direction, sent.amount, sent.fee), (Direction::Outgoing, 12, 1));
+    // The incoming viewing key sees the change but not the spend
+    assert_eq!(incoming.balance(), 37);
+    assert!(incoming.notes().iter().all(|note| note.spent_at.is_none()));
+
+    // Dropping the last block unspends the note
+    full.scan(&blockchain.recent_blocks()[..blockchain.block_count() as usize - 1]);
+    assert_eq!(full.balance(), 30);
+    assert_eq!(full.notes()[0].spent_at, None);
+
+    // A view-only wallet file holds the key but no account
+    let dir = temp_dir("viewing-wallet");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.dat");
+    full_key.save_to_file(&path).unwrap();
+    assert!(ViewingKey::is_viewing_file(&path).unwrap());
+    assert_eq!(ViewingKey::load_from_file(&path).unwrap(), full_key);
+    assert!(!Wallet::is_plaintext_file(&path).unwrap());
+    assert!(Wallet::public_key_from_file(&path).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+}